@@ -0,0 +1,51 @@
+use actix_web::{web, HttpResponse};
+use actix_files::Files;
+use crate::config::CONFIG;
+
+struct Asset {
+    path: &'static str,
+    content_type: &'static str,
+    body: &'static str,
+}
+
+static ASSETS: &[Asset] = &[
+    Asset { path: "/index.html", content_type: "text/html; charset=utf-8", body: include_str!("../../static/index.html") },
+    Asset { path: "/app.js", content_type: "application/javascript; charset=utf-8", body: include_str!("../../static/app.js") },
+    Asset { path: "/style.css", content_type: "text/css; charset=utf-8", body: include_str!("../../static/style.css") },
+];
+
+/// Serves the frontend, from `web.asset_dir` if configured, otherwise from the copies embedded at build time.
+/// Must be registered after the api routes since it claims every remaining path when serving from disk.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    if let Some(dir) = &CONFIG.web.asset_dir {
+        cfg.service(Files::new("/", dir).index_file("index.html"));
+        return;
+    }
+    cfg.service(web::resource("/").route(web::get().to(|| embedded("/index.html"))));
+    for asset in ASSETS {
+        cfg.service(web::resource(asset.path).route(web::get().to(move || embedded(asset.path))));
+    }
+}
+
+async fn embedded(path: &'static str) -> HttpResponse {
+    match ASSETS.iter().find(|a| a.path == path) {
+        Some(asset) => HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .body(asset.body),
+        None => HttpResponse::NotFound().finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::CONTENT_TYPE;
+
+    #[actix_rt::test]
+    async fn test_embedded_assets() {
+        let res = embedded("/app.js").await;
+        assert!(res.status().is_success());
+        assert_eq!("application/javascript; charset=utf-8", res.headers().get(CONTENT_TYPE).unwrap());
+        assert!(embedded("/missing.js").await.status().is_client_error());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::Bytes;
+use serde::Serialize;
+use crate::config::CONFIG;
+
+lazy_static! {
+    pub static ref RESPONSE_CACHE: ResponseCache = ResponseCache::new(CONFIG.cache.max_entries, CONFIG.cache.max_bytes);
+}
+
+/// Serialized query results, only valid for the snapshot version they were produced from.
+/// Evicts the least recently used entries once either limit is exceeded.
+pub struct ResponseCache {
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Inner {
+    entries: HashMap<String, Entry>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+struct Entry {
+    version: i64,
+    body: Bytes,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CacheStats {
+    #[serde(rename="Hits")]
+    pub hits: u64,
+    #[serde(rename="Misses")]
+    pub misses: u64,
+    #[serde(rename="Evictions")]
+    pub evictions: u64,
+    #[serde(rename="Entries")]
+    pub entries: usize,
+    #[serde(rename="Bytes")]
+    pub bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        ResponseCache {
+            max_entries,
+            max_bytes,
+            inner: Mutex::new(Inner { entries: HashMap::new(), order: VecDeque::new(), bytes: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str, version: i64) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        let found = match inner.entries.get(key) {
+            Some(e) if e.version == version => Some(e.body.clone()),
+            _ => None
+        };
+        match found {
+            Some(body) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                inner.touch(key);
+                Some(body)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: String, version: i64, body: Bytes) {
+        if body.len() > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        inner.bytes += body.len();
+        inner.order.push_back(key.clone());
+        inner.entries.insert(key, Entry { version, body });
+        while inner.entries.len() > self.max_entries || inner.bytes > self.max_bytes {
+            let oldest = match inner.order.front() {
+                Some(k) => k.clone(),
+                None => break
+            };
+            inner.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            if let Some(k) = self.order.remove(pos) {
+                self.order.push_back(k);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.bytes -= old.body.len();
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+/// Weak validator for a cached query, the body is fully determined by the query and the snapshot version.
+pub fn etag(key: &str, version: i64) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("W/\"{}-{:x}\"", version, hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_version_misses() {
+        let cache = ResponseCache::new(10, 1024);
+        cache.insert(String::from("a"), 1, Bytes::from("[]"));
+        assert_eq!(Some(Bytes::from("[]")), cache.get("a", 1));
+        assert_eq!(None, cache.get("a", 2));
+        assert_eq!(None, cache.get("b", 1));
+        let stats = cache.stats();
+        assert_eq!((1, 2), (stats.hits, stats.misses));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = ResponseCache::new(2, 1024);
+        cache.insert(String::from("a"), 1, Bytes::from("a"));
+        cache.insert(String::from("b"), 1, Bytes::from("b"));
+        cache.get("a", 1);
+        cache.insert(String::from("c"), 1, Bytes::from("c"));
+        assert!(cache.get("a", 1).is_some());
+        assert!(cache.get("b", 1).is_none());
+        assert!(cache.get("c", 1).is_some());
+        assert_eq!(1, cache.stats().evictions);
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let cache = ResponseCache::new(10, 8);
+        cache.insert(String::from("a"), 1, Bytes::from("12345"));
+        cache.insert(String::from("b"), 1, Bytes::from("12345"));
+        assert_eq!((1, 5), (cache.stats().entries, cache.stats().bytes));
+        cache.insert(String::from("c"), 1, Bytes::from("too large to cache"));
+        assert!(cache.get("c", 1).is_none());
+        assert!(cache.get("b", 1).is_some());
+    }
+
+    #[test]
+    fn test_etag_changes_with_version() {
+        assert_eq!(etag("a", 1), etag("a", 1));
+        assert_ne!(etag("a", 1), etag("a", 2));
+        assert_ne!(etag("a", 1), etag("b", 1));
+    }
+}
@@ -0,0 +1,277 @@
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use rust_xlsxwriter::{Workbook, Format};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::domain::models::product::Product;
+use crate::domain::models::projection::PRODUCT_FIELDS;
+use crate::domain::result::{Result, ErrorKind};
+use std::pin::Pin;
+
+/// Most products an xlsx export may hold. The workbook is assembled in memory before it is sent,
+/// so larger `count`s are rejected, csv and ndjson stream any number of rows.
+pub const XLSX_MAX_ROWS: usize = 10_000;
+
+/// Encoded chunks of an export, in order.
+pub type ByteStream = Pin<Box<dyn Stream<Item=Result<Bytes>> + Send>>;
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ExportOpts {
+    /// csv, ndjson, xlsx or json, xlsx takes a `count` of at most `XLSX_MAX_ROWS`.
+    #[serde(default)]
+    pub format: String,
+
+    /// Comma separated `Product` fields as they are named in the JSON output, every field if empty.
+    #[serde(default)]
+    pub columns: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Ndjson,
+    Xlsx,
+}
+
+const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+impl ExportFormat {
+    pub fn parse(source: &str) -> Result<ExportFormat> {
+        match source.to_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            "xlsx" => Ok(ExportFormat::Xlsx),
+            _ => Err(ErrorKind::InvalidParameter(String::from("format"), source.to_string()).into())
+        }
+    }
+
+    /// First export format listed in an Accept header, plain JSON isn't an export and is left to the caller.
+    pub fn from_accept(accept: &str) -> Option<ExportFormat> {
+        accept.split(',')
+            .map(|t| t.split(';').next().unwrap_or("").trim())
+            .filter_map(|t| match t {
+                "text/csv" => Some(ExportFormat::Csv),
+                "application/x-ndjson" | "application/jsonl" => Some(ExportFormat::Ndjson),
+                XLSX_CONTENT_TYPE => Some(ExportFormat::Xlsx),
+                _ => None
+            })
+            .next()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json; charset=utf-8",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Xlsx => XLSX_CONTENT_TYPE,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// Every field of `Product` in declaration order, as named in the JSON output.
+pub fn product_columns() -> Vec<String> {
+    PRODUCT_FIELDS.iter().map(|f| f.name.to_string()).collect()
+}
+
+pub struct Exporter {
+    format: ExportFormat,
+    columns: Vec<String>,
+}
+
+impl Exporter {
+    pub fn new(format: ExportFormat, columns: &str) -> Result<Exporter> {
+        let known = product_columns();
+        let wanted: Vec<String> = columns.split(',')
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .map(|c| c.to_string())
+            .collect();
+        if let Some(unknown) = wanted.iter().find(|c| !known.contains(c)) {
+            return Err(ErrorKind::InvalidParameter(String::from("columns"), unknown.clone()).into());
+        }
+        let columns = if wanted.is_empty() { known } else { wanted };
+        Ok(Exporter { format, columns })
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    /// Rejects xlsx exports of more than `XLSX_MAX_ROWS` products, the other formats stream any `count`.
+    pub fn check_count(&self, count: usize) -> Result<()> {
+        if self.format == ExportFormat::Xlsx && count > XLSX_MAX_ROWS {
+            return Err(ErrorKind::InvalidParameter(String::from("count"), count.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Encodes one chunk per product as they are read, only xlsx has to be assembled up front.
+    pub fn encode<S>(self, products: S) -> ByteStream
+        where S: Stream<Item=Result<Product>> + Send + 'static {
+        let columns = self.columns.clone();
+        match self.format {
+            ExportFormat::Xlsx => Box::pin(stream::once(async move {
+                let products: Vec<Product> = products.take(XLSX_MAX_ROWS).try_collect().await?;
+                Ok(Bytes::from(self.xlsx(&products)?))
+            })),
+            ExportFormat::Csv => {
+                let header = Bytes::from(csv_line(columns.iter().map(|c| c.to_string())));
+                Box::pin(stream::once(future::ok(header)).chain(products.map_ok(move |p| {
+                    let row = row(&p, &columns);
+                    Bytes::from(csv_line(row.iter().map(csv_cell)))
+                })))
+            }
+            ExportFormat::Ndjson => Box::pin(products.map_ok(move |p| {
+                let line = serde_json::to_string(&object(&p, &columns)).expect("Failed to serialize value");
+                Bytes::from(format!("{}\n", line))
+            })),
+            ExportFormat::Json => {
+                let rows = products.enumerate().map(move |(i, p)| p.map(|p| {
+                    let item = serde_json::to_string(&object(&p, &columns)).expect("Failed to serialize value");
+                    Bytes::from(if i == 0 { item } else { format!(",{}", item) })
+                }));
+                Box::pin(stream::once(future::ok(Bytes::from("["))).chain(rows).chain(stream::once(future::ok(Bytes::from("]")))))
+            }
+        }
+    }
+
+    fn xlsx(&self, products: &[Product]) -> Result<Vec<u8>> {
+        let mut workbook = Workbook::new();
+        let sheet = workbook.add_worksheet();
+        let bold = Format::new().set_bold();
+        for (col, name) in self.columns.iter().enumerate() {
+            sheet.write_string_with_format(0, col as u16, name, &bold)?;
+        }
+        for (i, product) in products.iter().enumerate() {
+            let line = (i + 1) as u32;
+            for (col, value) in row(product, &self.columns).into_iter().enumerate() {
+                let col = col as u16;
+                match value {
+                    Value::Number(n) => sheet.write_number(line, col, n.as_f64().unwrap_or(0.0))?,
+                    Value::Bool(b) => sheet.write_boolean(line, col, b)?,
+                    Value::String(s) => sheet.write_string(line, col, s)?,
+                    Value::Null => continue,
+                    other => sheet.write_string(line, col, other.to_string())?,
+                };
+            }
+        }
+        Ok(workbook.save_to_buffer()?)
+    }
+}
+
+fn row(product: &Product, columns: &[String]) -> Vec<Value> {
+    let mut value = serde_json::to_value(product).expect("Failed to serialize value");
+    columns.iter()
+        .map(|c| value.get_mut(c.as_str()).map(Value::take).unwrap_or(Value::Null))
+        .collect()
+}
+
+fn object(product: &Product, columns: &[String]) -> Value {
+    Value::Object(columns.iter().cloned().zip(row(product, columns)).collect())
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn csv_line<I: Iterator<Item=String>>(cells: I) -> String {
+    let escaped: Vec<String> = cells
+        .map(|c| if c.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", c.replace('"', "\"\""))
+        } else {
+            c
+        })
+        .collect();
+    format!("{}\r\n", escaped.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::product;
+
+    fn encode(exporter: Exporter, products: Vec<Product>) -> Vec<u8> {
+        let chunks: Vec<Bytes> = futures::executor::block_on(
+            exporter.encode(stream::iter(products.into_iter().map(Ok))).try_collect()).unwrap();
+        chunks.into_iter().flat_map(|b| b.to_vec()).collect()
+    }
+
+    fn collect(exporter: Exporter, products: Vec<Product>) -> String {
+        String::from_utf8(encode(exporter, products)).unwrap()
+    }
+
+    #[test]
+    fn test_csv_quotes_and_columns() {
+        let mut p = product("1", "öl", 20.0, 500.0, 5.0);
+        p.product_name_bold = String::from("Brooklyn \"East IPA\", burk");
+        let exporter = Exporter::new(ExportFormat::Csv, "ProductId, ProductNameBold,Price").unwrap();
+        let csv = collect(exporter, vec![p]);
+        assert_eq!("ProductId,ProductNameBold,Price\r\n1,\"Brooklyn \"\"East IPA\"\", burk\",20.0\r\n", csv);
+    }
+
+    #[test]
+    fn test_ndjson_and_json() {
+        let products = vec![product("1", "öl", 20.0, 500.0, 5.0), product("2", "vin", 90.0, 750.0, 13.0)];
+        let ndjson = collect(Exporter::new(ExportFormat::Ndjson, "ProductId,Category").unwrap(), products.clone());
+        assert_eq!("{\"ProductId\":\"1\",\"Category\":\"öl\"}\n{\"ProductId\":\"2\",\"Category\":\"vin\"}\n", ndjson);
+        let json = collect(Exporter::new(ExportFormat::Json, "ProductId").unwrap(), products);
+        assert_eq!(serde_json::json!([{"ProductId": "1"}, {"ProductId": "2"}]), serde_json::from_str::<Value>(&json).unwrap());
+        let empty = collect(Exporter::new(ExportFormat::Json, "").unwrap(), Vec::new());
+        assert_eq!("[]", empty);
+    }
+
+    #[test]
+    fn test_all_columns_by_default() {
+        let columns = product_columns();
+        assert_eq!("ProductId", columns[0]);
+        assert!(columns.contains(&String::from("ApkRecycling")));
+        assert!(Exporter::new(ExportFormat::Csv, "ProductId,Nope").is_err());
+    }
+
+    #[test]
+    fn test_xlsx_is_a_zip() {
+        let exporter = Exporter::new(ExportFormat::Xlsx, "").unwrap();
+        let body = encode(exporter, vec![product("1", "öl", 20.0, 500.0, 5.0)]);
+        assert_eq!(b"PK", &body[..2]);
+    }
+
+    #[test]
+    fn test_xlsx_row_cap() {
+        let xlsx = Exporter::new(ExportFormat::Xlsx, "").unwrap();
+        assert!(xlsx.check_count(XLSX_MAX_ROWS).is_ok());
+        assert!(xlsx.check_count(XLSX_MAX_ROWS + 1).is_err());
+        assert!(Exporter::new(ExportFormat::Csv, "").unwrap().check_count(XLSX_MAX_ROWS + 1).is_ok());
+    }
+
+    #[test]
+    fn test_error_ends_stream() {
+        let products = vec![Ok(product("1", "öl", 20.0, 500.0, 5.0)), Err(ErrorKind::NotFound(String::from("x")).into())];
+        let exporter = Exporter::new(ExportFormat::Ndjson, "ProductId").unwrap();
+        let chunks: Vec<Result<Bytes>> = futures::executor::block_on(exporter.encode(stream::iter(products)).collect());
+        assert_eq!(2, chunks.len());
+        assert!(chunks[1].is_err());
+    }
+
+    #[test]
+    fn test_format_from_accept() {
+        assert_eq!(Some(ExportFormat::Csv), ExportFormat::from_accept("text/csv;q=0.9, application/json"));
+        assert_eq!(None, ExportFormat::from_accept("application/json, */*"));
+        assert_eq!(ExportFormat::Ndjson, ExportFormat::parse("NDJSON").unwrap());
+        assert!(ExportFormat::parse("pdf").is_err());
+    }
+}
@@ -1,10 +1,22 @@
+mod assets;
+mod cache;
+mod export;
+mod negotiation;
+mod openapi;
+mod rate_limit;
 mod service;
 mod web;
-use crate::domain::result::Result;
+use crate::domain::result::{Result, ErrorKind};
+use crate::domain::models::product::ProductOpts;
+use export::{ExportOpts, ExportFormat, Exporter};
+use actix_web::web::Query;
+use futures::StreamExt;
+use std::io::Write;
 
 use tokio::time::{Instant, Duration};
 use tokio::runtime::Handle;
 use crate::domain::result;
+use crate::logging;
 
 pub async fn run(handle: &Handle) -> Result<()> {
     service::init_db().await?;
@@ -15,14 +27,49 @@ pub async fn run(handle: &Handle) -> Result<()> {
                                                     Duration::from_secs(60*60*3));
         loop {
             interval.tick().await;
-            info!("Starting db refresh.");
-            let res = service::update_db().await;
-            if res.is_err() {
-                error!("Error updating db: {}", result::fmt_backtrace(&res.unwrap_err()));
-            }
-            info!("Finished db refresh.");
+            logging::correlated(format!("refresh-{}", logging::new_id()), async {
+                info!("Starting db refresh.");
+                if let Err(e) = service::update_db().await {
+                    error!("Error updating db: {}", result::fmt_backtrace(&e));
+                }
+                info!("Finished db refresh.");
+            }).await;
         }
     });
     web::start().await
         .map_err(|e| e.into())
 }
+
+/// `export <format> [query] [--out <file>]`, the query takes the same parameters as `/top`, including `columns`.
+/// Reads the existing database without refreshing it and writes to stdout unless `--out` is given.
+pub async fn export(args: &[String]) -> Result<()> {
+    let format = ExportFormat::parse(args.first().map(|a| a.as_str()).unwrap_or(""))?;
+    let mut query = "";
+    let mut out = None;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--out" => out = rest.next(),
+            q => query = q,
+        }
+    }
+    let invalid_query = |e: actix_web::error::QueryPayloadError| -> result::Error {
+        ErrorKind::InvalidParameter(String::from("query"), e.to_string()).into()
+    };
+    let opts = Query::<ProductOpts>::from_query(query).map_err(invalid_query)?.0.normalize();
+    let export_opts = Query::<ExportOpts>::from_query(query).map_err(invalid_query)?.0;
+    let exporter = Exporter::new(format, &export_opts.columns)?;
+    exporter.check_count(opts.count)?;
+    service::init_db().await?;
+    let products = service::stream_products(opts).await?;
+    let mut writer: Box<dyn Write> = match out {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut chunks = exporter.encode(products);
+    while let Some(chunk) = chunks.next().await {
+        writer.write_all(&chunk?)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
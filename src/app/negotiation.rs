@@ -0,0 +1,76 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::HttpResponse;
+use actix_web::body::Body;
+
+/// Whether a response of `content_type` satisfies an Accept header, a missing header accepts anything.
+/// Ranges with `q=0` are explicit refusals.
+pub fn accepts(accept: Option<&str>, content_type: &str) -> bool {
+    let accept = match accept {
+        Some(a) if !a.trim().is_empty() => a,
+        _ => return true,
+    };
+    let media = essence(content_type);
+    let (kind, _) = split(&media);
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';');
+        let range = essence(parts.next().unwrap_or(""));
+        let refused = parts.any(|p| {
+            let p = p.trim();
+            p.starts_with("q=") && p[2..].trim().parse::<f32>().map(|q| q <= 0.0).unwrap_or(false)
+        });
+        if refused {
+            return false;
+        }
+        match split(&range) {
+            ("*", "*") => true,
+            (range_kind, "*") => range_kind == kind,
+            _ => range == media,
+        }
+    })
+}
+
+/// Replaces a successful response the client said it can't handle with 406, errors are left as they are.
+pub fn negotiate(accept: Option<&str>, res: ServiceResponse<Body>) -> ServiceResponse<Body> {
+    if !res.status().is_success() {
+        return res;
+    }
+    let acceptable = match res.headers().get(CONTENT_TYPE).and_then(|h| h.to_str().ok()) {
+        Some(content_type) => accepts(accept, content_type),
+        None => true,
+    };
+    if acceptable {
+        return res;
+    }
+    res.into_response(HttpResponse::NotAcceptable().finish())
+}
+
+/// `type/subtype` lowercased, without parameters.
+fn essence(media: &str) -> String {
+    media.split(';').next().unwrap_or("").trim().to_lowercase()
+}
+
+fn split(media: &str) -> (&str, &str) {
+    let mut parts = media.splitn(2, '/');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = "application/json; charset=utf-8";
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts(None, JSON));
+        assert!(accepts(Some(""), JSON));
+        assert!(accepts(Some("*/*"), JSON));
+        assert!(accepts(Some("text/html, application/*;q=0.8"), JSON));
+        assert!(accepts(Some("Application/JSON"), JSON));
+        assert!(accepts(Some("text/csv"), "text/csv; charset=utf-8"));
+        assert!(!accepts(Some("application/xml"), JSON));
+        assert!(!accepts(Some("text/*"), JSON));
+        assert!(!accepts(Some("application/json;q=0"), JSON));
+    }
+}
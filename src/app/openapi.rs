@@ -0,0 +1,414 @@
+use actix_web::web::Query;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use crate::app::export::{ExportOpts, XLSX_MAX_ROWS};
+use crate::app::web::ErrorBody;
+use chrono::{FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use crate::domain::models::basket::{Basket, BasketItem, BasketOpts};
+use crate::domain::models::detail::ProductDetail;
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::product::{Product, ProductOpts, SiteResponse, SiteCompareOpts};
+use crate::domain::models::site::{Site, SiteOpts, OpeningTime, SiteOpening, SiteStats, CategoryBest, SiteComparison};
+use crate::domain::models::stock::{StockLevel, StockOpts};
+use crate::domain::scoring::SCORES;
+use crate::domain::models::watchlist::{Watchlist, WatchItem, NewWatchlist, NotifierTarget, NotifierKind};
+
+pub const API_PREFIX: &str = "/api/v1";
+
+/// `Option` fields of the response types, by the name they are serialized with.
+const PRODUCT_NULLABLE: &[&str] = &["ProductNameThin", "ProducerName", "SupplierName", "Seal", "EthicalLabel",
+    "SellStartDate", "OriginLevel1", "OriginLevel2", "Vintage", "SubCategory", "Type", "Style",
+    "BeverageDescriptionShort", "Usage", "Taste"];
+const SITE_NULLABLE: &[&str] = &["Alias", "DisplayName", "Phone", "Email", "Services", "Depot", "Name", "OpenFrom",
+    "OpenTo"];
+const SITE_OPENING_NULLABLE: &[&str] = &["ClosedReason", "NextOpening"];
+const STOCK_NULLABLE: &[&str] = &["Stock", "Shelf", "LastSeen"];
+const WATCHLIST_NULLABLE: &[&str] = &["LastPrice"];
+
+/// OpenAPI 3 document for everything under `API_PREFIX`. Schemas are inferred from serialized sample values
+/// and query parameters from what the option types accept, so renaming or adding a field updates the spec.
+/// Every `operationId` is the name of the actix resource serving it, prefixed with the method when the
+/// resource serves more than one so the ids stay unique.
+pub fn spec() -> Value {
+    let mut product_params = [query_parameters(&ProductOpts::default()), query_parameters(&ExportOpts::default())].concat();
+    for param in product_params.iter_mut().filter(|p| p["name"] == "score") {
+        param["schema"]["enum"] = json!(SCORES.names());
+    }
+    let mut basket_params = query_parameters(&BasketOpts::default());
+    for param in basket_params.iter_mut().filter(|p| p["name"] == "score") {
+        param["schema"]["enum"] = json!(SCORES.names());
+    }
+    let site_product_params = product_params.iter()
+        .filter(|p| p["name"] != "site_id")
+        .cloned()
+        .collect::<Vec<Value>>();
+    let products = json!({"type": "array", "items": reference("Product")});
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "systemet-apk",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": format!("Systembolaget products ranked by apk, alcohol per krona. Product lists accept `fields` \
+                or `view=compact` to return a subset of each product, and `format`, `columns` or an Accept header \
+                to download them as csv, ndjson or xlsx. Downloads are streamed as they are read, except xlsx which \
+                is assembled whole and so rejects a `count` above {}. `score` ranks them by a configured formula instead of apk. \
+                `/basket/optimize` picks the bottles with the most alcohol, or the highest total score, within a budget.",
+                XLSX_MAX_ROWS)
+        },
+        "servers": [{"url": API_PREFIX}],
+        "paths": {
+            "/top": {
+                "get": operation("top", "Top products by apk", product_params, ok(products.clone()))
+            },
+            "/products/{product_id}": {
+                "get": operation("product", "One product with its ranks and the stores listing it",
+                                 vec![path_parameter("product_id", "string")], found(reference("ProductDetail")))
+            },
+            "/products/by-number/{product_number}": {
+                "get": operation("product_by_number", "One product by its product number",
+                                 vec![path_parameter("product_number", "string")], found(reference("ProductDetail")))
+            },
+            "/site_names": {
+                "get": operation("site_names", "Id and name of every store", Vec::new(),
+                                 ok(json!({"type": "array", "items": reference("SiteResponse")})))
+            },
+            "/sites": {
+                "get": operation("sites", "Stores and agents matching the filters, with their opening if `open_now` \
+                                 or `open_at` is given", query_parameters(&site_opts()),
+                                 ok(json!({"type": "array", "items": {"oneOf": [reference("Site"), reference("SiteOpening")]}})))
+            },
+            "/sites/compare": {
+                "get": operation("site_compare", "Products only in one of two stores and in both",
+                                 query_parameters(&compare_opts()), ok(reference("SiteComparison")))
+            },
+            "/sites/{site_id}/products": {
+                "get": operation("site_products", "Top products in one store",
+                                 [vec![path_parameter("site_id", "string")], site_product_params].concat(),
+                                 ok(products))
+            },
+            "/sites/{site_id}/stats": {
+                "get": operation("site_stats", "Assortment summary for one store",
+                                 vec![path_parameter("site_id", "string")], ok(reference("SiteStats")))
+            },
+            "/sites/{site_id}/stock": {
+                "get": operation("site_stock", "Stock of every product listed in one store",
+                                 [vec![path_parameter("site_id", "string")],
+                                     query_parameters(&StockOpts::default())].concat(),
+                                 ok(json!({"type": "array", "items": reference("StockLevel")})))
+            },
+            "/basket/optimize": {
+                "get": operation("basket_optimize", "Bottles with the most alcohol within a budget", basket_params,
+                                 found(reference("Basket")))
+            },
+            "/watchlists": {
+                "post": with_body(operation("watchlists", "Create a watchlist", Vec::new(), ok(reference("Watchlist"))),
+                                  "NewWatchlist")
+            },
+            "/watchlists/{watchlist_id}": {
+                "get": operation("watchlist", "One watchlist", vec![path_parameter("watchlist_id", "integer")],
+                                 ok(reference("Watchlist"))),
+                "delete": operation("delete_watchlist", "Delete a watchlist", vec![path_parameter("watchlist_id", "integer")],
+                                    json!({"204": {"description": "Deleted"}, "404": {"description": "Not found"}}))
+            }
+        },
+        "components": {
+            "schemas": {
+                "Product": schema(&product(), PRODUCT_NULLABLE),
+                "ProductDetail": schema(&ProductDetail {
+                    product: product(),
+                    stores: vec![stock_level()],
+                }, &[PRODUCT_NULLABLE, STOCK_NULLABLE].concat()),
+                "Error": schema(&ErrorBody { status: 404, error: String::new() }, &[]),
+                "SiteResponse": schema(&SiteResponse { site_name: String::new(), site_id: SiteId::default() }, &[]),
+                "Site": schema(&site(), SITE_NULLABLE),
+                "SiteOpening": schema(&SiteOpening {
+                    site: site(),
+                    is_open: false,
+                    closed_reason: Some(String::new()),
+                    next_opening: Some(FixedOffset::east(3600).ymd(2020, 1, 1).and_hms(10, 0, 0)),
+                }, &[SITE_NULLABLE, SITE_OPENING_NULLABLE].concat()),
+                "SiteStats": schema(&site_stats(), &[]),
+                "StockLevel": schema(&stock_level(), STOCK_NULLABLE),
+                "SiteComparison": schema(&SiteComparison {
+                    site_a: SiteId::default(),
+                    site_b: SiteId::default(),
+                    only_a: vec![product()],
+                    only_b: vec![product()],
+                    both: vec![product()],
+                }, PRODUCT_NULLABLE),
+                "Basket": schema(&Basket {
+                    items: vec![BasketItem { product: product(), quantity: 0, cost: 0.0, alcohol_grams: 0.0 }],
+                    bottles: 0,
+                    total_cost: 0.0,
+                    total_alcohol_grams: 0.0,
+                    total_score: 0.0,
+                    optimal: true,
+                }, PRODUCT_NULLABLE),
+                "Watchlist": schema(&watchlist(), WATCHLIST_NULLABLE),
+                "NewWatchlist": schema(&NewWatchlist {
+                    name: String::new(),
+                    site_id: SiteId::default(),
+                    notifier: notifier(),
+                    products: vec![ProductId::default()],
+                }, &[]),
+            }
+        }
+    })
+}
+
+fn operation(id: &str, summary: &str, parameters: Vec<Value>, responses: Value) -> Value {
+    json!({"operationId": id, "summary": summary, "parameters": parameters, "responses": responses})
+}
+
+fn with_body(mut operation: Value, schema: &str) -> Value {
+    operation["requestBody"] = json!({"required": true, "content": {"application/json": {"schema": reference(schema)}}});
+    operation
+}
+
+fn ok(schema: Value) -> Value {
+    json!({
+        "200": {"description": "OK", "content": {"application/json": {"schema": schema}}},
+        "400": error("Invalid parameter"),
+        "404": {"description": "Not found"}
+    })
+}
+
+/// `ok` with the 404 explained by an `Error` body.
+fn found(schema: Value) -> Value {
+    let mut responses = ok(schema);
+    responses["404"] = error("Not found");
+    responses
+}
+
+fn error(description: &str) -> Value {
+    json!({"description": description, "content": {"application/json": {"schema": reference("Error")}}})
+}
+
+fn reference(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+fn path_parameter(name: &str, kind: &str) -> Value {
+    json!({"name": name, "in": "path", "required": true, "schema": {"type": kind}})
+}
+
+/// JSON schema of whatever `sample` serializes to, arrays need at least one element to describe their items.
+/// Properties named in `nullable` are marked so at any depth, they are the `Option` fields of the sample.
+pub fn schema<T: Serialize>(sample: &T, nullable: &[&str]) -> Value {
+    let mut schema = infer(&serde_json::to_value(sample).expect("Failed to serialize value"));
+    mark_nullable(&mut schema, nullable);
+    schema
+}
+
+fn mark_nullable(schema: &mut Value, nullable: &[&str]) {
+    if let Some(items) = schema.get_mut("items") {
+        mark_nullable(items, nullable);
+    }
+    if let Some(Value::Object(properties)) = schema.get_mut("properties") {
+        for (name, property) in properties.iter_mut() {
+            if nullable.contains(&name.as_str()) {
+                property["nullable"] = json!(true);
+            }
+            mark_nullable(property, nullable);
+        }
+    }
+}
+
+fn infer(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({"nullable": true}),
+        Value::Bool(_) => json!({"type": "boolean"}),
+        Value::Number(n) if n.is_f64() => json!({"type": "number"}),
+        Value::Number(_) => json!({"type": "integer"}),
+        Value::String(_) => json!({"type": "string"}),
+        Value::Array(items) => json!({"type": "array", "items": items.first().map(infer).unwrap_or_else(|| json!({}))}),
+        Value::Object(map) => {
+            let properties: Map<String, Value> = map.iter().map(|(k, v)| (k.clone(), infer(v))).collect();
+            json!({"type": "object", "properties": properties})
+        }
+    }
+}
+
+/// Query parameters of an options type, a parameter is required if the type can't be parsed without it.
+/// `Option` fields must be `Some` in `sample` to get a type.
+pub fn query_parameters<T: Serialize + DeserializeOwned>(sample: &T) -> Vec<Value> {
+    let fields = match serde_json::to_value(sample).expect("Failed to serialize value") {
+        Value::Object(map) => map,
+        _ => return Vec::new(),
+    };
+    let pairs: Vec<(String, String)> = fields.iter()
+        .map(|(k, v)| (k.clone(), match v {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }))
+        .collect();
+    fields.iter()
+        .map(|(name, value)| {
+            let without: Vec<String> = pairs.iter()
+                .filter(|(k, _)| k != name)
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            let required = Query::<T>::from_query(&without.join("&")).is_err();
+            json!({"name": name, "in": "query", "required": required, "schema": infer(value)})
+        })
+        .collect()
+}
+
+/// `Option` fields are `Some` so they get a type, `PRODUCT_NULLABLE` marks them nullable.
+fn product() -> Product {
+    Product {
+        product_name_thin: Some(String::new()),
+        producer_name: Some(String::new()),
+        supplier_name: Some(String::new()),
+        seal: Some(String::new()),
+        ethical_label: Some(String::new()),
+        sell_start_date: Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0)),
+        origin_level1: Some(String::new()),
+        origin_level2: Some(String::new()),
+        vintage: Some(0),
+        sub_category: Some(String::new()),
+        a_type: Some(String::new()),
+        style: Some(String::new()),
+        beverage_description_short: Some(String::new()),
+        usage_text: Some(String::new()),
+        taste: Some(String::new()),
+        ..Product::default()
+    }
+}
+
+fn site() -> Site {
+    Site {
+        alias: Some(String::new()),
+        display_name: Some(String::new()),
+        phone: Some(String::new()),
+        email: Some(String::new()),
+        services: Some(String::new()),
+        depot: Some(String::new()),
+        name: Some(String::new()),
+        opening_hours: vec![OpeningTime {
+            is_open: true,
+            reason: String::new(),
+            date: NaiveDate::from_ymd(2020, 1, 1),
+            open_from: Some(NaiveTime::from_hms(10, 0, 0)),
+            open_to: Some(NaiveTime::from_hms(19, 0, 0)),
+        }],
+        ..Site::default()
+    }
+}
+
+fn site_opts() -> SiteOpts {
+    SiteOpts {
+        is_store: Some(true),
+        is_agent: Some(false),
+        is_tasting_store: Some(false),
+        ..SiteOpts::default()
+    }
+}
+
+fn compare_opts() -> SiteCompareOpts {
+    SiteCompareOpts {
+        a: SiteId::default(),
+        b: SiteId::default(),
+        count: 0,
+        include_recycling: false,
+        max_volume: 0.0,
+        category: String::new(),
+    }
+}
+
+fn site_stats() -> SiteStats {
+    SiteStats {
+        site_id: SiteId::default(),
+        assortment_size: 0,
+        median_apk: 0.0,
+        best_per_category: vec![CategoryBest {
+            category: String::new(),
+            product_id: ProductId::default(),
+            product_name_bold: String::new(),
+            apk: 0.0,
+        }],
+    }
+}
+
+fn stock_level() -> StockLevel {
+    StockLevel {
+        site_id: SiteId::default(),
+        product_id: ProductId::default(),
+        stock: Some(0),
+        shelf: Some(String::new()),
+        last_seen: Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0)),
+    }
+}
+
+fn notifier() -> NotifierTarget {
+    NotifierTarget { kind: NotifierKind::Webhook, target: String::new() }
+}
+
+fn watchlist() -> Watchlist {
+    Watchlist {
+        watchlist_id: 0,
+        name: String::new(),
+        site_id: SiteId::default(),
+        notifier: notifier(),
+        items: vec![WatchItem { product_id: ProductId::default(), last_price: Some(0.0), last_in_store: false }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter<'a>(params: &'a [Value], name: &str) -> &'a Value {
+        params.iter().find(|p| p["name"] == name).unwrap()
+    }
+
+    #[test]
+    fn test_query_parameters() {
+        let params = query_parameters(&ProductOpts::default());
+        assert_eq!(json!(true), parameter(&params, "count")["required"]);
+        assert_eq!(json!(true), parameter(&params, "max_volume")["required"]);
+        assert_eq!(json!(false), parameter(&params, "category")["required"]);
+        assert_eq!(json!({"type": "integer"}), parameter(&params, "count")["schema"]);
+        assert_eq!(json!({"type": "number"}), parameter(&params, "max_volume")["schema"]);
+        assert_eq!(json!({"type": "boolean"}), parameter(&params, "open_now")["schema"]);
+        let spec = spec();
+        let scores = &parameter(spec["paths"]["/top"]["get"]["parameters"].as_array().unwrap(), "score")["schema"];
+        assert!(scores["enum"].as_array().unwrap().contains(&json!("standard_drinks_per_krona")));
+        let basket = &spec["paths"]["/basket/optimize"]["get"]["parameters"].as_array().unwrap();
+        assert_eq!(json!(true), parameter(basket, "budget")["required"]);
+        assert_eq!(json!(false), parameter(basket, "max_bottles")["required"]);
+        assert!(parameter(basket, "score")["schema"]["enum"].as_array().unwrap().contains(&json!("apk")));
+        let sites = query_parameters(&site_opts());
+        assert_eq!(json!({"type": "boolean"}), parameter(&sites, "is_store")["schema"]);
+        assert!(sites.iter().all(|p| p["required"] == json!(false)));
+    }
+
+    #[test]
+    fn test_schemas_follow_serde_renames() {
+        let spec = spec();
+        let product = &spec["components"]["schemas"]["Product"]["properties"];
+        assert_eq!(json!({"type": "string", "nullable": true}), product["Usage"]);
+        assert_eq!(json!({"type": "string"}), product["ProductNameBold"]);
+        assert_eq!(json!({"type": "number"}), product["ApkRecycling"]);
+        assert_eq!(json!({"type": "integer", "nullable": true}), product["Vintage"]);
+        assert_eq!(json!({"type": "string", "nullable": true}), product["SellStartDate"]);
+        assert_eq!(None, product["Price"].get("nullable"));
+        let site = &spec["components"]["schemas"]["Site"]["properties"];
+        assert_eq!(json!("object"), site["OpeningHours"]["items"]["type"]);
+        assert_eq!(json!(true), site["Name"]["nullable"]);
+        assert_eq!(json!(true), site["Alias"]["nullable"]);
+        assert_eq!(None, site["City"].get("nullable"));
+        assert_eq!(json!(true), site["OpeningHours"]["items"]["properties"]["OpenFrom"]["nullable"]);
+        assert_eq!(json!(true), site["OpeningHours"]["items"]["properties"]["OpenTo"]["nullable"]);
+        assert_eq!(json!({"type": "number"}), site["Position"]["properties"]["Lat"]);
+        let opening = &spec["components"]["schemas"]["SiteOpening"]["properties"];
+        assert_eq!(json!({"type": "string", "nullable": true}), opening["NextOpening"]);
+        assert_eq!(json!({"type": "string"}), opening["City"]);
+        let schemas = &spec["components"]["schemas"];
+        assert_eq!(json!(true), schemas["ProductDetail"]["properties"]["Stores"]["items"]["properties"]["Stock"]["nullable"]);
+        assert_eq!(json!(true), schemas["Basket"]["properties"]["Items"]["items"]["properties"]["Vintage"]["nullable"]);
+        assert_eq!(None, schemas["Watchlist"]["properties"]["Name"].get("nullable"));
+        assert_eq!(json!(true), schemas["Watchlist"]["properties"]["Items"]["items"]["properties"]["LastPrice"]["nullable"]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use crate::app::web;
+use crate::config::{CONFIG, LimitConfig};
+
+lazy_static! {
+    static ref PUBLIC: RateLimiter = RateLimiter::new(CONFIG.web.rate_limit.public);
+    static ref ADMIN: RateLimiter = RateLimiter::new(CONFIG.web.rate_limit.admin);
+}
+
+/// Method and route of the requests limited separately and more strictly than the rest of the api and the
+/// frontend: the operational routes, and the api resources changing watchlists, which also make outbound notifier
+/// calls. Api resources are named as in `web::API_RESOURCES` and match under `API_PREFIX` as well as at the root,
+/// `*` is any method.
+const ADMIN_ROUTES: &[(&str, &str)] = &[
+    ("*", "/metrics"),
+    ("*", "/test"),
+    ("*", "/error"),
+    ("POST", "watchlists"),
+    ("DELETE", "watchlist"),
+];
+
+/// Buckets kept before full ones are dropped, a full bucket is the same as no bucket.
+const MAX_TRACKED: usize = 10_000;
+
+pub struct RateLimiter {
+    limit: LimitConfig,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: LimitConfig) -> Self {
+        RateLimiter { limit, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available.
+    pub fn acquire(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&client) {
+            let limit = self.limit;
+            buckets.retain(|_, b| b.refilled(&limit, now) < limit.burst);
+        }
+        let limit = self.limit;
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: limit.burst, updated: now });
+        bucket.tokens = bucket.refilled(&limit, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if limit.per_second <= 0.0 {
+            return Err(Duration::from_secs(u64::from(u32::MAX)));
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second))
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, limit: &LimitConfig, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * limit.per_second).min(limit.burst)
+    }
+}
+
+/// Charges the request to its client's bucket, `Err` holds the wait before the client may retry.
+pub fn check(req: &ServiceRequest) -> Result<(), Duration> {
+    let config = &CONFIG.web.rate_limit;
+    if !config.enabled {
+        return Ok(());
+    }
+    let client = if config.trust_forwarded_for {
+        req.connection_info().realip_remote_addr().and_then(parse_addr)
+    } else {
+        req.peer_addr().map(|a| a.ip())
+    };
+    let client = match client {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let limiter: &RateLimiter = if is_admin(req.method(), req.path()) { &ADMIN } else { &PUBLIC };
+    limiter.acquire(client, Instant::now())
+}
+
+fn is_admin(method: &Method, path: &str) -> bool {
+    let resource = web::api_resource(path);
+    ADMIN_ROUTES.iter().any(|(route_method, route)| {
+        (*route_method == "*" || *route_method == method.as_str()) && (*route == path || Some(*route) == resource)
+    })
+}
+
+/// `Forwarded` addresses may carry a port, and IPv6 ones brackets.
+fn parse_addr(source: &str) -> Option<IpAddr> {
+    source.parse::<IpAddr>().ok()
+        .or_else(|| source.parse::<std::net::SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| source.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+/// Whole seconds for a `Retry-After` header, rounded up so a client that waits that long gets through.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    let secs = wait.as_secs();
+    if wait.subsec_nanos() > 0 { secs + 1 } else { secs.max(1) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = RateLimiter::new(LimitConfig { per_second: 2.0, burst: 3.0 });
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire(ip(1), start).is_ok());
+        }
+        let wait = limiter.acquire(ip(1), start).unwrap_err();
+        assert_eq!(Duration::from_millis(500), wait);
+        assert_eq!(1, retry_after_secs(wait));
+        assert!(limiter.acquire(ip(2), start).is_ok());
+        assert!(limiter.acquire(ip(1), start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.acquire(ip(1), start + Duration::from_millis(600)).is_err());
+        for _ in 0..3 {
+            assert!(limiter.acquire(ip(1), start + Duration::from_secs(60)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_admin_routes() {
+        for prefix in &["", "/api/v1"] {
+            let path = |p: &str| format!("{}{}", prefix, p);
+            assert!(is_admin(&Method::POST, &path("/watchlists")), "{}", prefix);
+            assert!(is_admin(&Method::DELETE, &path("/watchlists/3")), "{}", prefix);
+            assert!(!is_admin(&Method::GET, &path("/watchlists/3")), "{}", prefix);
+            assert!(!is_admin(&Method::GET, &path("/top")), "{}", prefix);
+            assert!(!is_admin(&Method::POST, &path("/top")), "{}", prefix);
+        }
+        assert!(is_admin(&Method::GET, "/metrics"));
+        assert!(is_admin(&Method::POST, "/test"));
+        assert!(!is_admin(&Method::GET, "/"));
+    }
+
+    #[test]
+    fn test_parse_forwarded_addr() {
+        assert_eq!(Some(ip(7)), parse_addr("10.0.0.7"));
+        assert_eq!(Some(ip(7)), parse_addr("10.0.0.7:5123"));
+        assert_eq!(Some("::1".parse().unwrap()), parse_addr("[::1]"));
+        assert_eq!(None, parse_addr("unknown"));
+        assert_eq!(3, retry_after_secs(Duration::from_secs(3)));
+    }
+}
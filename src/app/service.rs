@@ -1,33 +1,185 @@
-use crate::domain::models::product::{ProductOpts, Product, SiteResponse};
-use crate::external::client::ApiCaller;
+use crate::domain::models::basket::{Basket, BasketOpts};
+use crate::domain::models::ids::SiteId;
+use crate::domain::models::detail::{ProductDetail, ProductLookup};
+use crate::domain::models::product::{ProductOpts, Product, SiteResponse, SiteCompareOpts};
+use crate::config::CONFIG;
+use crate::external::{ApiCaller, create_caller};
 use crate::database::api;
+pub use crate::database::api::ProductStream;
 use crate::domain::result::Result;
-use crate::domain::models::site::Site;
+use crate::domain::models::projection::ProductField;
+use crate::domain::models::site::{Site, SiteStats, SiteComparison, SiteOpts, SiteOpening, open_time};
+use crate::domain::models::stock::{StockLevel, StockOpts};
+use crate::domain::models::serialization_helpers::select_fields;
+use serde_json::Value;
+use crate::domain::models::watchlist::{Watchlist, NewWatchlist, Notification, NotifierTarget};
+use crate::domain::models::refresh::RefreshSummary;
+use crate::external::notifier::{create_notifier, check_target};
+use crate::domain::result;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::RwLock;
 
 lazy_static! {
-    static ref CALLER: ApiCaller = ApiCaller::new();
+    static ref CALLER: Box<dyn ApiCaller> = create_caller(&CONFIG.upstream);
+    static ref NOTIFY_CLIENT: Client = Client::new();
+    static ref LAST_REFRESH: RwLock<Option<RefreshSummary>> = RwLock::new(None);
 }
 
 pub async fn update_db() -> Result<()> {
-    let products = CALLER.request_products_and_stores().await?;
-    api::update_db(products.0, &products.1, &products.2).await
+    let upstream = CALLER.request_products_and_stores().await?;
+    let load = api::update_db(upstream.products, &upstream.sites, &upstream.mapping).await?;
+    let summary = RefreshSummary::new(upstream.drift, load, api::snapshot_version());
+    if summary.drift {
+        warn!("Refresh finished with upstream drift: {}", summary);
+    } else {
+        info!("Refresh finished: {}", summary);
+    }
+    *LAST_REFRESH.write().unwrap() = Some(summary);
+    if let Err(e) = notify_watchers().await {
+        error!("Error evaluating watchlists: {}", result::fmt_backtrace(&e));
+    }
+    Ok(())
+}
+
+/// Compares every watchlist against the fresh snapshot and delivers one notification per watchlist with changes.
+/// Watch state is persisted even if delivery fails, so a broken notifier doesn't cause repeated alerts.
+pub async fn notify_watchers() -> Result<()> {
+    let states = api::select_watch_states().await?;
+    let mut ids: Vec<i64> = states.iter().map(|s| s.watchlist_id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let watchlists: HashMap<i64, Watchlist> = api::select_watchlists(&ids).await?.into_iter()
+        .map(|w| (w.watchlist_id, w))
+        .collect();
+    let mut pending: HashMap<i64, Notification> = HashMap::new();
+    for state in &states {
+        let watchlist = match watchlists.get(&state.watchlist_id) {
+            Some(w) => w,
+            None => continue
+        };
+        let events = state.evaluate(&watchlist.site_id);
+        if events.is_empty() {
+            continue;
+        }
+        pending.entry(state.watchlist_id)
+            .or_insert_with(|| Notification {
+                watchlist_id: watchlist.watchlist_id,
+                watchlist_name: watchlist.name.clone(),
+                events: Vec::new(),
+            })
+            .events.extend(events);
+    }
+    api::update_watch_states(&states).await?;
+    info!("Evaluated {} watched products, {} watchlists have changes", states.len(), pending.len());
+    for (id, notification) in pending {
+        let notified = match create_notifier(&watchlists[&id].notifier, &NOTIFY_CLIENT, &CONFIG.notify) {
+            Ok(notifier) => notifier.notify(&notification).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = notified {
+            warn!("Failed to notify watchlist={}: {}", id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Fails with `InvalidParameter` for a target the config doesn't allow notifying.
+pub fn check_notifier(target: &NotifierTarget) -> Result<()> {
+    check_target(target, &CONFIG.notify)
+}
+
+pub async fn create_watchlist(watchlist: NewWatchlist) -> Result<Option<Watchlist>> {
+    let id = api::insert_watchlist(&watchlist).await?;
+    api::select_watchlist(id).await
+}
+
+pub async fn fetch_watchlist(watchlist_id: i64) -> Result<Option<Watchlist>> {
+    api::select_watchlist(watchlist_id).await
+}
+
+pub async fn delete_watchlist(watchlist_id: i64) -> Result<bool> {
+    api::delete_watchlist(watchlist_id).await
 }
 
 pub async fn fetch_products(opts: ProductOpts) -> Result<Vec<Product>> {
     api::select_products(opts).await
 }
 
+pub async fn fetch_site_products(site_id: &SiteId, opts: ProductOpts) -> Result<Option<Vec<Product>>> {
+    api::select_site_products(site_id, opts).await
+}
+
+pub async fn stream_products(opts: ProductOpts) -> Result<ProductStream> {
+    api::stream_products(opts).await
+}
+
+pub async fn stream_site_products(site_id: &SiteId, opts: ProductOpts) -> Result<Option<ProductStream>> {
+    api::stream_site_products(site_id, opts).await
+}
+
+pub async fn fetch_product_fields(opts: ProductOpts, fields: &[&'static ProductField]) -> Result<Vec<Value>> {
+    api::select_product_fields(opts, fields).await
+}
+
+pub async fn fetch_site_product_fields(site_id: &SiteId, opts: ProductOpts, fields: &[&'static ProductField])
+    -> Result<Option<Vec<Value>>> {
+    api::select_site_product_fields(site_id, opts, fields).await
+}
+
+pub async fn fetch_product(lookup: ProductLookup) -> Result<Option<ProductDetail>> {
+    api::select_product_detail(&lookup).await
+}
+
+pub async fn fetch_site_stats(site_id: &SiteId) -> Result<Option<SiteStats>> {
+    api::select_site_stats_by_id(site_id).await
+}
+
+pub async fn fetch_site_stock(site_id: &SiteId, opts: StockOpts) -> Result<Option<Vec<StockLevel>>> {
+    api::select_site_stock(site_id, &opts).await
+}
+
+pub async fn optimize_basket(opts: BasketOpts) -> Result<Option<Basket>> {
+    api::optimize_basket(&opts).await
+}
+
+pub async fn compare_sites(opts: SiteCompareOpts) -> Result<Option<SiteComparison>> {
+    api::compare_sites(opts).await
+}
+
+/// Summary of the last successful refresh, `None` until one has finished.
+pub fn last_refresh() -> Option<RefreshSummary> {
+    LAST_REFRESH.read().unwrap().clone()
+}
+
+pub fn snapshot_version() -> i64 {
+    api::snapshot_version()
+}
+
 pub async fn init_db() -> Result<()> {
     api::init_db().await
 }
 
 pub async fn fetch_site_names() -> Result<Vec<SiteResponse>> {
-    let sites: Vec<Site> = api::select_sites().await?;
+    let sites: Vec<Site> = api::select_sites(&SiteOpts { is_store: Some(true), ..SiteOpts::default() }).await?;
     let mut names = Vec::with_capacity(sites.len());
     for site in sites {
-        if site.name != "" {
-            names.push(SiteResponse{site_id: site.site_id, site_name: site.name});
+        if let Some(site_name) = site.name {
+            names.push(SiteResponse{site_id: site.site_id, site_name});
         }
     }
     Ok(names)
 }
+
+/// Sites with their opening at the `open_now`/`open_at` time if one is given.
+pub async fn fetch_sites(opts: SiteOpts) -> Result<Vec<Value>> {
+    let time = open_time(opts.open_now, &opts.open_at)?;
+    let sites = api::select_sites_at(&opts, time.as_ref()).await?;
+    match time {
+        Some(time) => {
+            let openings: Vec<SiteOpening> = sites.into_iter().map(|site| SiteOpening::at(site, &time)).collect();
+            Ok(select_fields(&openings, &opts.fields)?)
+        }
+        None => Ok(select_fields(&sites, &opts.fields)?),
+    }
+}
@@ -1,50 +1,372 @@
 use std::io;
 
+use std::future::Future;
 use actix_web::http::{Method, StatusCode};
-use actix_utils::mpsc;
+use actix_web::http::header::{ETAG, IF_NONE_MATCH, CACHE_CONTROL, ACCEPT, CONTENT_DISPOSITION, RETRY_AFTER};
 use actix_web::{
     error, guard, middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
-use actix_web::web::Query;
+use actix_web::web::{Query, Json, Path};
 use bytes::Bytes;
-use crate::domain::models::product::{ProductOpts};
-use crate::domain::result::{Result, fmt_backtrace};
-use crate::app::service;
-use actix_cors::Cors;
+use futures::{StreamExt, TryFutureExt, TryStreamExt};
+use futures::future::{Either, ok};
+use actix_web::dev::{ResourceDef, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use crate::logging;
+use crate::domain::models::basket::BasketOpts;
+use crate::domain::models::ids::SiteId;
+use crate::domain::models::detail::ProductLookup;
+use crate::domain::models::product::{ProductOpts, SiteCompareOpts};
+use crate::domain::models::watchlist::NewWatchlist;
+use crate::domain::models::site::SiteOpts;
+use crate::domain::models::stock::StockOpts;
+use crate::domain::models::refresh::RefreshSummary;
+use crate::domain::result::{Result, ErrorKind, fmt_backtrace};
+use crate::app::service::ProductStream;
+use crate::app::{service, assets, cache, negotiation, openapi, rate_limit};
+use crate::app::openapi::API_PREFIX;
+use crate::app::export::{ExportOpts, ExportFormat, Exporter};
+use crate::app::cache::{RESPONSE_CACHE, CacheStats};
+use crate::config::CONFIG;
+use actix_cors::{Cors, CorsFactory};
 use serde::Serialize;
+use serde_json::Value;
 
+const JSON_CONTENT_TYPE: &str = "application/json; charset=utf-8";
 
-async fn get_top(product_opts: Query<ProductOpts>) -> HttpResponse {
-    ok_or_err(service::fetch_products(product_opts.0).await)
+/// Correlation id of a request, echoed on the response and attached to everything logged while handling it.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// actix' default format with the request id appended.
+const ACCESS_LOG_FORMAT: &str = "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T %{x-request-id}o";
+
+/// Items serialized per chunk of a streamed list response.
+const STREAM_CHUNK: usize = 256;
+
+async fn get_top(req: HttpRequest, product_opts: Query<ProductOpts>, export_opts: Query<ExportOpts>) -> HttpResponse {
+    let opts = product_opts.0.normalize();
+    let fields = match opts.validate_score().and_then(|_| opts.projection()) {
+        Ok(fields) => fields,
+        Err(e) => return ok_or_err::<()>(Err(e)),
+    };
+    match exporter(&req, &export_opts, opts.count) {
+        Ok(Some(exporter)) => return export(exporter, "top", service::stream_products(opts).await.map(Some)).await,
+        Ok(None) => {}
+        Err(e) => return ok_or_err::<()>(Err(e)),
+    }
+    match (opts.cache_key(), fields) {
+        (Some(key), Some(fields)) => cached(&req, format!("top?{}", key), async {
+            service::fetch_product_fields(opts, &fields).await.map(Some)
+        }).await,
+        (Some(key), None) => cached(&req, format!("top?{}", key), async {
+            service::fetch_products(opts).await.map(Some)
+        }).await,
+        (None, Some(fields)) => list_or_err(service::fetch_product_fields(opts, &fields).await),
+        (None, None) => list_or_err(service::fetch_products(opts).await)
+    }
+}
+
+async fn get_product(product_id: Path<String>) -> HttpResponse {
+    product_or_404(ProductLookup::Id(product_id.0.into())).await
+}
+
+async fn get_product_by_number(product_number: Path<String>) -> HttpResponse {
+    product_or_404(ProductLookup::Number(product_number.0.into())).await
+}
+
+async fn product_or_404(lookup: ProductLookup) -> HttpResponse {
+    let missing = format!("product with {}", lookup);
+    match service::fetch_product(lookup).await {
+        Ok(Some(detail)) => to_ok(&detail),
+        Ok(None) => ok_or_err::<()>(Err(ErrorKind::NotFound(missing).into())),
+        Err(e) => ok_or_err::<()>(Err(e)),
+    }
 }
 
 async fn get_site_names() -> HttpResponse {
-    ok_or_err(service::fetch_site_names().await)
+    list_or_err(service::fetch_site_names().await)
+}
+
+async fn get_sites(site_opts: Query<SiteOpts>) -> HttpResponse {
+    list_or_err(service::fetch_sites(site_opts.0).await)
+}
+
+async fn get_site_products(req: HttpRequest, site_id: Path<SiteId>, product_opts: Query<ProductOpts>,
+                           export_opts: Query<ExportOpts>) -> HttpResponse {
+    let opts = product_opts.0.normalize();
+    let fields = match opts.validate_score().and_then(|_| opts.projection()) {
+        Ok(fields) => fields,
+        Err(e) => return ok_or_err::<()>(Err(e)),
+    };
+    match exporter(&req, &export_opts, opts.count) {
+        Ok(Some(exporter)) => return export(exporter, &format!("site-{}", site_id.0),
+                                            service::stream_site_products(&site_id.0, opts).await).await,
+        Ok(None) => {}
+        Err(e) => return ok_or_err::<()>(Err(e)),
+    }
+    match (opts.cache_key(), fields) {
+        (Some(key), Some(fields)) => cached(&req, format!("sites/{}/products?{}", site_id.0, key),
+                                            service::fetch_site_product_fields(&site_id.0, opts, &fields)).await,
+        (Some(key), None) => cached(&req, format!("sites/{}/products?{}", site_id.0, key),
+                                    service::fetch_site_products(&site_id.0, opts)).await,
+        (None, Some(fields)) => list_or_404(service::fetch_site_product_fields(&site_id.0, opts, &fields).await),
+        (None, None) => list_or_404(service::fetch_site_products(&site_id.0, opts).await)
+    }
+}
+
+async fn get_site_stats(site_id: Path<SiteId>) -> HttpResponse {
+    ok_or_404(service::fetch_site_stats(&site_id.0).await)
+}
+
+async fn get_site_stock(site_id: Path<SiteId>, stock_opts: Query<StockOpts>) -> HttpResponse {
+    list_or_404(service::fetch_site_stock(&site_id.0, stock_opts.0).await)
+}
+
+async fn get_site_compare(compare_opts: Query<SiteCompareOpts>) -> HttpResponse {
+    ok_or_404(service::compare_sites(compare_opts.0).await)
+}
+
+async fn get_basket_optimize(basket_opts: Query<BasketOpts>) -> HttpResponse {
+    let opts = basket_opts.0.normalize();
+    if let Err(e) = opts.validate() {
+        return ok_or_err::<()>(Err(e));
+    }
+    let missing = format!("basket within {} SEK covering categories={}", opts.budget, opts.categories);
+    match service::optimize_basket(opts).await {
+        Ok(Some(basket)) => to_ok(&basket),
+        Ok(None) => ok_or_err::<()>(Err(ErrorKind::NotFound(missing).into())),
+        Err(e) => ok_or_err::<()>(Err(e)),
+    }
+}
+
+async fn post_watchlist(watchlist: Json<NewWatchlist>) -> HttpResponse {
+    if let Err(e) = service::check_notifier(&watchlist.notifier) {
+        return ok_or_err::<()>(Err(e));
+    }
+    ok_or_404(service::create_watchlist(watchlist.0).await)
+}
+
+async fn get_watchlist(watchlist_id: Path<i64>) -> HttpResponse {
+    ok_or_404(service::fetch_watchlist(watchlist_id.0).await)
+}
+
+async fn delete_watchlist(watchlist_id: Path<i64>) -> HttpResponse {
+    match service::delete_watchlist(watchlist_id.0).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Caught error responding to request: {}", fmt_backtrace(&e));
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+lazy_static! {
+    static ref OPENAPI: Value = openapi::spec();
+}
+
+/// Body of error responses that say what went wrong.
+#[derive(Serialize)]
+pub struct ErrorBody {
+    #[serde(rename="Status")]
+    pub status: u16,
+    #[serde(rename="Error")]
+    pub error: String,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    #[serde(flatten)]
+    cache: CacheStats,
+    #[serde(rename="LastRefresh")]
+    last_refresh: Option<RefreshSummary>,
+}
+
+async fn get_metrics() -> HttpResponse {
+    to_ok(&Metrics { cache: RESPONSE_CACHE.stats(), last_refresh: service::last_refresh() })
+}
+
+/// Serves `fetch` through the response cache, answering 304 if the client already holds the current version.
+async fn cached<T, F>(req: &HttpRequest, key: String, fetch: F) -> HttpResponse
+    where T: Serialize, F: Future<Output=Result<Option<T>>> {
+    let version = service::snapshot_version();
+    let tag = cache::etag(&key, version);
+    let cache_control = format!("public, max-age={}", CONFIG.cache.max_age_secs);
+    let matches = req.headers().get(IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.split(',').any(|t| t.trim() == tag || t.trim() == "*"))
+        .unwrap_or(false);
+    if matches {
+        return HttpResponse::NotModified()
+            .header(ETAG, tag)
+            .header(CACHE_CONTROL, cache_control)
+            .finish();
+    }
+    let body = match RESPONSE_CACHE.get(&key, version) {
+        Some(body) => body,
+        None => match fetch.await {
+            Ok(Some(t)) => {
+                let body = Bytes::from(serde_json::to_string(&t).expect("Failed to serialize value"));
+                RESPONSE_CACHE.insert(key, version, body.clone());
+                body
+            }
+            Ok(None) => return HttpResponse::NotFound().finish(),
+            Err(e) => return ok_or_err::<T>(Err(e))
+        }
+    };
+    HttpResponse::Ok()
+        .header(ETAG, tag)
+        .header(CACHE_CONTROL, cache_control)
+        .content_type(JSON_CONTENT_TYPE)
+        .body(body)
+}
+
+/// Export requested with `format=` or the Accept header, `None` leaves the request to the regular JSON response.
+fn exporter(req: &HttpRequest, opts: &ExportOpts, count: usize) -> Result<Option<Exporter>> {
+    let format = export_format(opts, req.headers().get(ACCEPT).and_then(|h| h.to_str().ok()))?;
+    if format == ExportFormat::Json && opts.columns.is_empty() {
+        return Ok(None);
+    }
+    let exporter = Exporter::new(format, &opts.columns)?;
+    exporter.check_count(count)?;
+    Ok(Some(exporter))
+}
+
+/// `format=` wins over the first export format in the Accept header, plain JSON is the default.
+fn export_format(opts: &ExportOpts, accept: Option<&str>) -> Result<ExportFormat> {
+    if !opts.format.is_empty() {
+        return ExportFormat::parse(&opts.format);
+    }
+    Ok(accept.and_then(ExportFormat::from_accept).unwrap_or(ExportFormat::Json))
+}
+
+/// Streams the encoded rows as an attachment named `name` while they are read from the database.
+/// An error before the first product is answered like any other, later ones abort the response.
+async fn export(exporter: Exporter, name: &str, res: Result<Option<ProductStream>>) -> HttpResponse {
+    let mut products = match res {
+        Ok(Some(products)) => products,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => return ok_or_err::<()>(Err(e)),
+    };
+    let first = match products.next().await {
+        Some(Err(e)) => return ok_or_err::<()>(Err(e)),
+        first => first,
+    };
+    let format = exporter.format();
+    let chunks = exporter.encode(futures::stream::iter(first).chain(products))
+        .map_err(|e| {
+            error!("Caught error streaming export: {}", fmt_backtrace(&e));
+            error::ErrorInternalServerError("export failed")
+        });
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header(CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", name, format.extension()))
+        .streaming(chunks)
+}
+
+fn ok_or_404<T: Sized + Serialize>(res: Result<Option<T>>) -> HttpResponse {
+    match res {
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Ok(Some(t)) => to_ok(&t),
+        Err(e) => ok_or_err::<T>(Err(e)),
+    }
 }
 
 fn ok_or_err<T: Sized + Serialize>(res: Result<T>) -> HttpResponse {
     res.map(|t| -> HttpResponse {
         to_ok(&t)
     }).unwrap_or_else(|e| -> HttpResponse {
+        if let ErrorKind::InvalidParameter(..) = e.kind() {
+            return error_response(StatusCode::BAD_REQUEST, e.to_string());
+        }
+        if let ErrorKind::NotFound(..) = e.kind() {
+            return error_response(StatusCode::NOT_FOUND, e.to_string());
+        }
         error!("Caught error responding to request: {}", fmt_backtrace(&e));
         HttpResponse::InternalServerError().finish()
     })
 }
 
+fn error_response(status: StatusCode, error: String) -> HttpResponse {
+    let body = ErrorBody { status: status.as_u16(), error };
+    HttpResponse::build(status)
+        .content_type(JSON_CONTENT_TYPE)
+        .body(serde_json::to_vec(&body).expect("Failed to serialize value"))
+}
+
+/// Query strings and bodies that don't deserialize are refused with an `ErrorBody` like every other 400.
+fn bad_request(err: impl std::fmt::Display) -> Error {
+    let error = err.to_string();
+    error::InternalError::from_response(error.clone(), error_response(StatusCode::BAD_REQUEST, error)).into()
+}
+
 fn to_ok<T: Sized + Serialize>(val: &T) -> HttpResponse {
-    let text = serde_json::to_string(val)
+    let body = serde_json::to_vec(val)
         .expect("Failed to serialize value");
-    let (tx, rx_body) = mpsc::channel();
-    let _ = tx.send(Ok::<_, Error>(Bytes::from(text)));
+    HttpResponse::Ok()
+        .content_type(JSON_CONTENT_TYPE)
+        .body(body)
+}
 
-    HttpResponse::Ok().streaming(rx_body)
+fn list_or_404<T: Serialize + 'static>(res: Result<Option<Vec<T>>>) -> HttpResponse {
+    match res {
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Ok(Some(items)) => to_ok_streamed(items),
+        Err(e) => ok_or_err::<()>(Err(e)),
+    }
+}
+
+fn list_or_err<T: Serialize + 'static>(res: Result<Vec<T>>) -> HttpResponse {
+    list_or_404(res.map(Some))
+}
+
+/// Serializes `items` as a JSON array while the response is written, `STREAM_CHUNK` items at a time.
+fn to_ok_streamed<T: Serialize + 'static>(items: Vec<T>) -> HttpResponse {
+    let mut rest = items.into_iter();
+    let mut first = true;
+    let chunks = std::iter::from_fn(move || {
+        let mut buf = Vec::new();
+        for item in rest.by_ref().take(STREAM_CHUNK) {
+            if !first {
+                buf.push(b',');
+            }
+            first = false;
+            serde_json::to_writer(&mut buf, &item).expect("Failed to serialize value");
+        }
+        if buf.is_empty() { None } else { Some(Bytes::from(buf)) }
+    });
+    let body = std::iter::once(Bytes::from_static(b"["))
+        .chain(chunks)
+        .chain(std::iter::once(Bytes::from_static(b"]")));
+    HttpResponse::Ok()
+        .content_type(JSON_CONTENT_TYPE)
+        .streaming(futures::stream::iter(body.map(Ok::<_, Error>)))
 }
 
 pub async fn start() -> io::Result<()> {
-    let bind = "127.0.0.1:8080";
+    let bind = CONFIG.web.bind.as_str();
 
     HttpServer::new(|| {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap_fn(negotiated)
+            .wrap_fn(|req, srv| match rate_limit::check(&req) {
+                Ok(()) => Either::Left(srv.call(req)),
+                Err(wait) => {
+                    let res = HttpResponse::TooManyRequests()
+                        .header(RETRY_AFTER, rate_limit::retry_after_secs(wait).to_string())
+                        .finish();
+                    Either::Right(ok(req.into_response(res)))
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let id = request_id(&req);
+                let header = HeaderValue::from_str(&id).expect("Request ids are header safe");
+                logging::correlated(id, srv.call(req)).map_ok(move |mut res| {
+                    res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+                    res
+                })
+            })
+            .wrap(middleware::Logger::new(ACCESS_LOG_FORMAT))
+            .wrap(middleware::Compress::default())
             .service(
                 web::resource("/test").to(|req: HttpRequest| match *req.method() {
                     Method::GET => HttpResponse::Ok(),
@@ -58,18 +380,16 @@ pub async fn start() -> io::Result<()> {
                     StatusCode::INTERNAL_SERVER_ERROR,
                 )
             }))
-            .wrap(Cors::new()
-                .allowed_origin("*")
-                .allowed_methods(vec!["GET", "POST", "OPTIONS"])
-                .finish()
-            )
+            .wrap(cors())
+            .service(web::scope(API_PREFIX)
+                .service(web::resource("/openapi.json").route(web::get().to(get_openapi)))
+                .configure(|cfg| api(cfg, true)))
+            .configure(|cfg| api(cfg, false))
             .service(
-                web::resource("/top").
-                    route(web::get().to(get_top))
-            ).service(
-            web::resource("/site_names").
-                route(web::get().to(get_site_names))
+            web::resource("/metrics").
+                route(web::get().to(get_metrics))
             )
+            .configure(assets::configure)
             .default_service(
                 // 404 for GET request
                 web::resource("")
@@ -87,6 +407,286 @@ pub async fn start() -> io::Result<()> {
         .await
 }
 
+/// The caller's id if it sent a usable one, so a request can be followed across services, otherwise a new one.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .map(String::from)
+        .unwrap_or_else(logging::new_id)
+}
+
+fn cors() -> CorsFactory {
+    let config = &CONFIG.web.cors;
+    let mut cors = Cors::new()
+        .allowed_methods(config.allowed_methods.iter().map(|m| m.as_str()))
+        .max_age(config.max_age_secs);
+    if !config.allowed_origins.iter().any(|o| o == "*") {
+        for origin in &config.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    cors.finish()
+}
+
+/// Refuses with 406 before the handler runs when the client can't take what the route produces. Responses of
+/// routes that aren't known up front, the assets, are checked once they are made instead.
+fn negotiated<S>(req: ServiceRequest, srv: &mut S) -> impl Future<Output=std::result::Result<ServiceResponse, Error>>
+    where S: Service<Request=ServiceRequest, Response=ServiceResponse, Error=Error> {
+    let accept = req.headers().get(ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    if let Some(content_type) = produces(&req) {
+        if !negotiation::accepts(accept.as_deref(), content_type) {
+            return Either::Right(ok(req.into_response(HttpResponse::NotAcceptable().finish())));
+        }
+    }
+    Either::Left(srv.call(req).map_ok(move |res| negotiation::negotiate(accept.as_deref(), res)))
+}
+
+/// Content type a request will be answered with if it succeeds, `None` if it has no body or isn't known
+/// before the handler runs.
+fn produces(req: &ServiceRequest) -> Option<&'static str> {
+    if req.method() == Method::DELETE {
+        return None;
+    }
+    let path = req.path();
+    if path == "/metrics" || path == format!("{}/openapi.json", API_PREFIX) {
+        return Some(JSON_CONTENT_TYPE);
+    }
+    let name = api_resource(path)?;
+    if !EXPORTING_RESOURCES.contains(&name) {
+        return Some(JSON_CONTENT_TYPE);
+    }
+    // An invalid format is refused by the handler
+    let opts = Query::<ExportOpts>::from_query(req.query_string()).ok()?;
+    let accept = req.headers().get(ACCEPT).and_then(|h| h.to_str().ok());
+    export_format(&opts, accept).ok().map(|format| format.content_type())
+}
+
+/// Name of the api resource serving `path`, under `API_PREFIX` or at the root.
+pub fn api_resource(path: &str) -> Option<&'static str> {
+    let path = path.strip_prefix(API_PREFIX).unwrap_or(path);
+    API_ROUTES.iter()
+        .find(|(_, def)| def.is_match(path))
+        .map(|(name, _)| *name)
+}
+
+lazy_static! {
+    static ref API_ROUTES: Vec<(&'static str, ResourceDef)> = API_RESOURCES.iter()
+        .map(|(name, path)| (*name, ResourceDef::new(*path)))
+        .collect();
+}
+
+/// Resources answering with an export when one is asked for, see `exporter`.
+const EXPORTING_RESOURCES: &[&str] = &["top", "site_products"];
+
+/// Name and path of every resource of the public api, the names are the `operationId`s in the OpenAPI document.
+const API_RESOURCES: &[(&str, &str)] = &[
+    ("top", "/top"),
+    ("product", "/products/{product_id}"),
+    ("product_by_number", "/products/by-number/{product_number}"),
+    ("site_names", "/site_names"),
+    ("sites", "/sites"),
+    ("site_compare", "/sites/compare"),
+    ("site_products", "/sites/{site_id}/products"),
+    ("site_stats", "/sites/{site_id}/stats"),
+    ("site_stock", "/sites/{site_id}/stock"),
+    ("basket_optimize", "/basket/optimize"),
+    ("watchlists", "/watchlists"),
+    ("watchlist", "/watchlists/{watchlist_id}"),
+];
+
+/// The public api, served under `API_PREFIX` and, for clients from before it was versioned, at the root.
+/// Only the versioned resources are `named`, their names are the `operationId`s in the OpenAPI document.
+fn api(cfg: &mut web::ServiceConfig, named: bool) {
+    let resource = |name: &str| {
+        let (_, path) = API_RESOURCES.iter().find(|(n, _)| *n == name).expect("Resource missing from API_RESOURCES");
+        if named {
+            web::resource(*path).name(name)
+        } else {
+            web::resource(*path)
+        }
+    };
+    cfg.app_data(web::QueryConfig::default().error_handler(|e, _| bad_request(e)))
+        .app_data(web::JsonConfig::default().error_handler(|e, _| bad_request(e)))
+        .service(resource("top")
+            .route(web::get().to(get_top)))
+        .service(resource("product")
+            .route(web::get().to(get_product)))
+        .service(resource("product_by_number")
+            .route(web::get().to(get_product_by_number)))
+        .service(resource("site_names")
+            .route(web::get().to(get_site_names)))
+        .service(resource("sites")
+            .route(web::get().to(get_sites)))
+        .service(resource("site_compare")
+            .route(web::get().to(get_site_compare)))
+        .service(resource("site_products")
+            .route(web::get().to(get_site_products)))
+        .service(resource("site_stats")
+            .route(web::get().to(get_site_stats)))
+        .service(resource("site_stock")
+            .route(web::get().to(get_site_stock)))
+        .service(resource("basket_optimize")
+            .route(web::get().to(get_basket_optimize)))
+        .service(resource("watchlists")
+            .route(web::post().to(post_watchlist)))
+        .service(resource("watchlist")
+            .route(web::get().to(get_watchlist))
+            .route(web::delete().to(delete_watchlist)));
+}
+
+async fn get_openapi() -> HttpResponse {
+    to_ok(&*OPENAPI)
+}
+
 async fn p404() -> HttpResponse {
     HttpResponse::NotFound().finish()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::test::read_body;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn body(res: HttpResponse) -> String {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let bytes = read_body(ServiceResponse::new(req, res)).await;
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_streamed_list() {
+        let items: Vec<usize> = (0..STREAM_CHUNK * 2 + 1).collect();
+        let res = to_ok_streamed(items.clone());
+        assert_eq!(JSON_CONTENT_TYPE, res.headers().get(CONTENT_TYPE).unwrap());
+        assert_eq!(items, serde_json::from_str::<Vec<usize>>(&body(res).await).unwrap());
+        assert_eq!("[]", body(to_ok_streamed(Vec::<usize>::new())).await);
+    }
+
+    #[actix_rt::test]
+    async fn test_not_found_body() {
+        let res = ok_or_err::<()>(Err(ErrorKind::NotFound(String::from("product with product_id=1")).into()));
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        assert_eq!(JSON_CONTENT_TYPE, res.headers().get(CONTENT_TYPE).unwrap());
+        assert_eq!(r#"{"Status":404,"Error":"no product with product_id=1 found"}"#, body(res).await);
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_request_body() {
+        let res = ok_or_err::<()>(Err(ErrorKind::InvalidParameter(String::from("count"), String::from("-1")).into()));
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(JSON_CONTENT_TYPE, res.headers().get(CONTENT_TYPE).unwrap());
+        let body: Value = serde_json::from_str(&body(res).await).unwrap();
+        assert_eq!(400, body["Status"]);
+
+        // Refused by the extractor before the handler runs
+        let mut app = actix_web::test::init_service(App::new()
+            .service(web::scope(API_PREFIX).configure(|cfg| api(cfg, true)))).await;
+        let req = actix_web::test::TestRequest::get().uri("/api/v1/top?count=many").to_request();
+        let res = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(JSON_CONTENT_TYPE, res.headers().get(CONTENT_TYPE).unwrap());
+        let body: Value = serde_json::from_slice(&read_body(res).await).unwrap();
+        assert_eq!(400, body["Status"]);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let req = |id: &str| actix_web::test::TestRequest::default().header(REQUEST_ID_HEADER, id).to_srv_request();
+        assert_eq!("abc-123_x", request_id(&req("abc-123_x")));
+        let generated = request_id(&req("no spaces"));
+        assert_eq!(16, generated.len());
+        assert_ne!(generated, request_id(&actix_web::test::TestRequest::default().to_srv_request()));
+    }
+
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn handled() -> HttpResponse {
+        HANDLED.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::Ok().content_type(JSON_CONTENT_TYPE).body("[]")
+    }
+
+    /// A request for something the route can't produce is refused without running its handler.
+    #[actix_rt::test]
+    async fn test_not_acceptable_before_handler() {
+        let mut app = actix_web::test::init_service(App::new()
+            .wrap_fn(negotiated)
+            .service(web::scope(API_PREFIX).route("/top", web::get().to(handled)))
+            .route("/sites", web::get().to(handled))).await;
+        let cases = [("/api/v1/top", "application/xml", StatusCode::NOT_ACCEPTABLE),
+            ("/api/v1/top?format=csv", "application/json", StatusCode::NOT_ACCEPTABLE),
+            ("/sites", "text/csv", StatusCode::NOT_ACCEPTABLE),
+            ("/api/v1/top", "text/html, application/*;q=0.8", StatusCode::OK),
+            ("/sites", "*/*", StatusCode::OK)];
+        for (uri, accept, status) in &cases {
+            let req = actix_web::test::TestRequest::get().uri(uri).header(ACCEPT, *accept).to_request();
+            assert_eq!(*status, actix_web::test::call_service(&mut app, req).await.status(), "{} {}", uri, accept);
+        }
+        assert_eq!(2, HANDLED.load(Ordering::SeqCst));
+    }
+
+    /// Path of the resource named `name`, with the comma separated `params` filled in.
+    async fn resolve(req: HttpRequest, path: Path<(String, String)>) -> HttpResponse {
+        let (name, params) = path.into_inner();
+        let params: Vec<&str> = params.split(',').filter(|p| !p.is_empty()).collect();
+        match req.url_for(&name, params) {
+            Ok(url) => HttpResponse::Ok().body(url.path().to_string()),
+            Err(_) => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    /// Every operation in the OpenAPI document must have a unique id naming a resource served at the documented
+    /// path, and every resource of the api must be documented.
+    #[actix_rt::test]
+    async fn test_openapi_matches_routes() {
+        let mut app = actix_web::test::init_service(App::new()
+            .service(web::scope(API_PREFIX).configure(|cfg| api(cfg, true)))
+            .route("/resolve/{name}/{params}", web::get().to(resolve))).await;
+        let spec = openapi::spec();
+        let paths = spec["paths"].as_object().unwrap();
+        assert!(!paths.is_empty());
+        let mut operation_ids = HashSet::new();
+        for (path, item) in paths {
+            let placeholders: Vec<&str> = path.split('/')
+                .filter(|s| s.starts_with('{'))
+                .collect();
+            let params: Vec<String> = (0..placeholders.len()).map(|i| format!("p{}", i)).collect();
+            let mut expected = format!("{}{}", API_PREFIX, path);
+            for (placeholder, param) in placeholders.iter().zip(&params) {
+                expected = expected.replace(placeholder, param);
+            }
+            for (method, operation) in item.as_object().unwrap() {
+                let id = operation["operationId"].as_str().unwrap();
+                assert!(operation_ids.insert(id), "operationId {} isn't unique", id);
+                let name = id.strip_prefix(&format!("{}_", method)).unwrap_or(id);
+                let req = actix_web::test::TestRequest::get()
+                    .uri(&format!("/resolve/{}/{},", name, params.join(",")))
+                    .to_request();
+                let resolved = actix_web::test::read_response(&mut app, req).await;
+                assert_eq!(expected, String::from_utf8(resolved.to_vec()).unwrap(),
+                           "{} {} isn't served as documented", method, path);
+
+                // A request with a method the resource has no route for is refused before reaching a handler.
+                let unrouted = if method == "patch" { Method::PUT } else { Method::PATCH };
+                let req = actix_web::test::TestRequest::with_uri(&expected).method(unrouted).to_request();
+                let res = actix_web::test::call_service(&mut app, req).await;
+                assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status(), "{} isn't a resource", path);
+            }
+        }
+        for (name, path) in API_RESOURCES {
+            let req = actix_web::test::TestRequest::get().uri(&format!("/resolve/{}/p0,p1,", name)).to_request();
+            let res = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(StatusCode::OK, res.status(), "{} isn't registered", name);
+            let operations = paths.get(*path).and_then(|item| item.as_object())
+                .unwrap_or_else(|| panic!("{} isn't documented", path));
+            let documented = operations.iter()
+                .any(|(method, o)| o["operationId"] == *name || o["operationId"] == format!("{}_{}", method, name));
+            assert!(documented, "{} isn't documented as {}", path, name);
+        }
+    }
+}
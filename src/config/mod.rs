@@ -0,0 +1,381 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use crate::domain::result::Result;
+
+static CONFIG_FILE: &str = "config.yml";
+static CONFIG_ENV: &str = "SYSTEMET_CONFIG";
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load()
+        .unwrap_or_else(|e| {
+            error!("Failed to load config, falling back to defaults: {}", e);
+            Config::default()
+        });
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub web: WebConfig,
+
+    #[serde(default)]
+    pub cache: CacheConfig,
+
+    #[serde(default)]
+    pub database: DatabaseConfig,
+
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+
+    #[serde(default)]
+    pub notify: NotifyConfig,
+
+    /// Formulas by name, selectable with `score=` next to the built in scores, see `domain::scoring`.
+    #[serde(default)]
+    pub scores: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Backend {
+    #[serde(rename="sqlite")]
+    Sqlite,
+    /// Only available when built with the `postgres` feature.
+    #[serde(rename="postgres")]
+    Postgres,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DatabaseConfig {
+    #[serde(default="default_backend")]
+    pub backend: Backend,
+
+    /// Database file for the sqlite backend.
+    #[serde(default="default_db_path")]
+    pub path: String,
+
+    /// Connection string for the postgres backend, e.g. `host=localhost user=postgres dbname=systemet`.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    pub url: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { backend: default_backend(), path: default_db_path(), url: String::new() }
+    }
+}
+
+fn default_backend() -> Backend {
+    Backend::Sqlite
+}
+
+fn default_db_path() -> String {
+    String::from("products.db")
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum UpstreamApi {
+    /// One response per payload, `product/v1/product`, `site/v1/site` and `getproductswithstore`.
+    #[serde(rename="bulk")]
+    Bulk,
+    /// The paginated product search, store assortments are searched one store at a time.
+    #[serde(rename="search")]
+    Search,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamConfig {
+    #[serde(default="default_upstream_api")]
+    pub api: UpstreamApi,
+
+    /// Base url of the search api, only used by `search`.
+    #[serde(default="default_search_url")]
+    pub base_url: String,
+
+    /// Sent as `Ocp-Apim-Subscription-Key` by the search api.
+    #[serde(default)]
+    pub api_key: String,
+
+    #[serde(default="default_page_size")]
+    pub page_size: usize,
+
+    /// Fails a search that never reports its last page instead of loading part of the catalogue.
+    #[serde(default="default_max_pages")]
+    pub max_pages: usize,
+
+    /// Store assortments searched at the same time.
+    #[serde(default="default_store_concurrency")]
+    pub concurrency: usize,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            api: default_upstream_api(),
+            base_url: default_search_url(),
+            api_key: String::new(),
+            page_size: default_page_size(),
+            max_pages: default_max_pages(),
+            concurrency: default_store_concurrency(),
+        }
+    }
+}
+
+fn default_upstream_api() -> UpstreamApi {
+    UpstreamApi::Bulk
+}
+
+fn default_search_url() -> String {
+    String::from("https://api-extern.systembolaget.se/sb-api-ecommerce/v1")
+}
+
+fn default_page_size() -> usize {
+    30
+}
+
+fn default_max_pages() -> usize {
+    2000
+}
+
+fn default_store_concurrency() -> usize {
+    4
+}
+
+/// Where watchlist notifications may be delivered, targets come from whoever creates the watchlist.
+#[derive(Debug, Deserialize)]
+pub struct NotifyConfig {
+    /// Hosts webhooks may be sent to, compared ignoring case. Webhooks are refused if there are none.
+    #[serde(default)]
+    pub webhook_hosts: Vec<String>,
+
+    /// Allows the `file` and `stdout` notifiers, for trying watchlists out locally.
+    #[serde(default)]
+    pub allow_local: bool,
+
+    /// Directory `file` targets are relative paths in.
+    #[serde(default="default_notify_dir")]
+    pub file_dir: String,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig { webhook_hosts: Vec::new(), allow_local: false, file_dir: default_notify_dir() }
+    }
+}
+
+fn default_notify_dir() -> String {
+    String::from("notifications")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default="default_cache_entries")]
+    pub max_entries: usize,
+
+    #[serde(default="default_cache_bytes")]
+    pub max_bytes: usize,
+
+    /// `max-age` handed to clients, they revalidate with the ETag after this.
+    #[serde(default="default_max_age")]
+    pub max_age_secs: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: default_cache_entries(),
+            max_bytes: default_cache_bytes(),
+            max_age_secs: default_max_age(),
+        }
+    }
+}
+
+fn default_cache_entries() -> usize {
+    512
+}
+
+fn default_cache_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_max_age() -> u32 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebConfig {
+    #[serde(default="default_bind")]
+    pub bind: String,
+
+    /// Serve the frontend from this directory instead of the assets embedded in the binary.
+    #[serde(default)]
+    pub asset_dir: Option<String>,
+
+    #[serde(default)]
+    pub cors: CorsConfig,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            bind: default_bind(),
+            asset_dir: None,
+            cors: CorsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+fn default_bind() -> String {
+    String::from("127.0.0.1:8080")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed to call the api from a browser, `*` allows any.
+    #[serde(default="default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+
+    #[serde(default="default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+
+    /// How long browsers may cache a preflight response.
+    #[serde(default="default_preflight_max_age")]
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: default_allowed_origins(),
+            allowed_methods: default_allowed_methods(),
+            max_age_secs: default_preflight_max_age(),
+        }
+    }
+}
+
+fn default_allowed_origins() -> Vec<String> {
+    vec![String::from("*")]
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    ["GET", "POST", "DELETE", "OPTIONS"].iter().map(|m| m.to_string()).collect()
+}
+
+fn default_preflight_max_age() -> usize {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default="default_true")]
+    pub enabled: bool,
+
+    #[serde(default="default_public_limit")]
+    pub public: LimitConfig,
+
+    /// Applies to `/metrics` and the other operational routes, and to creating and deleting watchlists.
+    #[serde(default="default_admin_limit")]
+    pub admin: LimitConfig,
+
+    /// Take the client address from `Forwarded`/`X-Forwarded-For`, only enable behind a proxy that sets them.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            public: default_public_limit(),
+            admin: default_admin_limit(),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+/// A token bucket per client address, refilled at `per_second` up to `burst`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct LimitConfig {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_public_limit() -> LimitConfig {
+    LimitConfig { per_second: 5.0, burst: 30.0 }
+}
+
+fn default_admin_limit() -> LimitConfig {
+    LimitConfig { per_second: 0.2, burst: 5.0 }
+}
+
+impl Config {
+    /// Reads the file pointed to by `SYSTEMET_CONFIG`, or `config.yml`, a missing file means defaults.
+    fn load() -> Result<Config> {
+        let path = std::env::var(CONFIG_ENV).unwrap_or_else(|_| CONFIG_FILE.to_string());
+        if !std::path::Path::new(&path).exists() {
+            info!("No config file at {}, using defaults", path);
+            return Ok(Config::default());
+        }
+        info!("Loading config from {}", path);
+        Config::parse(&std::fs::read_to_string(&path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Config> {
+        Ok(serde_yaml::from_str(source)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_partial() {
+        let config = Config::parse("web:\n  asset_dir: ./frontend/dist\n").unwrap();
+        assert_eq!("127.0.0.1:8080", config.web.bind);
+        assert_eq!(Some(String::from("./frontend/dist")), config.web.asset_dir);
+        assert_eq!(512, config.cache.max_entries);
+        assert_eq!(Backend::Sqlite, config.database.backend);
+        assert_eq!("products.db", config.database.path);
+        assert_eq!(UpstreamApi::Bulk, config.upstream.api);
+        assert!(!config.notify.allow_local);
+        assert!(config.notify.webhook_hosts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        let config = Config::parse("upstream:\n  api: search\n  api_key: secret\n  page_size: 10\n").unwrap();
+        assert_eq!(UpstreamApi::Search, config.upstream.api);
+        assert_eq!("secret", config.upstream.api_key);
+        assert_eq!(10, config.upstream.page_size);
+        assert_eq!(default_search_url(), config.upstream.base_url);
+        assert!(Config::parse("upstream:\n  api: graphql\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_web_limits() {
+        let config = Config::parse("web:\n  cors:\n    allowed_origins: [\"https://example.com\"]\n  \
+            rate_limit:\n    admin:\n      per_second: 1\n      burst: 2\n").unwrap();
+        assert_eq!(vec![String::from("https://example.com")], config.web.cors.allowed_origins);
+        assert_eq!(4, config.web.cors.allowed_methods.len());
+        assert!(config.web.rate_limit.enabled);
+        assert_eq!(LimitConfig { per_second: 1.0, burst: 2.0 }, config.web.rate_limit.admin);
+        assert_eq!(default_public_limit(), config.web.rate_limit.public);
+        assert_eq!(vec![String::from("*")], Config::default().web.cors.allowed_origins);
+    }
+
+    #[test]
+    fn test_parse_postgres() {
+        let config = Config::parse("database:\n  backend: postgres\n  url: host=localhost user=postgres\n").unwrap();
+        assert_eq!(Backend::Postgres, config.database.backend);
+        assert_eq!("host=localhost user=postgres", config.database.url);
+    }
+}
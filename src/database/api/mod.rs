@@ -1,50 +1,211 @@
-use crate::domain::models::product::{Product, ProductOpts, MinimalSite};
-use crate::domain::models::site::Site;
+use crate::config::CONFIG;
+use crate::domain::models::basket::{Basket, BasketOpts};
+use crate::domain::models::detail::{ProductDetail, ProductLookup};
+use crate::domain::models::ids::SiteId;
+use crate::domain::models::product::{Product, ProductOpts, MinimalSite, SiteCompareOpts};
+use crate::domain::models::projection::ProductField;
+use crate::domain::models::site::{Site, SiteStats, SiteComparison, SiteOpts, open_time};
+use crate::domain::models::stock::{StockLevel, StockOpts};
+use crate::domain::models::watchlist::{Watchlist, NewWatchlist, WatchState};
+use crate::domain::models::refresh::LoadStats;
+use crate::domain::{arithmetic, basket, slug};
 use crate::domain::result::*;
-use rusqlite::{Connection};
-use std::collections::{HashMap};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
 
-use super::storage::storage::{select_all_sites, select_all_products, insert_products, insert_sites, insert_junctions};
-use super::storage::init::*;
+use super::backend::{Storage, create_storage};
+pub use super::backend::ProductStream;
 
-static DB_NAME: &str = "products.db";
+lazy_static! {
+    static ref STORAGE: Box<dyn Storage> = create_storage(&CONFIG.database)
+        .unwrap_or_else(|e| {
+            error!("Failed to create storage backend: {}", e);
+            std::process::exit(-1);
+        });
+}
+
+static SNAPSHOT_VERSION: AtomicI64 = AtomicI64::new(0);
+
+/// Version of the currently loaded data, bumped by every `update_db`.
+pub fn snapshot_version() -> i64 {
+    SNAPSHOT_VERSION.load(Ordering::SeqCst)
+}
 
 pub async fn select_products(opts: ProductOpts) -> Result<Vec<Product>> {
-    select_all_products(opts, create_connection().await?).await
+    if !opts.site_id.is_empty() && !site_open(&opts).await? {
+        return Ok(Vec::new());
+    }
+    STORAGE.select_products(opts, Vec::new(), Vec::new()).await
+}
+
+/// `select_products` read as the stream is polled, for exports of any size.
+pub async fn stream_products(opts: ProductOpts) -> Result<ProductStream> {
+    if !opts.site_id.is_empty() && !site_open(&opts).await? {
+        return Ok(Box::pin(futures::stream::empty()));
+    }
+    STORAGE.stream_products(opts).await
+}
+
+/// `select_products` holding only `fields`.
+pub async fn select_product_fields(opts: ProductOpts, fields: &[&'static ProductField]) -> Result<Vec<Value>> {
+    if !opts.site_id.is_empty() && !site_open(&opts).await? {
+        return Ok(Vec::new());
+    }
+    STORAGE.select_product_fields(opts, fields).await
+}
+
+pub async fn select_sites(opts: &SiteOpts) -> Result<Vec<Site>> {
+    let time = open_time(opts.open_now, &opts.open_at)?;
+    select_sites_at(opts, time.as_ref()).await
+}
+
+/// Sites matching `opts`, those closed at `time` are left out unless `opts.include_closed`.
+pub async fn select_sites_at(opts: &SiteOpts, time: Option<&DateTime<Utc>>) -> Result<Vec<Site>> {
+    let mut sites = STORAGE.select_sites(opts).await?;
+    if let (Some(time), false) = (time, opts.include_closed) {
+        sites.retain(|s| s.is_open_at(time));
+    }
+    Ok(sites)
+}
+
+pub async fn select_site_products(site_id: &SiteId, mut opts: ProductOpts) -> Result<Option<Vec<Product>>> {
+    if !STORAGE.site_exists(site_id).await? {
+        return Ok(None);
+    }
+    opts.site_id = site_id.clone();
+    select_products(opts).await.map(Some)
+}
+
+pub async fn stream_site_products(site_id: &SiteId, mut opts: ProductOpts) -> Result<Option<ProductStream>> {
+    if !STORAGE.site_exists(site_id).await? {
+        return Ok(None);
+    }
+    opts.site_id = site_id.clone();
+    stream_products(opts).await.map(Some)
+}
+
+pub async fn select_site_product_fields(site_id: &SiteId, mut opts: ProductOpts, fields: &[&'static ProductField])
+    -> Result<Option<Vec<Value>>> {
+    if !STORAGE.site_exists(site_id).await? {
+        return Ok(None);
+    }
+    opts.site_id = site_id.clone();
+    select_product_fields(opts, fields).await.map(Some)
+}
+
+/// Whether the store in `opts.site_id` is open at the requested time, always true if no time was requested.
+async fn site_open(opts: &ProductOpts) -> Result<bool> {
+    let time = match open_time(opts.open_now, &opts.open_at)? {
+        Some(t) => t,
+        None => return Ok(true)
+    };
+    let site_opts = SiteOpts { site_id: opts.site_id.clone(), ..SiteOpts::default() };
+    let sites = STORAGE.select_sites(&site_opts).await?;
+    Ok(sites.iter().any(|s| s.is_open_at(&time)))
+}
+
+pub async fn select_site_stats_by_id(site_id: &SiteId) -> Result<Option<SiteStats>> {
+    if !STORAGE.site_exists(site_id).await? {
+        return Ok(None);
+    }
+    STORAGE.select_site_stats(site_id).await.map(Some)
 }
 
-pub async fn select_sites() -> Result<Vec<Site>> {
-    select_all_sites(create_connection().await?).await
+pub async fn select_product_detail(lookup: &ProductLookup) -> Result<Option<ProductDetail>> {
+    let product = match STORAGE.select_product(lookup).await? {
+        Some(product) => product,
+        None => return Ok(None),
+    };
+    let stores = STORAGE.select_listings(&product.product_id).await?;
+    Ok(Some(ProductDetail { product, stores }))
 }
 
-pub async fn update_db(products: Vec<Product>, sites: &Vec<Site>, mapping: &Vec<MinimalSite>) -> Result<()> {
-    let mut product_map = HashMap::new();
-    for product in products {
-        product_map.insert(String::from(&product.product_id), product);
+pub async fn select_site_stock(site_id: &SiteId, opts: &StockOpts) -> Result<Option<Vec<StockLevel>>> {
+    if !STORAGE.site_exists(site_id).await? {
+        return Ok(None);
     }
-    let mut assembled_products = Vec::new();
-    for site in mapping {
-        for prod in &site.products{
-            if let Some(found) = product_map.remove(&prod.product_id) {
-                let mut p = found;
-                p.link = p.construct_link();
-                assembled_products.push(p);
+    STORAGE.select_stock(site_id, opts.min_stock).await.map(Some)
+}
+
+/// The best basket of the current snapshot, `None` if none covers the required categories.
+pub async fn optimize_basket(opts: &BasketOpts) -> Result<Option<Basket>> {
+    let mut stock = HashMap::new();
+    if !opts.site_id.is_empty() {
+        if !STORAGE.site_exists(&opts.site_id).await? {
+            return Err(ErrorKind::NotFound(format!("site with site_id={}", opts.site_id)).into());
+        }
+        for level in STORAGE.select_stock(&opts.site_id, 0).await? {
+            if let Some(units) = level.stock {
+                stock.insert(level.product_id, units);
             }
         }
     }
-    tokio::try_join!(insert_products(&assembled_products, create_connection().await?), insert_sites(sites, create_connection().await?))?;
-    insert_junctions(mapping, create_connection().await?).await?;
-    Ok(())
+    let products = STORAGE.select_products(opts.product_opts(), Vec::new(), Vec::new()).await?;
+    basket::optimize(products, &stock, opts)
+}
+
+pub async fn compare_sites(opts: SiteCompareOpts) -> Result<Option<SiteComparison>> {
+    if !STORAGE.site_exists(&opts.a).await? || !STORAGE.site_exists(&opts.b).await? {
+        return Ok(None);
+    }
+    let (only_a, only_b, both) = tokio::try_join!(
+        STORAGE.select_products(opts.product_opts(&opts.a), Vec::new(), vec![opts.b.clone()]),
+        STORAGE.select_products(opts.product_opts(&opts.b), Vec::new(), vec![opts.a.clone()]),
+        STORAGE.select_products(opts.product_opts(&opts.a), vec![opts.b.clone()], Vec::new())
+    )?;
+    Ok(Some(SiteComparison { site_a: opts.a, site_b: opts.b, only_a, only_b, both }))
+}
+
+pub async fn update_db(products: Vec<Product>, sites: &[Site], mapping: &[MinimalSite]) -> Result<LoadStats> {
+    // Keep the whole catalogue, availability lives in the junction table.
+    // Web-only and order-only products are listed by no store but can still be ordered to any of them
+    let mut seen = HashSet::new();
+    let mut assembled_products: Vec<Product> = products.into_iter()
+        .filter(|p| seen.insert(p.product_id.clone()))
+        .collect();
+    arithmetic::rank_products(&mut assembled_products);
+    let collisions = slug::assign_links(&mut assembled_products);
+    if collisions > 0 {
+        warn!("{} products share a link with another product and link a search instead", collisions);
+    }
+    let stats = STORAGE.load_snapshot(&assembled_products, sites, mapping).await?;
+    if stats.rejected() > 0 {
+        warn!("Rejected {} of {} junction rows: {}", stats.rejected(), stats.rejected() + stats.inserted, stats);
+    }
+    let version = STORAGE.snapshot_version().await?;
+    SNAPSHOT_VERSION.store(version, Ordering::SeqCst);
+    info!("Loaded snapshot version {}", version);
+    Ok(stats)
 }
 
 pub async fn init_db() -> Result<()>{
-    info!("Initializing database {}", DB_NAME);
-    tokio::try_join!(init_product_db(create_connection().await?), init_site_db(create_connection().await?))?;
-    init_junction_db(create_connection().await?).await?;
+    STORAGE.init().await?;
+    SNAPSHOT_VERSION.store(STORAGE.snapshot_version().await?, Ordering::SeqCst);
     Ok(())
 }
 
-async fn create_connection() -> Result<Connection> {
-    Connection::open(DB_NAME)
-        .map_err(|e| e.into())
-}
\ No newline at end of file
+pub async fn insert_watchlist(watchlist: &NewWatchlist) -> Result<i64> {
+    STORAGE.insert_watchlist(watchlist).await
+}
+
+pub async fn select_watchlist(watchlist_id: i64) -> Result<Option<Watchlist>> {
+    STORAGE.select_watchlist(watchlist_id).await
+}
+
+pub async fn select_watchlists(watchlist_ids: &[i64]) -> Result<Vec<Watchlist>> {
+    STORAGE.select_watchlists(watchlist_ids).await
+}
+
+pub async fn delete_watchlist(watchlist_id: i64) -> Result<bool> {
+    STORAGE.delete_watchlist(watchlist_id).await
+}
+
+pub async fn select_watch_states() -> Result<Vec<WatchState>> {
+    STORAGE.select_watch_states().await
+}
+
+pub async fn update_watch_states(states: &[WatchState]) -> Result<()> {
+    STORAGE.update_watch_states(states).await
+}
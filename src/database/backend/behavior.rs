@@ -0,0 +1,353 @@
+//! Behavior every `Storage` implementation must share, run against a fresh database by each backend's tests.
+use chrono::{NaiveDate, NaiveTime};
+use futures::TryStreamExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::runtime::Runtime;
+use crate::domain::arithmetic::rank_products;
+use crate::domain::scoring;
+use crate::domain::models::basket::BasketOpts;
+use crate::domain::models::detail::ProductLookup;
+use crate::domain::models::fixtures::{product, site, minimal_site};
+use crate::domain::models::product::{Product, ProductOpts};
+use crate::domain::models::site::{SiteOpts, OpeningTime, Position};
+use crate::domain::models::watchlist::{NewWatchlist, NotifierTarget, NotifierKind, WatchEvent};
+use crate::domain::models::projection::{ProductField, PRODUCT_FIELDS};
+use super::Storage;
+
+fn opts(site_id: &str) -> ProductOpts {
+    ProductOpts {
+        count: 10,
+        include_recycling: false,
+        exists_in_store: false,
+        max_volume: f64::MAX,
+        site_id: site_id.into(),
+        category: String::new(),
+        ..ProductOpts::default()
+    }
+}
+
+fn ids(products: Vec<Product>) -> Vec<String> {
+    products.into_iter().map(|p| p.product_id.to_string()).collect()
+}
+
+fn catalogue() -> Vec<Product> {
+    let mut wine = product("3", "vin", 100.0, 750.0, 13.0);
+    wine.vintage = Some(2018);
+    let mut spirit = product("4", "sprit", 300.0, 700.0, 40.0);
+    spirit.sell_start_date = None;
+    let mut products = vec![
+        product("1", "öl", 20.0, 500.0, 5.0),
+        product("2", "öl", 15.0, 330.0, 5.0),
+        wine,
+        spirit,
+        // Order-only, not in any store's assortment
+        product("5", "vin", 90.0, 750.0, 14.0),
+    ];
+    rank_products(&mut products);
+    products
+}
+
+pub async fn run(storage: &dyn Storage) {
+    storage.init().await.unwrap();
+    // Init has to be safe against an existing database
+    storage.init().await.unwrap();
+    assert_eq!(0, storage.snapshot_version().await.unwrap());
+    load(storage).await;
+    products(storage).await;
+    sites(storage).await;
+    site_stats(storage).await;
+    stock(storage).await;
+    product_detail(storage).await;
+    watchlists(storage).await;
+    reload(storage).await;
+    concurrent_reload(storage).await;
+}
+
+async fn load(storage: &dyn Storage) {
+    let mut centrum = site("0102", "Centrum");
+    centrum.city = String::from("Stockholm");
+    centrum.postal_code = String::from("113 50");
+    centrum.address = String::from("Odengatan 1");
+    centrum.position = Position { lat: 59.34, long: 18.05 };
+    centrum.opening_hours = vec![OpeningTime {
+        is_open: false,
+        reason: String::from("Juldagen"),
+        date: NaiveDate::from_ymd(2020, 12, 25),
+        open_from: None,
+        open_to: None,
+    }, OpeningTime {
+        is_open: true,
+        reason: String::new(),
+        date: NaiveDate::from_ymd(2020, 12, 26),
+        open_from: Some(NaiveTime::from_hms(10, 0, 0)),
+        open_to: Some(NaiveTime::from_hms(15, 0, 0)),
+    }];
+    let mut agent = site("A001", "Ombud");
+    agent.name = None;
+    agent.is_store = false;
+    agent.is_agent = true;
+    let sites = vec![centrum, site("0104", "Söder"), agent];
+    let mut centrum_assortment = minimal_site("0102", &["1", "2", "3", "3", "9"]);
+    for (listed, stock) in centrum_assortment.products.iter_mut().zip(&[4, 3, 0, 0]) {
+        listed.stock = Some(*stock);
+    }
+    centrum_assortment.products[0].shelf = Some(String::from("A3"));
+    let mapping = vec![
+        centrum_assortment,
+        minimal_site("0104", &["3", "4"]),
+        minimal_site("9999", &["1"]),
+    ];
+    let stats = storage.load_snapshot(&catalogue(), &sites, &mapping).await.unwrap();
+    assert_eq!((5, 1, 1, 1, 0), (stats.inserted, stats.unknown_product, stats.unknown_site, stats.duplicate, stats.failed));
+    assert_eq!(1, storage.snapshot_version().await.unwrap());
+}
+
+async fn products(storage: &dyn Storage) {
+    let mut expected = catalogue();
+    expected.sort_by(|a, b| b.apk.partial_cmp(&a.apk).unwrap());
+    let all = storage.select_products(opts(""), Vec::new(), Vec::new()).await.unwrap();
+    assert_eq!(ids(expected), ids(all.clone()));
+    let first = &all[0];
+    assert_eq!(catalogue().into_iter().find(|p| p.product_id == first.product_id).unwrap().apk, first.apk);
+
+    let in_stores = storage.select_products(ProductOpts { exists_in_store: true, ..opts("") }, Vec::new(), Vec::new())
+        .await.unwrap();
+    assert!(!ids(in_stores.clone()).contains(&String::from("5")));
+    assert_eq!(4, in_stores.len());
+
+    let limited = storage.select_products(ProductOpts { count: 2, ..opts("") }, Vec::new(), Vec::new()).await.unwrap();
+    assert_eq!(2, limited.len());
+
+    // Baskets are picked from the whole catalogue without a limit
+    let basket = BasketOpts::default().product_opts();
+    assert_eq!(catalogue().len(), storage.select_products(basket, Vec::new(), Vec::new()).await.unwrap().len());
+    let basket = BasketOpts { site_id: "0102".into(), ..BasketOpts::default() }.product_opts();
+    assert_eq!(3, storage.select_products(basket, Vec::new(), Vec::new()).await.unwrap().len());
+
+    let beer = storage.select_products(ProductOpts { category: String::from("öl"), ..opts("") }, Vec::new(), Vec::new())
+        .await.unwrap();
+    assert_eq!(vec!["1", "2"], ids(beer));
+
+    let small = storage.select_products(ProductOpts { max_volume: 500.0, ..opts("") }, Vec::new(), Vec::new())
+        .await.unwrap();
+    assert_eq!(vec!["1", "2"], ids(small));
+
+    let store = storage.select_products(opts("0102"), Vec::new(), Vec::new()).await.unwrap();
+    assert_eq!(vec!["1", "2", "3"], ids(store));
+
+    let only_centrum = storage.select_products(opts("0102"), Vec::new(), vec!["0104".into()]).await.unwrap();
+    assert_eq!(vec!["1", "2"], ids(only_centrum));
+    let both = storage.select_products(opts("0102"), vec!["0104".into()], Vec::new()).await.unwrap();
+    assert_eq!(vec!["3"], ids(both));
+
+    let quoted = storage.select_products(opts("01' OR '1'='1"), Vec::new(), Vec::new()).await.unwrap();
+    assert!(quoted.is_empty());
+
+    // Exports read the same rows as the lists, in the same order
+    let streamed: Vec<Product> = storage.stream_products(opts("")).await.unwrap().try_collect().await.unwrap();
+    assert_eq!(ids(all.clone()), ids(streamed));
+    let streamed: Vec<Product> = storage.stream_products(ProductOpts { count: 2, ..opts("") }).await.unwrap()
+        .try_collect().await.unwrap();
+    assert_eq!(2, streamed.len());
+    let streamed: Vec<Product> = storage.stream_products(ProductOpts { count: 0, ..opts("") }).await.unwrap()
+        .try_collect().await.unwrap();
+    assert!(streamed.is_empty());
+
+    let every_field: Vec<&'static ProductField> = PRODUCT_FIELDS.iter().collect();
+    let projected = storage.select_product_fields(opts(""), &every_field).await.unwrap();
+    let full: Vec<serde_json::Value> = all.iter().map(|p| serde_json::to_value(p).unwrap()).collect();
+    assert_eq!(full, projected);
+
+    // Every score ranks in the database the way it evaluates
+    for name in scoring::SCORES.names() {
+        let score = scoring::SCORES.get(name).unwrap();
+        let mut expected = catalogue();
+        expected.sort_by(|a, b| score.evaluate(b).partial_cmp(&score.evaluate(a)).unwrap());
+        let scored = storage.select_products(ProductOpts { score: name.to_string(), ..opts("") }, Vec::new(), Vec::new())
+            .await.unwrap();
+        assert_eq!(ids(expected), ids(scored), "{}", name);
+    }
+
+    let by_id = |id: &str| all.iter().find(|p| p.product_id == id).unwrap();
+    assert_eq!(Some(2018), by_id("3").vintage);
+    assert_eq!(None, by_id("1").vintage);
+    assert_eq!(None, by_id("4").sell_start_date);
+    assert_eq!(catalogue()[0].sell_start_date, by_id("1").sell_start_date);
+}
+
+async fn sites(storage: &dyn Storage) {
+    let all = storage.select_sites(&SiteOpts::default()).await.unwrap();
+    assert_eq!(vec!["0102", "0104", "A001"], all.iter().map(|s| s.site_id.as_str()).collect::<Vec<&str>>());
+    let centrum = &all[0];
+    assert_eq!("Juldagen", centrum.opening_hours[0].reason);
+    assert_eq!(None, centrum.opening_hours[0].open_from);
+    assert_eq!(NaiveDate::from_ymd(2020, 12, 26), centrum.opening_hours[1].date);
+    assert_eq!(Some(NaiveTime::from_hms(15, 0, 0)), centrum.opening_hours[1].open_to);
+    assert_eq!(Some("Centrum"), centrum.name.as_deref());
+    assert_eq!(None, all[2].name);
+    assert_eq!(59.34, centrum.position.lat);
+
+    let by_city = storage.select_sites(&SiteOpts { city: String::from("stockholm"), ..SiteOpts::default() })
+        .await.unwrap();
+    assert_eq!(1, by_city.len());
+    let by_postal = storage.select_sites(&SiteOpts { postal_code: String::from("1135"), ..SiteOpts::default() })
+        .await.unwrap();
+    assert_eq!(1, by_postal.len());
+    let by_q = storage.select_sites(&SiteOpts { q: String::from("odengatan"), ..SiteOpts::default() })
+        .await.unwrap();
+    assert_eq!(1, by_q.len());
+    let agents = storage.select_sites(&SiteOpts { is_agent: Some(true), ..SiteOpts::default() }).await.unwrap();
+    assert_eq!(vec!["A001"], agents.iter().map(|s| s.site_id.as_str()).collect::<Vec<&str>>());
+    let stores = storage.select_sites(&SiteOpts { is_store: Some(true), ..SiteOpts::default() }).await.unwrap();
+    assert_eq!(2, stores.len());
+
+    assert!(storage.site_exists(&"0104".into()).await.unwrap());
+    assert!(!storage.site_exists(&"9999".into()).await.unwrap());
+}
+
+async fn site_stats(storage: &dyn Storage) {
+    let stats = storage.select_site_stats(&"0102".into()).await.unwrap();
+    assert_eq!(3, stats.assortment_size);
+    assert_eq!(product("2", "öl", 15.0, 330.0, 5.0).apk, stats.median_apk);
+    let categories: Vec<(&str, &str)> = stats.best_per_category.iter()
+        .map(|b| (b.category.as_str(), b.product_id.as_str()))
+        .collect();
+    assert_eq!(vec![("vin", "3"), ("öl", "1")], categories);
+}
+
+async fn stock(storage: &dyn Storage) {
+    let centrum = storage.select_stock(&"0102".into(), 0).await.unwrap();
+    let levels: Vec<(&str, Option<i32>, Option<&str>, bool)> = centrum.iter()
+        .map(|s| (s.product_id.as_str(), s.stock, s.shelf.as_deref(), s.last_seen.is_some()))
+        .collect();
+    // Listed but out of stock, and never seen in stock
+    assert_eq!(vec![("1", Some(4), Some("A3"), true), ("2", Some(3), None, true), ("3", Some(0), None, false)], levels);
+    let plenty = storage.select_stock(&"0102".into(), 4).await.unwrap();
+    assert_eq!(vec!["1"], plenty.iter().map(|s| s.product_id.as_str()).collect::<Vec<&str>>());
+    // Stock isn't reported, a listing counts as seen
+    let soder = storage.select_stock(&"0104".into(), 0).await.unwrap();
+    assert!(soder.iter().all(|s| s.stock.is_none() && s.last_seen.is_some()));
+    assert!(storage.select_stock(&"0104".into(), 1).await.unwrap().is_empty());
+
+    let in_stock = storage.select_products(ProductOpts { min_stock: 1, ..opts("0102") }, Vec::new(), Vec::new())
+        .await.unwrap();
+    assert_eq!(vec!["1", "2"], ids(in_stock));
+    let unreported = storage.select_products(ProductOpts { min_stock: 1, ..opts("0104") }, Vec::new(), Vec::new())
+        .await.unwrap();
+    assert!(unreported.is_empty());
+}
+
+async fn product_detail(storage: &dyn Storage) {
+    let wine = storage.select_product(&ProductLookup::Id("3".into())).await.unwrap().unwrap();
+    assert_eq!(Some(2018), wine.vintage);
+    let by_number = storage.select_product(&ProductLookup::Number("301".into())).await.unwrap().unwrap();
+    assert_eq!(wine.product_id, by_number.product_id);
+    assert!(storage.select_product(&ProductLookup::Id("301".into())).await.unwrap().is_none());
+    assert!(storage.select_product(&ProductLookup::Number("1' OR '1'='1".into())).await.unwrap().is_none());
+
+    let listings = storage.select_listings(&"3".into()).await.unwrap();
+    let stores: Vec<(&str, Option<i32>)> = listings.iter().map(|s| (s.site_id.as_str(), s.stock)).collect();
+    assert_eq!(vec![("0102", Some(0)), ("0104", None)], stores);
+    assert!(storage.select_listings(&"5".into()).await.unwrap().is_empty());
+
+    // Ranks are stored as loaded
+    let ranked = catalogue().into_iter().find(|p| p.product_id == "3").unwrap();
+    assert_eq!((2, 2), (ranked.category_rank, wine.category_rank));
+    assert_eq!(("501-750", 100.0), (wine.volume_bucket.as_str(), wine.category_percentile));
+    assert_eq!((ranked.apk_rank, ranked.apk_percentile, ranked.sub_category_rank, ranked.volume_bucket_rank),
+               (wine.apk_rank, wine.apk_percentile, wine.sub_category_rank, wine.volume_bucket_rank));
+}
+
+async fn watchlists(storage: &dyn Storage) {
+    let new = NewWatchlist {
+        name: String::from("beers"),
+        site_id: "0104".into(),
+        notifier: NotifierTarget { kind: NotifierKind::Stdout, target: String::new() },
+        products: vec!["1".into(), "4".into()],
+    };
+    let id = storage.insert_watchlist(&new).await.unwrap();
+    let found = storage.select_watchlist(id).await.unwrap().unwrap();
+    assert_eq!("beers", found.name);
+    assert_eq!(NotifierKind::Stdout, found.notifier.kind);
+    let mut items: Vec<(String, bool)> = found.items.into_iter().map(|i| (i.product_id.to_string(), i.last_in_store)).collect();
+    items.sort();
+    assert_eq!(vec![(String::from("1"), false), (String::from("4"), true)], items);
+
+    let mut states = storage.select_watch_states().await.unwrap();
+    assert_eq!(2, states.len());
+    for state in &mut states {
+        state.price = Some(1.0);
+        state.in_store = true;
+    }
+    storage.update_watch_states(&states).await.unwrap();
+    let updated = storage.select_watchlist(id).await.unwrap().unwrap();
+    assert!(updated.items.iter().all(|i| i.last_price == Some(1.0) && i.last_in_store));
+    let together = storage.select_watchlists(&[id, id + 1000]).await.unwrap();
+    assert_eq!(1, together.len());
+    assert_eq!((id, 2), (together[0].watchlist_id, together[0].items.len()));
+
+    // Leaving the catalogue keeps the last price, coming back cheaper is a price drop
+    for state in &mut states {
+        state.price = None;
+    }
+    storage.update_watch_states(&states).await.unwrap();
+    let mut returned = storage.select_watch_states().await.unwrap();
+    assert!(returned.iter().all(|s| s.last_price == Some(1.0)));
+    returned[0].price = Some(0.5);
+    assert!(matches!(returned[0].evaluate(&"0104".into())[..], [WatchEvent::PriceDrop { old_price, .. }] if old_price == 1.0));
+
+    assert!(storage.delete_watchlist(id).await.unwrap());
+    assert!(!storage.delete_watchlist(id).await.unwrap());
+    assert!(storage.select_watchlist(id).await.unwrap().is_none());
+}
+
+async fn reload(storage: &dyn Storage) {
+    let seen = storage.select_stock(&"0102".into(), 0).await.unwrap()[1].last_seen;
+    let mut sold_out = minimal_site("0102", &["2"]);
+    sold_out.products[0].stock = Some(0);
+    let stats = storage.load_snapshot(&catalogue()[..2], &[site("0102", "Centrum")], &[sold_out])
+        .await.unwrap();
+    assert_eq!(1, stats.inserted);
+    assert_eq!(2, storage.snapshot_version().await.unwrap());
+    let all = storage.select_products(opts(""), Vec::new(), Vec::new()).await.unwrap();
+    assert_eq!(vec!["1", "2"], ids(all));
+    let store = storage.select_products(opts("0102"), Vec::new(), Vec::new()).await.unwrap();
+    assert_eq!(vec!["2"], ids(store));
+    // Still tells when the store last had it
+    let stock = storage.select_stock(&"0102".into(), 0).await.unwrap();
+    assert_eq!((Some(0), seen), (stock[0].stock, stock[0].last_seen));
+    assert!(storage.select_sites(&SiteOpts::default()).await.unwrap()[0].opening_hours.is_empty());
+    assert!(!storage.site_exists(&"0104".into()).await.unwrap());
+}
+
+/// Reloads back and forth between two assortments while another connection keeps reading the store,
+/// every read has to see one of them in full.
+async fn concurrent_reload(storage: &dyn Storage) {
+    let products = catalogue();
+    let sites = [site("0102", "Centrum")];
+    let assortments = [vec![minimal_site("0102", &["1", "2"])], vec![minimal_site("0102", &["2", "3"])]];
+    storage.load_snapshot(&products, &sites, &assortments[1]).await.unwrap();
+    let loading = AtomicBool::new(true);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut runtime = Runtime::new().unwrap();
+            for round in 0..20 {
+                runtime.block_on(storage.load_snapshot(&products, &sites, &assortments[round % 2])).unwrap();
+            }
+            loading.store(false, Ordering::SeqCst);
+        });
+        s.spawn(|| {
+            let mut runtime = Runtime::new().unwrap();
+            let mut reads = 0;
+            while loading.load(Ordering::SeqCst) || reads == 0 {
+                let store = runtime.block_on(storage.select_products(opts("0102"), Vec::new(), Vec::new())).unwrap();
+                let mut listed = ids(store);
+                listed.sort();
+                assert!(listed == ["1", "2"] || listed == ["2", "3"], "Read a half loaded snapshot: {:?}", listed);
+                let stock = runtime.block_on(storage.select_stock(&"0102".into(), 0)).unwrap();
+                assert_eq!(2, stock.len(), "Read a half loaded snapshot: {:?}", stock);
+                reads += 1;
+            }
+        });
+    });
+}
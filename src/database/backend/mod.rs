@@ -0,0 +1,84 @@
+use std::pin::Pin;
+use async_trait::async_trait;
+use futures::Stream;
+use serde_json::Value;
+use crate::config::{DatabaseConfig, Backend};
+use crate::domain::models::detail::ProductLookup;
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::product::{Product, ProductOpts, MinimalSite};
+use crate::domain::models::projection::ProductField;
+use crate::domain::models::refresh::LoadStats;
+use crate::domain::models::site::{Site, SiteStats, SiteOpts};
+use crate::domain::models::stock::StockLevel;
+use crate::domain::models::watchlist::{Watchlist, NewWatchlist, WatchState};
+use crate::domain::result::Result;
+
+pub mod sqlite;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(test)]
+mod behavior;
+
+/// Products in ranking order, read from the database as the stream is polled.
+pub type ProductStream = Pin<Box<dyn Stream<Item=Result<Product>> + Send>>;
+
+/// Everything the app needs from a database, implemented once per supported backend.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Creates missing tables and indexes, safe to run against an existing database.
+    async fn init(&self) -> Result<()>;
+
+    /// Replaces the products, sites and store assortments with a new snapshot and bumps the snapshot version,
+    /// all in one transaction so readers see either the old or the new snapshot.
+    async fn load_snapshot(&self, products: &[Product], sites: &[Site], mapping: &[MinimalSite]) -> Result<LoadStats>;
+
+    async fn snapshot_version(&self) -> Result<i64>;
+
+    /// Top products by apk, additionally required to be listed in every one of `include_sites`
+    /// and in none of `exclude_sites`.
+    async fn select_products(&self, opts: ProductOpts, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>)
+        -> Result<Vec<Product>>;
+
+    /// `select_products` without site constraints, read from a cursor so the products are never all in memory.
+    async fn stream_products(&self, opts: ProductOpts) -> Result<ProductStream>;
+
+    /// Same ranking as `select_products` without site constraints, as JSON objects holding only `fields`.
+    async fn select_product_fields(&self, opts: ProductOpts, fields: &[&'static ProductField]) -> Result<Vec<Value>>;
+
+    async fn select_product(&self, lookup: &ProductLookup) -> Result<Option<Product>>;
+
+    /// Listings of one product by site id.
+    async fn select_listings(&self, product_id: &ProductId) -> Result<Vec<StockLevel>>;
+
+    async fn select_sites(&self, opts: &SiteOpts) -> Result<Vec<Site>>;
+
+    async fn site_exists(&self, site_id: &SiteId) -> Result<bool>;
+
+    async fn select_site_stats(&self, site_id: &SiteId) -> Result<SiteStats>;
+
+    /// Listings in one store by product id, only those with at least `min_stock` units if it's above 0.
+    async fn select_stock(&self, site_id: &SiteId, min_stock: i64) -> Result<Vec<StockLevel>>;
+
+    async fn insert_watchlist(&self, watchlist: &NewWatchlist) -> Result<i64>;
+
+    async fn select_watchlist(&self, watchlist_id: i64) -> Result<Option<Watchlist>>;
+
+    /// The watchlists among `watchlist_ids` by id, loaded together. Ids without a watchlist are skipped.
+    async fn select_watchlists(&self, watchlist_ids: &[i64]) -> Result<Vec<Watchlist>>;
+
+    async fn delete_watchlist(&self, watchlist_id: i64) -> Result<bool>;
+
+    async fn select_watch_states(&self) -> Result<Vec<WatchState>>;
+
+    async fn update_watch_states(&self, states: &[WatchState]) -> Result<()>;
+}
+
+pub fn create_storage(config: &DatabaseConfig) -> Result<Box<dyn Storage>> {
+    match config.backend {
+        Backend::Sqlite => Ok(Box::new(sqlite::SqliteStorage::new(&config.path))),
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => Ok(Box::new(postgres::PostgresStorage::new(&config.url))),
+        #[cfg(not(feature = "postgres"))]
+        Backend::Postgres => Err(crate::domain::result::ErrorKind::UnsupportedBackend(String::from("postgres")).into()),
+    }
+}
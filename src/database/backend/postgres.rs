@@ -0,0 +1,750 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use chrono::Utc;
+use serde_json::{Map, Value};
+use tokio_postgres::{Client, NoTls, Row};
+use tokio_postgres::types::ToSql;
+use crate::database::storage::query_utils::{QueryBuilder, SiteQueryBuilder, Dialect, Param, product_columns};
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::detail::ProductLookup;
+use crate::domain::models::product::{Product, ProductOpts, MinimalSite};
+use crate::domain::models::projection::{ProductField, FieldKind};
+use crate::domain::models::refresh::{LoadStats, junction_rows, out_of_stock};
+use crate::domain::models::serialization_helpers::{parse_date, parse_datetime, parse_time, DATETIME_FORMAT,
+                                                   DATE_FORMAT, TIME_FORMAT};
+use crate::domain::models::site::{Site, SiteStats, SiteOpts, CategoryBest, OpeningTime, Position, median};
+use crate::domain::models::stock::StockLevel;
+use crate::domain::models::watchlist::{Watchlist, WatchItem, NewWatchlist, NotifierTarget, NotifierKind, WatchState};
+use crate::domain::result::Result;
+use futures::{stream, TryStreamExt};
+use super::{Storage, ProductStream};
+
+/// Junction rows are sent as arrays, this many per statement.
+const JUNCTION_CHUNK: usize = 10_000;
+
+/// Products fetched from a cursor at a time.
+const CURSOR_FETCH: usize = 256;
+
+/// A shared PostgreSQL database, every call opens its own connection.
+pub struct PostgresStorage {
+    url: String,
+}
+
+impl PostgresStorage {
+    pub fn new(url: &str) -> Self {
+        PostgresStorage { url: url.to_string() }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let (client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Postgres connection closed with error: {}", e);
+            }
+        });
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn init(&self) -> Result<()> {
+        info!("Initializing postgres database");
+        let client = self.connect().await?;
+        client.batch_execute("
+            CREATE TABLE IF NOT EXISTS products (
+                    product_id VARCHAR PRIMARY KEY,
+                    product_number text,
+                    product_name_bold text,
+                    product_name_thin text,
+                    category text,
+                    product_number_short text,
+                    producer_name text,
+                    supplier_name text,
+                    is_kosher BOOLEAN not null,
+                    bottle_text_short text,
+                    restricted_parcel_quantity INTEGER not null,
+                    seal text,
+                    is_organic BOOLEAN not null,
+                    is_ethical BOOLEAN not null,
+                    ethical_label text,
+                    is_web_launch BOOLEAN not null,
+                    sell_start_date text,
+                    is_completely_out_of_stock BOOLEAN not null,
+                    is_temporary_out_of_stock BOOLEAN not null,
+                    alcohol_percentage DOUBLE PRECISION not null,
+                    volume DOUBLE PRECISION not null,
+                    price DOUBLE PRECISION not null,
+                    country text,
+                    origin_level1 text,
+                    origin_level2 text,
+                    vintage INTEGER,
+                    sub_category text,
+                    a_type text,
+                    style text,
+                    assortment_text text,
+                    beverage_description_short text,
+                    usage_text text,
+                    taste text,
+                    assortment text,
+                    is_manufacturing_country BOOLEAN not null,
+                    recycle_fee DOUBLE PRECISION not null,
+                    is_regional_retricted BOOLEAN not null,
+                    is_in_store_search_assortment text,
+                    is_news BOOLEAN not null,
+                    apk DOUBLE PRECISION not null,
+                    apk_recycling DOUBLE PRECISION not null,
+                    link text not null,
+                    ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            -- Tables created before vintage became optional
+            ALTER TABLE products ALTER COLUMN vintage DROP NOT NULL;
+            -- Set on every load, defaulted so rows from before they were added read until the next refresh
+            ALTER TABLE products
+                ADD COLUMN IF NOT EXISTS apk_rank INTEGER not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS apk_percentile DOUBLE PRECISION not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS category_rank INTEGER not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS category_percentile DOUBLE PRECISION not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS sub_category_rank INTEGER not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS sub_category_percentile DOUBLE PRECISION not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS volume_bucket text not null DEFAULT '',
+                ADD COLUMN IF NOT EXISTS volume_bucket_rank INTEGER not null DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS volume_bucket_percentile DOUBLE PRECISION not null DEFAULT 0;
+            CREATE INDEX IF NOT EXISTS idx_products_apk ON products (apk DESC);
+            CREATE INDEX IF NOT EXISTS idx_products_apk_recycling ON products (apk_recycling DESC);
+            CREATE INDEX IF NOT EXISTS idx_products_category_apk ON products (category, apk DESC);
+            CREATE INDEX IF NOT EXISTS idx_products_category_apk_recycling ON products (category, apk_recycling DESC);
+            CREATE TABLE IF NOT EXISTS sites (
+                    site_id VARCHAR PRIMARY KEY,
+                    is_tasting_store BOOLEAN,
+                    alias text,
+                    address text,
+                    display_name text,
+                    postal_code text,
+                    city text,
+                    county text,
+                    country text,
+                    is_store BOOLEAN,
+                    is_agent BOOLEAN,
+                    is_active_for_agent_order BOOLEAN,
+                    phone text,
+                    email text,
+                    services text,
+                    depot text,
+                    name text,
+                    lat DOUBLE PRECISION,
+                    long DOUBLE PRECISION
+            );
+            CREATE TABLE IF NOT EXISTS opening_hours (
+                    site_key VARCHAR REFERENCES sites(site_id) ON DELETE CASCADE,
+                    date text not null,
+                    is_open BOOLEAN not null,
+                    reason text,
+                    open_from text,
+                    open_to text,
+                    PRIMARY KEY (site_key, date)
+            );
+            CREATE TABLE IF NOT EXISTS sites_products (
+                    product_key VARCHAR REFERENCES products(product_id) ON DELETE CASCADE,
+                    site_key VARCHAR REFERENCES sites(site_id) ON DELETE CASCADE,
+                    stock INTEGER,
+                    shelf text,
+                    last_seen text,
+                    PRIMARY KEY (product_key, site_key)
+            );
+            ALTER TABLE sites_products ADD COLUMN IF NOT EXISTS stock INTEGER;
+            ALTER TABLE sites_products ADD COLUMN IF NOT EXISTS shelf text;
+            ALTER TABLE sites_products ADD COLUMN IF NOT EXISTS last_seen text;
+            CREATE INDEX IF NOT EXISTS idx_sites_products_site ON sites_products (site_key, product_key);
+            CREATE TABLE IF NOT EXISTS watchlists (
+                    watchlist_id BIGSERIAL PRIMARY KEY,
+                    name text not null,
+                    site_id text not null,
+                    notifier_kind text not null,
+                    notifier_target text not null,
+                    ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS watchlist_items (
+                    watchlist_key BIGINT REFERENCES watchlists(watchlist_id) ON DELETE CASCADE,
+                    product_id VARCHAR not null,
+                    last_price DOUBLE PRECISION,
+                    last_in_store BOOLEAN not null DEFAULT false,
+                    PRIMARY KEY (watchlist_key, product_id)
+            );
+            CREATE TABLE IF NOT EXISTS snapshot (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    version BIGINT not null,
+                    ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO snapshot (id, version) VALUES (0, 0) ON CONFLICT DO NOTHING;
+        ").await?;
+        info!("Postgres tables created");
+        Ok(())
+    }
+
+    async fn load_snapshot(&self, products: &[Product], sites: &[Site], mapping: &[MinimalSite]) -> Result<LoadStats> {
+        let start = SystemTime::now();
+        info!("Starting transaction to load {} products and {} sites", products.len(), sites.len());
+        let mut client = self.connect().await?;
+        let transaction = client.transaction().await?;
+        let (out_of_stock_products, out_of_stock_sites): (Vec<&str>, Vec<&str>) = out_of_stock(mapping).into_iter().unzip();
+        let mut previous = HashMap::new();
+        for row in transaction.query("
+                SELECT sp.product_key, sp.site_key, sp.last_seen FROM sites_products sp
+                JOIN UNNEST($1::VARCHAR[], $2::VARCHAR[]) AS k (product_key, site_key)
+                ON sp.product_key = k.product_key AND sp.site_key = k.site_key
+                WHERE sp.last_seen IS NOT NULL", &[&out_of_stock_products, &out_of_stock_sites]).await? {
+            if let Some(last_seen) = parse_datetime(row.try_get(2)?) {
+                previous.insert((row.try_get(0)?, row.try_get(1)?), last_seen);
+            }
+        }
+        transaction.batch_execute("TRUNCATE sites_products, opening_hours, sites, products").await?;
+        let stmt = transaction.prepare("
+              INSERT INTO products (
+              product_id, product_number, product_name_bold, product_name_thin, category, product_number_short,
+              producer_name, supplier_name, is_kosher, bottle_text_short, restricted_parcel_quantity, seal,
+              is_organic, is_ethical, ethical_label, is_web_launch, sell_start_date, is_completely_out_of_stock,
+              is_temporary_out_of_stock, alcohol_percentage, volume, price, country, origin_level1, origin_level2,
+              vintage, sub_category, a_type, style, assortment_text, beverage_description_short, usage_text, taste,
+              assortment, is_manufacturing_country, recycle_fee, is_regional_retricted,
+              is_in_store_search_assortment, is_news, apk, apk_recycling, link, apk_rank, apk_percentile,
+              category_rank, category_percentile, sub_category_rank, sub_category_percentile, volume_bucket,
+              volume_bucket_rank, volume_bucket_percentile)
+        VALUES (
+              $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21,
+              $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38, $39, $40, $41,
+              $42, $43, $44, $45, $46, $47, $48, $49, $50, $51
+        )").await?;
+        for product in products {
+            transaction.execute(&stmt, &[
+                &product.product_id,
+                &product.product_number,
+                &product.product_name_bold,
+                &product.product_name_thin,
+                &product.category.to_lowercase(),
+                &product.product_number_short,
+                &product.producer_name,
+                &product.supplier_name,
+                &product.is_kosher,
+                &product.bottle_text_short,
+                &product.restricted_parcel_quantity,
+                &product.seal,
+                &product.is_organic,
+                &product.is_ethical,
+                &product.ethical_label,
+                &product.is_web_launch,
+                &product.sell_start_date.map(|d| d.format(DATETIME_FORMAT).to_string()),
+                &product.is_completely_out_of_stock,
+                &product.is_temporary_out_of_stock,
+                &product.alcohol_percentage,
+                &product.volume,
+                &product.price,
+                &product.country,
+                &product.origin_level1,
+                &product.origin_level2,
+                &product.vintage,
+                &product.sub_category,
+                &product.a_type,
+                &product.style,
+                &product.assortment_text,
+                &product.beverage_description_short,
+                &product.usage_text,
+                &product.taste,
+                &product.assortment,
+                &product.is_manufacturing_country,
+                &product.recycle_fee,
+                &product.is_regional_retricted,
+                &product.is_in_store_search_assortment,
+                &product.is_news,
+                &product.apk,
+                &product.apk_recycling,
+                &product.link,
+                &product.apk_rank,
+                &product.apk_percentile,
+                &product.category_rank,
+                &product.category_percentile,
+                &product.sub_category_rank,
+                &product.sub_category_percentile,
+                &product.volume_bucket,
+                &product.volume_bucket_rank,
+                &product.volume_bucket_percentile,
+            ]).await?;
+        }
+        let site_stmt = transaction.prepare("
+              INSERT INTO sites (
+              site_id, is_tasting_store, alias, address, display_name, postal_code, city, county, country,
+              is_store, is_agent, is_active_for_agent_order, phone, email, services, depot, name, lat, long)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)").await?;
+        let opening_stmt = transaction.prepare("
+                INSERT INTO opening_hours (site_key, date, is_open, reason, open_from, open_to)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (site_key, date) DO UPDATE SET
+                is_open = excluded.is_open, reason = excluded.reason,
+                open_from = excluded.open_from, open_to = excluded.open_to").await?;
+        for site in sites {
+            transaction.execute(&site_stmt, &[
+                &site.site_id,
+                &site.is_tasting_store,
+                &site.alias,
+                &site.address,
+                &site.display_name,
+                &site.postal_code,
+                &site.city,
+                &site.county,
+                &site.country,
+                &site.is_store,
+                &site.is_agent,
+                &site.is_active_for_agent_order,
+                &site.phone,
+                &site.email,
+                &site.services,
+                &site.depot,
+                &site.name,
+                &site.position.lat,
+                &site.position.long,
+            ]).await?;
+            for opening in &site.opening_hours {
+                transaction.execute(&opening_stmt, &[
+                    &site.site_id,
+                    &opening.date.format(DATE_FORMAT).to_string(),
+                    &opening.is_open,
+                    &opening.reason,
+                    &opening.open_from.map(|t| t.format(TIME_FORMAT).to_string()),
+                    &opening.open_to.map(|t| t.format(TIME_FORMAT).to_string()),
+                ]).await?;
+            }
+        }
+        let product_keys: HashSet<String> = products.iter().map(|p| p.product_id.to_string()).collect();
+        let site_keys: HashSet<String> = sites.iter().map(|s| s.site_id.to_string()).collect();
+        let mut stats = LoadStats::default();
+        let rows = junction_rows(mapping, &product_keys, &site_keys, Utc::now().naive_utc(), &previous, &mut stats);
+        let junction_stmt = transaction.prepare("
+                INSERT INTO sites_products (product_key, site_key, stock, shelf, last_seen)
+                SELECT * FROM UNNEST($1::VARCHAR[], $2::VARCHAR[], $3::INTEGER[], $4::TEXT[], $5::TEXT[])
+                ON CONFLICT DO NOTHING").await?;
+        for chunk in rows.chunks(JUNCTION_CHUNK) {
+            let product_ids: Vec<&str> = chunk.iter().map(|r| r.product_id).collect();
+            let site_ids: Vec<&str> = chunk.iter().map(|r| r.site_id).collect();
+            let stock: Vec<Option<i32>> = chunk.iter().map(|r| r.stock).collect();
+            let shelves: Vec<Option<&str>> = chunk.iter().map(|r| r.shelf).collect();
+            let last_seen: Vec<Option<String>> = chunk.iter()
+                .map(|r| r.last_seen.map(|t| t.format(DATETIME_FORMAT).to_string()))
+                .collect();
+            let inserted = transaction.execute(&junction_stmt, &[&product_ids, &site_ids, &stock, &shelves, &last_seen])
+                .await? as usize;
+            stats.inserted += inserted;
+            stats.duplicate += chunk.len() - inserted;
+        }
+        transaction.execute("UPDATE snapshot SET version = version + 1, ts = CURRENT_TIMESTAMP WHERE id = 0", &[])
+            .await?;
+        transaction.commit().await?;
+        info!("Committed snapshot in {} millis: {}", SystemTime::now().duration_since(start)?.as_millis(), stats);
+        Ok(stats)
+    }
+
+    async fn snapshot_version(&self) -> Result<i64> {
+        let row = self.connect().await?
+            .query_one("SELECT version FROM snapshot WHERE id = 0", &[]).await?;
+        Ok(row.try_get(0)?)
+    }
+
+    async fn select_products(&self, opts: ProductOpts, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>)
+        -> Result<Vec<Product>> {
+        let (query, params) = QueryBuilder::build_with_sites(opts, include_sites, exclude_sites, Dialect::Postgres);
+        let rows = self.connect().await?.query(query.as_str(), &bind(&params)).await?;
+        rows.iter().map(product_from_row).collect()
+    }
+
+    /// Reads through a cursor in a read only transaction, the connection is owned by the stream and closed with it.
+    async fn stream_products(&self, opts: ProductOpts) -> Result<ProductStream> {
+        let (query, params) = QueryBuilder::build_with_sites(opts, Vec::new(), Vec::new(), Dialect::Postgres);
+        let client = self.connect().await?;
+        client.batch_execute("BEGIN READ ONLY").await?;
+        client.execute(format!("DECLARE products_export NO SCROLL CURSOR FOR {}", query.trim_end_matches(';')).as_str(),
+                       &bind(&params)).await?;
+        let pages = stream::try_unfold(Some(client), fetch_page);
+        Ok(Box::pin(pages.map_ok(|products| stream::iter(products.into_iter().map(Ok))).try_flatten()))
+    }
+
+    async fn select_product_fields(&self, opts: ProductOpts, fields: &[&'static ProductField]) -> Result<Vec<Value>> {
+        let (query, params) = QueryBuilder::build_projected(opts, fields, Dialect::Postgres);
+        let rows = self.connect().await?.query(query.as_str(), &bind(&params)).await?;
+        let mut products = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let mut object = Map::with_capacity(fields.len());
+            for (i, field) in fields.iter().enumerate() {
+                let value = match field.kind {
+                    FieldKind::Text => Value::from(row.try_get::<_, Option<String>>(i)?),
+                    FieldKind::Bool => Value::from(row.try_get::<_, bool>(i)?),
+                    FieldKind::Int => Value::from(row.try_get::<_, Option<i32>>(i)?),
+                    FieldKind::Real => Value::from(row.try_get::<_, f64>(i)?),
+                };
+                object.insert(field.name.to_string(), value);
+            }
+            products.push(Value::Object(object));
+        }
+        Ok(products)
+    }
+
+    async fn select_product(&self, lookup: &ProductLookup) -> Result<Option<Product>> {
+        let query = format!("SELECT {} FROM products p WHERE p.{} = $1", product_columns(), lookup.column());
+        let row = self.connect().await?.query_opt(query.as_str(), &[&lookup.value()]).await?;
+        row.as_ref().map(product_from_row).transpose()
+    }
+
+    async fn select_listings(&self, product_id: &ProductId) -> Result<Vec<StockLevel>> {
+        let rows = self.connect().await?.query("
+            SELECT site_key, product_key, stock, shelf, last_seen FROM sites_products
+            WHERE product_key = $1
+            ORDER BY site_key COLLATE \"C\"", &[&product_id]).await?;
+        rows.iter().map(stock_from_row).collect()
+    }
+
+    async fn select_sites(&self, opts: &SiteOpts) -> Result<Vec<Site>> {
+        let (query, params) = SiteQueryBuilder::build_for(opts, Dialect::Postgres);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p as &(dyn ToSql + Sync)).collect();
+        let client = self.connect().await?;
+        let rows = client.query(query.as_str(), &params).await?;
+        let openings = client.query("
+            SELECT site_key, is_open, reason, date, open_from, open_to FROM opening_hours ORDER BY site_key, date", &[])
+            .await?;
+        let mut by_site: HashMap<SiteId, Vec<OpeningTime>> = HashMap::new();
+        for opening in &openings {
+            let date = match parse_date(opening.try_get(3)?) {
+                Some(date) => date,
+                None => continue,
+            };
+            let time = |i: usize| -> Result<_> { Ok(opening.try_get::<_, Option<&str>>(i)?.and_then(parse_time)) };
+            by_site.entry(opening.try_get(0)?).or_default().push(OpeningTime {
+                is_open: opening.try_get(1)?,
+                reason: opening.try_get::<_, Option<String>>(2)?.unwrap_or_default(),
+                date,
+                open_from: time(4)?,
+                open_to: time(5)?,
+            });
+        }
+        let mut sites = Vec::new();
+        for row in &rows {
+            let mut site = site_from_row(row)?;
+            site.opening_hours = by_site.remove(&site.site_id).unwrap_or_default();
+            sites.push(site);
+        }
+        Ok(sites)
+    }
+
+    async fn site_exists(&self, site_id: &SiteId) -> Result<bool> {
+        let found = self.connect().await?
+            .query_opt("SELECT 1 FROM sites WHERE site_id = $1", &[&site_id]).await?;
+        Ok(found.is_some())
+    }
+
+    async fn select_site_stats(&self, site_id: &SiteId) -> Result<SiteStats> {
+        let client = self.connect().await?;
+        let rows = client.query("
+            SELECT p.apk FROM products p
+            JOIN sites_products sp ON sp.product_key = p.product_id
+            WHERE sp.site_key = $1
+            ORDER BY p.apk", &[&site_id]).await?;
+        let mut apks = Vec::new();
+        for row in &rows {
+            apks.push(row.try_get::<_, f64>(0)?);
+        }
+        // Byte order on category to match sqlite regardless of the database locale
+        let rows = client.query(r#"
+            SELECT DISTINCT ON (p.category COLLATE "C") p.category, p.product_id, p.product_name_bold, p.apk
+            FROM products p
+            JOIN sites_products sp ON sp.product_key = p.product_id
+            WHERE sp.site_key = $1
+            ORDER BY p.category COLLATE "C", p.apk DESC"#, &[&site_id]).await?;
+        let mut best_per_category = Vec::new();
+        for row in &rows {
+            best_per_category.push(CategoryBest {
+                category: row.try_get(0)?,
+                product_id: row.try_get(1)?,
+                product_name_bold: row.try_get(2)?,
+                apk: row.try_get(3)?,
+            });
+        }
+        Ok(SiteStats {
+            site_id: site_id.clone(),
+            assortment_size: apks.len() as i64,
+            median_apk: median(&apks),
+            best_per_category,
+        })
+    }
+
+    async fn select_stock(&self, site_id: &SiteId, min_stock: i64) -> Result<Vec<StockLevel>> {
+        let rows = self.connect().await?.query("
+            SELECT site_key, product_key, stock, shelf, last_seen FROM sites_products
+            WHERE site_key = $1 AND ($2::BIGINT <= 0 OR stock >= $2::BIGINT)
+            ORDER BY product_key COLLATE \"C\"", &[&site_id, &min_stock]).await?;
+        rows.iter().map(stock_from_row).collect()
+    }
+
+    async fn insert_watchlist(&self, watchlist: &NewWatchlist) -> Result<i64> {
+        let mut client = self.connect().await?;
+        let transaction = client.transaction().await?;
+        let row = transaction.query_one("
+            INSERT INTO watchlists (name, site_id, notifier_kind, notifier_target)
+            VALUES ($1, $2, $3, $4)
+            RETURNING watchlist_id", &[
+                &watchlist.name,
+                &watchlist.site_id,
+                &watchlist.notifier.kind.as_str(),
+                &watchlist.notifier.target,
+            ]).await?;
+        let id: i64 = row.try_get(0)?;
+        // Seed from the current snapshot so the next refresh only reports actual changes
+        for product_id in &watchlist.products {
+            transaction.execute("
+                INSERT INTO watchlist_items (watchlist_key, product_id, last_price, last_in_store)
+                SELECT $1::BIGINT, $2::VARCHAR,
+                    (SELECT price FROM products WHERE product_id = $2::VARCHAR),
+                    EXISTS(
+                        SELECT 1 FROM sites_products sp WHERE sp.product_key = $2::VARCHAR
+                        AND ($3::VARCHAR = '' OR sp.site_key = $3::VARCHAR)
+                    )
+                ON CONFLICT DO NOTHING", &[&id, product_id, &watchlist.site_id]).await?;
+        }
+        transaction.commit().await?;
+        Ok(id)
+    }
+
+    async fn select_watchlist(&self, watchlist_id: i64) -> Result<Option<Watchlist>> {
+        Ok(self.select_watchlists(&[watchlist_id]).await?.pop())
+    }
+
+    async fn select_watchlists(&self, watchlist_ids: &[i64]) -> Result<Vec<Watchlist>> {
+        let rows = self.connect().await?.query("
+            SELECT w.watchlist_id, w.name, w.site_id, w.notifier_kind, w.notifier_target,
+                   wi.product_id, wi.last_price, wi.last_in_store
+            FROM watchlists w
+            LEFT JOIN watchlist_items wi ON wi.watchlist_key = w.watchlist_id
+            WHERE w.watchlist_id = ANY($1)
+            ORDER BY w.watchlist_id, wi.product_id COLLATE \"C\"", &[&watchlist_ids]).await?;
+        let mut watchlists: Vec<Watchlist> = Vec::new();
+        for row in &rows {
+            let watchlist_id: i64 = row.try_get(0)?;
+            if watchlists.last().map(|w| w.watchlist_id) != Some(watchlist_id) {
+                watchlists.push(Watchlist {
+                    watchlist_id,
+                    name: row.try_get(1)?,
+                    site_id: row.try_get(2)?,
+                    notifier: NotifierTarget { kind: NotifierKind::parse(row.try_get(3)?)?, target: row.try_get(4)? },
+                    items: Vec::new(),
+                });
+            }
+            if let (Some(watchlist), Some(product_id)) = (watchlists.last_mut(), row.try_get::<_, Option<ProductId>>(5)?) {
+                watchlist.items.push(WatchItem {
+                    product_id,
+                    last_price: row.try_get(6)?,
+                    last_in_store: row.try_get(7)?,
+                });
+            }
+        }
+        Ok(watchlists)
+    }
+
+    async fn delete_watchlist(&self, watchlist_id: i64) -> Result<bool> {
+        let mut client = self.connect().await?;
+        let transaction = client.transaction().await?;
+        transaction.execute("DELETE FROM watchlist_items WHERE watchlist_key = $1", &[&watchlist_id]).await?;
+        let deleted = transaction.execute("DELETE FROM watchlists WHERE watchlist_id = $1", &[&watchlist_id]).await?;
+        transaction.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    async fn select_watch_states(&self) -> Result<Vec<WatchState>> {
+        let rows = self.connect().await?.query("
+            SELECT wi.watchlist_key, wi.product_id, wi.last_price, wi.last_in_store, p.price,
+                   p.product_name_bold, p.product_name_thin, p.link,
+                   EXISTS(
+                        SELECT 1 FROM sites_products sp WHERE sp.product_key = wi.product_id
+                        AND (w.site_id = '' OR sp.site_key = w.site_id)
+                   )
+            FROM watchlist_items wi
+            JOIN watchlists w ON w.watchlist_id = wi.watchlist_key
+            LEFT JOIN products p ON p.product_id = wi.product_id", &[]).await?;
+        let mut states = Vec::new();
+        for row in &rows {
+            let bold: Option<String> = row.try_get(5)?;
+            let thin: Option<String> = row.try_get(6)?;
+            let name = match (bold, thin) {
+                (Some(b), Some(t)) if !t.is_empty() => format!("{} {}", b, t),
+                (Some(b), _) => b,
+                _ => String::new(),
+            };
+            states.push(WatchState {
+                watchlist_id: row.try_get(0)?,
+                product_id: row.try_get(1)?,
+                last_price: row.try_get(2)?,
+                last_in_store: row.try_get(3)?,
+                price: row.try_get(4)?,
+                name,
+                link: row.try_get::<_, Option<String>>(7)?.unwrap_or_default(),
+                in_store: row.try_get(8)?,
+            });
+        }
+        Ok(states)
+    }
+
+    async fn update_watch_states(&self, states: &[WatchState]) -> Result<()> {
+        let mut client = self.connect().await?;
+        let transaction = client.transaction().await?;
+        let stmt = transaction.prepare("
+            UPDATE watchlist_items SET last_price = COALESCE($1, last_price), last_in_store = $2
+            WHERE watchlist_key = $3 AND product_id = $4").await?;
+        for state in states {
+            transaction.execute(&stmt, &[&state.price, &state.in_store, &state.watchlist_id, &state.product_id])
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+/// The next page of the `products_export` cursor and the client to fetch the one after it with, `None` once the
+/// cursor is exhausted and the transaction committed.
+async fn fetch_page(client: Option<Client>) -> Result<Option<(Vec<Product>, Option<Client>)>> {
+    let client = match client {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    let rows = client.query(format!("FETCH {} FROM products_export", CURSOR_FETCH).as_str(), &[]).await?;
+    let products = rows.iter().map(product_from_row).collect::<Result<Vec<Product>>>()?;
+    if products.len() < CURSOR_FETCH {
+        client.batch_execute("COMMIT").await?;
+        return Ok(Some((products, None)));
+    }
+    Ok(Some((products, Some(client))))
+}
+
+fn bind(params: &[Param]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter()
+        .map(|param| match param {
+            Param::Text(text) => text as &(dyn ToSql + Sync),
+            Param::Int(int) => int as &(dyn ToSql + Sync),
+            Param::Real(real) => real as &(dyn ToSql + Sync),
+        })
+        .collect()
+}
+
+fn product_from_row(row: &Row) -> Result<Product> {
+    Ok(Product {
+        product_id: row.try_get(0)?,
+        product_number: row.try_get(1)?,
+        product_name_bold: row.try_get(2)?,
+        product_name_thin: row.try_get(3)?,
+        category: row.try_get(4)?,
+        product_number_short: row.try_get(5)?,
+        producer_name: row.try_get(6)?,
+        supplier_name: row.try_get(7)?,
+        is_kosher: row.try_get(8)?,
+        bottle_text_short: row.try_get(9)?,
+        restricted_parcel_quantity: row.try_get(10)?,
+        seal: row.try_get(11)?,
+        is_organic: row.try_get(12)?,
+        is_ethical: row.try_get(13)?,
+        ethical_label: row.try_get(14)?,
+        is_web_launch: row.try_get(15)?,
+        sell_start_date: row.try_get::<_, Option<&str>>(16)?.and_then(parse_datetime),
+        is_completely_out_of_stock: row.try_get(17)?,
+        is_temporary_out_of_stock: row.try_get(18)?,
+        alcohol_percentage: row.try_get(19)?,
+        volume: row.try_get(20)?,
+        price: row.try_get(21)?,
+        country: row.try_get(22)?,
+        origin_level1: row.try_get(23)?,
+        origin_level2: row.try_get(24)?,
+        vintage: row.try_get(25)?,
+        sub_category: row.try_get(26)?,
+        a_type: row.try_get(27)?,
+        style: row.try_get(28)?,
+        assortment_text: row.try_get(29)?,
+        beverage_description_short: row.try_get(30)?,
+        usage_text: row.try_get(31)?,
+        taste: row.try_get(32)?,
+        assortment: row.try_get(33)?,
+        is_manufacturing_country: row.try_get(34)?,
+        recycle_fee: row.try_get(35)?,
+        is_regional_retricted: row.try_get(36)?,
+        is_in_store_search_assortment: row.try_get(37)?,
+        is_news: row.try_get(38)?,
+        apk: row.try_get(39)?,
+        apk_recycling: row.try_get(40)?,
+        link: row.try_get(41)?,
+        apk_rank: row.try_get(42)?,
+        apk_percentile: row.try_get(43)?,
+        category_rank: row.try_get(44)?,
+        category_percentile: row.try_get(45)?,
+        sub_category_rank: row.try_get(46)?,
+        sub_category_percentile: row.try_get(47)?,
+        volume_bucket: row.try_get(48)?,
+        volume_bucket_rank: row.try_get(49)?,
+        volume_bucket_percentile: row.try_get(50)?,
+    })
+}
+
+fn stock_from_row(row: &Row) -> Result<StockLevel> {
+    Ok(StockLevel {
+        site_id: row.try_get(0)?,
+        product_id: row.try_get(1)?,
+        stock: row.try_get(2)?,
+        shelf: row.try_get(3)?,
+        last_seen: row.try_get::<_, Option<&str>>(4)?.and_then(parse_datetime),
+    })
+}
+
+fn site_from_row(row: &Row) -> Result<Site> {
+    Ok(Site {
+        site_id: row.try_get(0)?,
+        is_tasting_store: row.try_get(1)?,
+        alias: row.try_get(2)?,
+        address: row.try_get(3)?,
+        display_name: row.try_get(4)?,
+        postal_code: row.try_get(5)?,
+        city: row.try_get(6)?,
+        county: row.try_get(7)?,
+        country: row.try_get(8)?,
+        is_store: row.try_get(9)?,
+        is_agent: row.try_get(10)?,
+        is_active_for_agent_order: row.try_get(11)?,
+        phone: row.try_get(12)?,
+        email: row.try_get(13)?,
+        services: row.try_get(14)?,
+        depot: row.try_get(15)?,
+        name: row.try_get(16)?,
+        opening_hours: Vec::new(),
+        position: Position {
+            lat: row.try_get::<_, Option<f64>>(17)?.unwrap_or(0.0),
+            long: row.try_get::<_, Option<f64>>(18)?.unwrap_or(0.0),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::backend::behavior;
+
+    static TEST_DB: &str = "systemet_apk_test";
+
+    /// Needs a running server, `SYSTEMET_TEST_POSTGRES` points at it, e.g. a local container started with
+    /// `docker run -p 5432:5432 -e POSTGRES_HOST_AUTH_METHOD=trust postgres`.
+    /// The test recreates its own database on that server and passes without running if the variable isn't set.
+    #[tokio::test]
+    async fn test_postgres_behavior() {
+        let url = match std::env::var("SYSTEMET_TEST_POSTGRES") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("SYSTEMET_TEST_POSTGRES isn't set, skipping the postgres behavior test");
+                return;
+            }
+        };
+        let admin = PostgresStorage::new(&url).connect().await.unwrap();
+        admin.batch_execute(&format!("DROP DATABASE IF EXISTS {}", TEST_DB)).await.unwrap();
+        admin.batch_execute(&format!("CREATE DATABASE {}", TEST_DB)).await.unwrap();
+        behavior::run(&PostgresStorage::new(&format!("{} dbname={}", url, TEST_DB))).await;
+    }
+}
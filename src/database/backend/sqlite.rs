@@ -0,0 +1,127 @@
+use async_trait::async_trait;
+use rusqlite::Connection;
+use serde_json::Value;
+use crate::database::storage::init::*;
+use crate::database::storage::storage;
+use crate::database::storage::watchlist;
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::detail::ProductLookup;
+use crate::domain::models::product::{Product, ProductOpts, MinimalSite};
+use crate::domain::models::projection::ProductField;
+use crate::domain::models::refresh::LoadStats;
+use crate::domain::models::site::{Site, SiteStats, SiteOpts};
+use crate::domain::models::stock::StockLevel;
+use crate::domain::models::watchlist::{Watchlist, NewWatchlist, WatchState};
+use crate::domain::result::Result;
+use super::{Storage, ProductStream};
+
+/// A single database file, every call opens its own connection.
+pub struct SqliteStorage {
+    path: String,
+}
+
+impl SqliteStorage {
+    pub fn new(path: &str) -> Self {
+        SqliteStorage { path: path.to_string() }
+    }
+
+    async fn connection(&self) -> Result<Connection> {
+        Connection::open(&self.path)
+            .map_err(|e| e.into())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn init(&self) -> Result<()> {
+        info!("Initializing database {}", self.path);
+        init_pragmas(self.connection().await?).await?;
+        tokio::try_join!(init_product_db(self.connection().await?), init_site_db(self.connection().await?))?;
+        init_junction_db(self.connection().await?).await?;
+        init_watchlist_db(self.connection().await?).await?;
+        init_snapshot_db(self.connection().await?).await
+    }
+
+    async fn load_snapshot(&self, products: &[Product], sites: &[Site], mapping: &[MinimalSite]) -> Result<LoadStats> {
+        storage::load_snapshot(products, sites, mapping, self.connection().await?).await
+    }
+
+    async fn snapshot_version(&self) -> Result<i64> {
+        storage::select_snapshot_version(self.connection().await?).await
+    }
+
+    async fn select_products(&self, opts: ProductOpts, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>)
+        -> Result<Vec<Product>> {
+        storage::select_products_with_sites(opts, include_sites, exclude_sites, self.connection().await?).await
+    }
+
+    async fn stream_products(&self, opts: ProductOpts) -> Result<ProductStream> {
+        storage::stream_products(opts, self.connection().await?)
+    }
+
+    async fn select_product_fields(&self, opts: ProductOpts, fields: &[&'static ProductField]) -> Result<Vec<Value>> {
+        storage::select_product_fields(opts, fields, self.connection().await?).await
+    }
+
+    async fn select_product(&self, lookup: &ProductLookup) -> Result<Option<Product>> {
+        storage::select_product(lookup, self.connection().await?).await
+    }
+
+    async fn select_listings(&self, product_id: &ProductId) -> Result<Vec<StockLevel>> {
+        storage::select_listings(product_id, self.connection().await?).await
+    }
+
+    async fn select_sites(&self, opts: &SiteOpts) -> Result<Vec<Site>> {
+        storage::select_sites(opts, self.connection().await?).await
+    }
+
+    async fn site_exists(&self, site_id: &SiteId) -> Result<bool> {
+        storage::site_exists(site_id, self.connection().await?).await
+    }
+
+    async fn select_site_stats(&self, site_id: &SiteId) -> Result<SiteStats> {
+        storage::select_site_stats(site_id, self.connection().await?).await
+    }
+
+    async fn select_stock(&self, site_id: &SiteId, min_stock: i64) -> Result<Vec<StockLevel>> {
+        storage::select_stock(site_id, min_stock, self.connection().await?).await
+    }
+
+    async fn insert_watchlist(&self, watchlist: &NewWatchlist) -> Result<i64> {
+        watchlist::insert_watchlist(watchlist, self.connection().await?).await
+    }
+
+    async fn select_watchlist(&self, watchlist_id: i64) -> Result<Option<Watchlist>> {
+        watchlist::select_watchlist(watchlist_id, self.connection().await?).await
+    }
+
+    async fn select_watchlists(&self, watchlist_ids: &[i64]) -> Result<Vec<Watchlist>> {
+        watchlist::select_watchlists(watchlist_ids, self.connection().await?).await
+    }
+
+    async fn delete_watchlist(&self, watchlist_id: i64) -> Result<bool> {
+        watchlist::delete_watchlist(watchlist_id, self.connection().await?).await
+    }
+
+    async fn select_watch_states(&self) -> Result<Vec<WatchState>> {
+        watchlist::select_watch_states(self.connection().await?).await
+    }
+
+    async fn update_watch_states(&self, states: &[WatchState]) -> Result<()> {
+        watchlist::update_watch_states(states, self.connection().await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::backend::behavior;
+
+    #[tokio::test]
+    async fn test_sqlite_behavior() {
+        let path = std::env::temp_dir().join("test_sqlite_behavior.db").to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        behavior::run(&SqliteStorage::new(&path)).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,2 +1,3 @@
 pub mod api;
-mod storage;
\ No newline at end of file
+mod backend;
+mod storage;
@@ -1,8 +1,56 @@
 use rusqlite::{Connection, NO_PARAMS};
 use crate::domain::result::Result;
 
+/// `CREATE TABLE IF NOT EXISTS` leaves tables from older versions untouched, columns added later go through here.
+pub fn add_column_if_missing(con: &Connection, table: &str, column: &str, declaration: &str) -> Result<()> {
+    let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut columns = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(1))?;
+    if !columns.any(|c| c.map(|c| c == column).unwrap_or(false)) {
+        info!("Adding column {} to table {}", column, table);
+        con.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, declaration), NO_PARAMS)?;
+    }
+    Ok(())
+}
+
+/// SQLite can't drop a `NOT NULL` constraint, tables that are reloaded on every refresh are dropped instead
+/// and recreated by the caller.
+pub fn drop_if_not_null(con: &Connection, table: &str, column: &str) -> Result<()> {
+    // The pragma statement has to be finalized before the table can be dropped
+    let not_null = {
+        let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table))?;
+        let mut columns = stmt.query_map(NO_PARAMS, |row| Ok((row.get::<_, String>(1)?, row.get::<_, bool>(3)?)))?;
+        columns.any(|c| c.map(|(name, not_null)| name == column && not_null).unwrap_or(false))
+    };
+    if not_null {
+        info!("Dropping table {} to make column {} nullable, it's refilled on the next refresh", table, column);
+        con.execute(&format!("DROP TABLE {}", table), NO_PARAMS)?;
+    }
+    Ok(())
+}
+
+/// WAL lets queries keep reading the previous snapshot while a refresh writes the next one.
+pub async fn init_pragmas(con: Connection) -> Result<()> {
+    let mode: String = con.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| row.get(0))?;
+    info!("Database journal mode is {}", mode);
+    Ok(())
+}
+
+/// Set on every load, defaulted so rows from before they were added read until the next refresh.
+const RANK_COLUMNS: &[(&str, &str)] = &[
+    ("apk_rank", "int not null default 0"),
+    ("apk_percentile", "REAL not null default 0"),
+    ("category_rank", "int not null default 0"),
+    ("category_percentile", "REAL not null default 0"),
+    ("sub_category_rank", "int not null default 0"),
+    ("sub_category_percentile", "REAL not null default 0"),
+    ("volume_bucket", "text not null default ''"),
+    ("volume_bucket_rank", "int not null default 0"),
+    ("volume_bucket_percentile", "REAL not null default 0"),
+];
+
 pub async fn init_product_db(con: Connection) -> Result<()> {
     info!("Creating products table");
+    drop_if_not_null(&con, "products", "vintage")?;
     con.execute("CREATE TABLE IF NOT EXISTS products (
                     product_id VARCHAR PRIMARY KEY,
                     product_number text,
@@ -29,7 +77,7 @@ pub async fn init_product_db(con: Connection) -> Result<()> {
                     country text,
                     origin_level1 text,
                     origin_level2 text,
-                    vintage int not null,
+                    vintage int,
                     sub_category text,
                     a_type text,
                     style text,
@@ -48,6 +96,16 @@ pub async fn init_product_db(con: Connection) -> Result<()> {
                     link text not null,
                     ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         )", NO_PARAMS)?;
+    for (column, declaration) in RANK_COLUMNS {
+        add_column_if_missing(&con, "products", column, declaration)?;
+    }
+    // Every ranking query sorts on apk or apk_recycling descending, optionally within one category
+    con.execute_batch("
+        CREATE INDEX IF NOT EXISTS idx_products_apk ON products (apk DESC);
+        CREATE INDEX IF NOT EXISTS idx_products_apk_recycling ON products (apk_recycling DESC);
+        CREATE INDEX IF NOT EXISTS idx_products_category_apk ON products (category, apk DESC);
+        CREATE INDEX IF NOT EXISTS idx_products_category_apk_recycling ON products (category, apk_recycling DESC);
+    ")?;
     info!("Products table created");
     Ok(())
 }
@@ -71,7 +129,20 @@ pub async fn init_site_db(con: Connection) -> Result<()> {
             email text,
             services text,
             depot text,
-            name text
+            name text,
+            lat REAL,
+            long REAL
+        )", NO_PARAMS)?;
+    add_column_if_missing(&con, "sites", "lat", "REAL")?;
+    add_column_if_missing(&con, "sites", "long", "REAL")?;
+    con.execute("CREATE TABLE IF NOT EXISTS opening_hours (
+            site_key VARCHAR REFERENCES sites(site_id) ON DELETE CASCADE,
+            date text not null,
+            is_open bool not null,
+            reason text,
+            open_from text,
+            open_to text,
+            PRIMARY KEY (site_key, date)
         )", NO_PARAMS)?;
     info!("Sites table created");
     Ok(())
@@ -83,8 +154,49 @@ pub async fn init_junction_db(con: Connection) -> Result<()> {
     con.execute("CREATE TABLE IF NOT EXISTS sites_products (
                     product_key VARCHAR REFERENCES products(product_id) ON DELETE CASCADE,
                     site_key VARCHAR REFERENCES sites(site_id) ON DELETE CASCADE,
+                    stock int,
+                    shelf text,
+                    last_seen text,
                     PRIMARY KEY (product_key, site_key)
         )", NO_PARAMS)?;
-    info!("Sites table created");
+    add_column_if_missing(&con, "sites_products", "stock", "int")?;
+    add_column_if_missing(&con, "sites_products", "shelf", "text")?;
+    add_column_if_missing(&con, "sites_products", "last_seen", "text")?;
+    // The primary key covers lookups by product, store scoped queries look up by site
+    con.execute("CREATE INDEX IF NOT EXISTS idx_sites_products_site ON sites_products (site_key, product_key)",
+                NO_PARAMS)?;
+    info!("Junction table created");
+    Ok(())
+}
+pub async fn init_watchlist_db(con: Connection) -> Result<()> {
+    info!("Creating watchlist tables");
+    con.execute_batch("CREATE TABLE IF NOT EXISTS watchlists (
+                    watchlist_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name text not null,
+                    site_id text not null,
+                    notifier_kind text not null,
+                    notifier_target text not null,
+                    ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE TABLE IF NOT EXISTS watchlist_items (
+                    watchlist_key INTEGER REFERENCES watchlists(watchlist_id) ON DELETE CASCADE,
+                    product_id VARCHAR not null,
+                    last_price REAL,
+                    last_in_store BOOLEAN not null DEFAULT false,
+                    PRIMARY KEY (watchlist_key, product_id)
+        );")?;
+    info!("Watchlist tables created");
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn init_snapshot_db(con: Connection) -> Result<()> {
+    info!("Creating snapshot table");
+    con.execute_batch("CREATE TABLE IF NOT EXISTS snapshot (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    version INTEGER not null,
+                    ts TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT OR IGNORE INTO snapshot (id, version) VALUES (0, 0);")?;
+    info!("Snapshot table created");
+    Ok(())
+}
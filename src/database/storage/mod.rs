@@ -1,3 +1,6 @@
-mod query_utils;
+pub mod query_utils;
 pub mod init;
-pub mod storage;
\ No newline at end of file
+pub mod storage;
+pub mod watchlist;
+#[cfg(test)]
+pub mod test_utils;
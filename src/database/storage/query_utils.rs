@@ -1,58 +1,408 @@
+use rusqlite::ToSql;
+use rusqlite::types::ToSqlOutput;
+use crate::domain::models::ids::SiteId;
 use crate::domain::models::product::ProductOpts;
+use crate::domain::models::projection::{ProductField, FieldKind, PRODUCT_FIELDS};
+use crate::domain::models::site::SiteOpts;
+use crate::domain::scoring;
+use crate::domain::scoring::expression::{Expr, Op, Function};
 
 pub struct QueryBuilder {
     opts: ProductOpts,
+    columns: String,
+    include_sites: Vec<SiteId>,
+    exclude_sites: Vec<SiteId>,
+    dialect: Dialect,
+    params: Vec<Param>,
 }
 
 impl QueryBuilder {
-    fn to_query(&self) -> String {
-        format!("{}{}{}{}{}", self.create_base(), self.include_category(), self.add_site(),
-                self.include_recycling(), self.limit())
+    fn new(opts: ProductOpts, columns: String, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>,
+           dialect: Dialect) -> Self {
+        QueryBuilder { opts, columns, include_sites, exclude_sites, dialect, params: Vec::new() }
     }
 
-    fn create_base(&self) -> String {
-        return format!("SELECT * FROM products p \
-            WHERE volume <= {}", self.opts.max_volume);
+    fn into_query(mut self) -> (String, Vec<Param>) {
+        let mut query = self.create_base();
+        query.push_str(&self.include_category());
+        query.push_str(&self.add_site());
+        query.push_str(&self.include_recycling());
+        query.push_str(&self.limit());
+        (query, self.params)
     }
 
-    fn add_site(&self) -> String {
-        if self.opts.site_id != "" {
+    /// Adds a parameter, returns its placeholder.
+    fn bind(&mut self, param: Param) -> String {
+        self.params.push(param);
+        self.dialect.bound(&self.params[self.params.len() - 1], self.params.len())
+    }
+
+    fn create_base(&mut self) -> String {
+        let max_volume = self.bind(Param::Real(self.opts.max_volume));
+        format!("SELECT {} FROM products p \
+            WHERE volume <= {}", self.columns, max_volume)
+    }
+
+    fn add_site(&mut self) -> String {
+        let mut condition = if !self.opts.site_id.is_empty() {
+            let site_id = self.bind(Param::Text(self.opts.site_id.to_string()));
             format!(" AND p.product_id IN (
-                                       SELECT product_key FROM sites_products sp WHERE sp.site_key='{}'
-                                       )", self.opts.site_id)
+                                       SELECT product_key FROM sites_products sp WHERE sp.site_key = {}{}
+                                       )", site_id, self.min_stock())
         } else if self.opts.exists_in_store {
             String::from(" AND EXISTS(
                             SELECT * FROM sites_products WHERE product_key = p.product_id
                        )")
         } else {
             String::new()
+        };
+        for site_id in std::mem::take(&mut self.include_sites) {
+            condition.push_str(&format!(" AND p.product_id IN (
+                                       SELECT product_key FROM sites_products sp WHERE sp.site_key = {}
+                                       )", self.bind(Param::Text(site_id.to_string()))));
+        }
+        for site_id in std::mem::take(&mut self.exclude_sites) {
+            condition.push_str(&format!(" AND p.product_id NOT IN (
+                                       SELECT product_key FROM sites_products sp WHERE sp.site_key = {}
+                                       )", self.bind(Param::Text(site_id.to_string()))));
+        }
+        condition
+    }
+
+    /// Unreported stock is NULL and never compares as enough.
+    fn min_stock(&mut self) -> String {
+        if self.opts.min_stock > 0 {
+            format!(" AND sp.stock >= {}", self.bind(Param::Int(self.opts.min_stock)))
+        } else {
+            String::new()
         }
     }
 
-    fn include_category(&self) -> String {
-        if self.opts.category != "" {
-            format!(" AND p.category = '{}'", self.opts.category)
+    fn include_category(&mut self) -> String {
+        if !self.opts.category.is_empty() {
+            format!(" AND p.category = {}", self.bind(Param::Text(self.opts.category.clone())))
         } else {
             String::new()
         }
     }
 
+    /// An unknown score was already refused by `ProductOpts::validate_score`, apk is a safe fallback.
     fn include_recycling(&self) -> String {
-        if self.opts.include_recycling {
+        if !self.opts.score.is_empty() {
+            let expr = scoring::SCORES.get(&self.opts.score)
+                .map(|score| score_sql(score.expression()))
+                .unwrap_or_else(|_| String::from("apk"));
+            format!(" ORDER BY {}", expr)
+        } else if self.opts.include_recycling {
             String::from(" ORDER BY apk_recycling")
         } else {
             String::from(" ORDER BY apk")
         }
     }
 
-    fn limit(&self) -> String {
-        format!(" DESC LIMIT {};", self.opts.count)
+    fn limit(&mut self) -> String {
+        format!(" DESC LIMIT {};", self.bind(Param::Int(self.opts.count as i64)))
+    }
+
+    /// Top products matching `opts`, additionally required to be listed in every one of `include_sites`
+    /// and in none of `exclude_sites`. Returns the query and its positional parameters.
+    pub fn build_with_sites(opts: ProductOpts, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>,
+                            dialect: Dialect) -> (String, Vec<Param>) {
+        QueryBuilder::new(opts, product_columns(), include_sites, exclude_sites, dialect).into_query()
+    }
+
+    /// Top products matching `opts`, selecting only the columns behind `fields` in that order.
+    pub fn build_projected(opts: ProductOpts, fields: &[&ProductField], dialect: Dialect) -> (String, Vec<Param>) {
+        QueryBuilder::new(opts, columns(fields), Vec::new(), Vec::new(), dialect).into_query()
+    }
+}
+
+/// A positional parameter of a built query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    Text(String),
+    Int(i64),
+    Real(f64),
+}
+
+impl ToSql for Param {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Param::Text(text) => text.to_sql(),
+            Param::Int(int) => int.to_sql(),
+            Param::Real(real) => real.to_sql(),
+        }
+    }
+}
+
+/// Every product column of `p`, in the order of `PRODUCT_FIELDS`. Columns added to an existing table end up
+/// after the rest, so rows are never read with `SELECT *`.
+pub fn product_columns() -> String {
+    columns(&PRODUCT_FIELDS.iter().collect::<Vec<&ProductField>>())
+}
+
+fn columns(fields: &[&ProductField]) -> String {
+    fields.iter()
+        .map(|f| format!("p.{}", f.column))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// `expr` as SQL over the columns of `p`, computing what `Expr::evaluate` does, valid in every dialect.
+pub fn score_sql(expr: &Expr) -> String {
+    let compare = |a: &Expr, op: &str, b: &Expr| format!("(CASE WHEN {} {} {} THEN 1 ELSE 0 END)",
+                                                          score_sql(a), op, score_sql(b));
+    match expr {
+        Expr::Number(n) => format!("{:?}", n),
+        Expr::Field(field) => match field.kind {
+            FieldKind::Bool => format!("(CASE WHEN p.{} THEN 1 ELSE 0 END)", field.column),
+            FieldKind::Int => format!("COALESCE(p.{}, 0)", field.column),
+            _ => format!("p.{}", field.column),
+        },
+        Expr::Neg(e) => format!("(-{})", score_sql(e)),
+        Expr::Binary(op, a, b) => match op {
+            Op::Add => format!("({} + {})", score_sql(a), score_sql(b)),
+            Op::Sub => format!("({} - {})", score_sql(a), score_sql(b)),
+            Op::Mul => format!("({} * {})", score_sql(a), score_sql(b)),
+            // Without the casts both backends divide integers as integers, postgres refuses to divide by zero
+            Op::Div => format!("COALESCE(CAST({} AS DOUBLE PRECISION) / NULLIF(CAST({} AS DOUBLE PRECISION), 0), 0)",
+                               score_sql(a), score_sql(b)),
+            Op::Lt => compare(a, "<", b),
+            Op::Le => compare(a, "<=", b),
+            Op::Gt => compare(a, ">", b),
+            Op::Ge => compare(a, ">=", b),
+            Op::Eq => compare(a, "=", b),
+            Op::Ne => compare(a, "<>", b),
+        },
+        Expr::Call(function, args) => {
+            let args: Vec<String> = args.iter().map(score_sql).collect();
+            match function {
+                Function::Min => format!("(CASE WHEN {a} < {b} THEN {a} ELSE {b} END)", a = args[0], b = args[1]),
+                Function::Max => format!("(CASE WHEN {a} > {b} THEN {a} ELSE {b} END)", a = args[0], b = args[1]),
+                Function::Abs => format!("ABS({})", args[0]),
+                Function::If => format!("(CASE WHEN {} <> 0 THEN {} ELSE {} END)", args[0], args[1], args[2]),
+            }
+        }
+    }
+}
+
+/// Where the supported backends disagree on syntax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Sqlite,
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    Postgres,
+}
+
+impl Dialect {
+    fn param(&self, index: usize) -> String {
+        match self {
+            Dialect::Sqlite => format!("?{}", index),
+            Dialect::Postgres => format!("${}", index),
+        }
+    }
+
+    /// Postgres infers the type of a parameter from where it's used, numbers are cast so `Param::Int` binds
+    /// as well against an `INTEGER` column as against `LIMIT`.
+    fn bound(&self, param: &Param, index: usize) -> String {
+        match (self, param) {
+            (Dialect::Postgres, Param::Int(_)) => format!("{}::BIGINT", self.param(index)),
+            (Dialect::Postgres, Param::Real(_)) => format!("{}::DOUBLE PRECISION", self.param(index)),
+            _ => self.param(index),
+        }
+    }
+
+    fn equals_nocase(&self, column: &str, index: usize) -> String {
+        match self {
+            Dialect::Sqlite => format!("{} = {} COLLATE NOCASE", column, self.param(index)),
+            Dialect::Postgres => format!("lower({}) = lower({})", column, self.param(index)),
+        }
+    }
+
+    /// SQLite's `LIKE` already ignores ASCII case.
+    fn like_nocase(&self, column: &str, index: usize) -> String {
+        match self {
+            Dialect::Sqlite => format!("{} LIKE {}", column, self.param(index)),
+            Dialect::Postgres => format!("{} ILIKE {}", column, self.param(index)),
+        }
+    }
+
+    fn boolean(&self, flag: bool) -> &'static str {
+        match (self, flag) {
+            (Dialect::Sqlite, true) => "1",
+            (Dialect::Sqlite, false) => "0",
+            (Dialect::Postgres, true) => "true",
+            (Dialect::Postgres, false) => "false",
+        }
+    }
+}
+
+pub struct SiteQueryBuilder;
+
+impl SiteQueryBuilder {
+    /// Returns the query and its positional parameters.
+    pub fn build(opts: &SiteOpts) -> (String, Vec<String>) {
+        SiteQueryBuilder::build_for(opts, Dialect::Sqlite)
     }
 
-    pub fn build(opts: ProductOpts) -> String {
-        let this = QueryBuilder{ opts };
-        return this.to_query();
+    pub fn build_for(opts: &SiteOpts, dialect: Dialect) -> (String, Vec<String>) {
+        let mut query = String::from("SELECT site_id, is_tasting_store, alias, address, display_name, postal_code, city, \
+            county, country, is_store, is_agent, is_active_for_agent_order, phone, email, services, depot, name, \
+            lat, long FROM sites WHERE 1=1");
+        let mut params = Vec::new();
+        if !opts.site_id.is_empty() {
+            params.push(opts.site_id.to_string());
+            query.push_str(&format!(" AND site_id = {}", dialect.param(params.len())));
+        }
+        if !opts.city.is_empty() {
+            params.push(opts.city.clone());
+            query.push_str(&format!(" AND {}", dialect.equals_nocase("city", params.len())));
+        }
+        if !opts.county.is_empty() {
+            params.push(opts.county.clone());
+            query.push_str(&format!(" AND {}", dialect.equals_nocase("county", params.len())));
+        }
+        if !opts.postal_code.is_empty() {
+            params.push(format!("{}%", opts.postal_code.replace(' ', "")));
+            query.push_str(&format!(" AND REPLACE(postal_code, ' ', '') LIKE {}", dialect.param(params.len())));
+        }
+        if !opts.q.is_empty() {
+            params.push(format!("%{}%", opts.q));
+            query.push_str(&format!(" AND ({} OR {})", dialect.like_nocase("address", params.len()),
+                                    dialect.like_nocase("alias", params.len())));
+        }
+        for (column, flag) in &[("is_store", opts.is_store), ("is_agent", opts.is_agent),
+            ("is_tasting_store", opts.is_tasting_store)] {
+            if let Some(flag) = flag {
+                query.push_str(&format!(" AND {} = {}", column, dialect.boolean(*flag)));
+            }
+        }
+        query.push_str(" ORDER BY site_id");
+        (query, params)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(site_id: &str) -> ProductOpts {
+        ProductOpts {
+            count: 10,
+            include_recycling: false,
+            exists_in_store: false,
+            max_volume: 1000.0,
+            site_id: site_id.into(),
+            category: String::from("öl"),
+            ..ProductOpts::default()
+        }
+    }
 
+    #[test]
+    fn test_excludes_other_site() {
+        let (query, params) = QueryBuilder::build_with_sites(opts("0102"), Vec::new(), vec!["0104".into()],
+                                                             Dialect::Sqlite);
+        assert!(query.contains("volume <= ?1 AND p.category = ?2"));
+        assert!(query.contains("sp.site_key = ?3"));
+        assert!(query.contains("NOT IN (\n"));
+        assert!(query.contains("sp.site_key = ?4"));
+        assert!(query.ends_with("ORDER BY apk DESC LIMIT ?5;"));
+        assert_eq!(vec![Param::Real(1000.0), Param::Text(String::from("öl")), Param::Text(String::from("0102")),
+                        Param::Text(String::from("0104")), Param::Int(10)], params);
+    }
+
+    #[test]
+    fn test_min_stock() {
+        let build = |opts| QueryBuilder::build_with_sites(opts, Vec::new(), Vec::new(), Dialect::Sqlite);
+        let (query, params) = build(ProductOpts { min_stock: 2, ..opts("0102") });
+        assert!(query.contains("sp.site_key = ?3 AND sp.stock >= ?4"));
+        assert_eq!(Param::Int(2), params[3]);
+        assert!(!build(ProductOpts { min_stock: 2, ..opts("") }).0.contains("sp.stock"));
+        assert!(!build(opts("0102")).0.contains("sp.stock"));
+    }
+
+    #[test]
+    fn test_postgres_casts_numbers() {
+        let (query, _) = QueryBuilder::build_with_sites(ProductOpts { min_stock: 2, ..opts("0102") }, Vec::new(),
+                                                        Vec::new(), Dialect::Postgres);
+        assert!(query.contains("volume <= $1::DOUBLE PRECISION AND p.category = $2"));
+        assert!(query.contains("sp.site_key = $3 AND sp.stock >= $4::BIGINT"));
+        assert!(query.ends_with("LIMIT $5::BIGINT;"));
+    }
+
+    #[test]
+    fn test_score_order() {
+        let (query, _) = QueryBuilder::build_with_sites(ProductOpts { score: String::from("apk_recycling"), ..opts("") },
+                                                        Vec::new(), Vec::new(), Dialect::Sqlite);
+        assert!(query.ends_with("ORDER BY p.apk_recycling DESC LIMIT ?3;"));
+        let (query, _) = QueryBuilder::build_with_sites(ProductOpts { score: String::from("standard_drinks_per_krona"),
+            include_recycling: true, ..opts("") }, Vec::new(), Vec::new(), Dialect::Sqlite);
+        assert!(query.contains("ORDER BY COALESCE(CAST(") && !query.contains("ORDER BY apk_recycling"));
+    }
+
+    /// The database has to rank by the same values `Score::evaluate` computes.
+    #[test]
+    fn test_score_sql_matches_evaluate() {
+        let con = rusqlite::Connection::open_in_memory().unwrap();
+        let product = serde_json::json!({"Apk": 2.0, "AlcoholPercentage": 80.0, "Volume": 500.0, "Price": 10.0,
+            "IsOrganic": true, "IsKosher": false, "Vintage": null, "RestrictedParcelQuantity": 3});
+        let row = "SELECT 2.0 AS apk, 80.0 AS alcohol_percentage, 500.0 AS volume, 10.0 AS price, 1 AS is_organic, \
+            0 AS is_kosher, NULL AS vintage, 3 AS restricted_parcel_quantity";
+        for source in &["Apk * if(AlcoholPercentage > 40, 40 / AlcoholPercentage, 1)", "Apk + 0.2 * IsOrganic - 0.001 * Price",
+            "RestrictedParcelQuantity / 2", "Price / Vintage", "-min(Apk, IsKosher) + max(abs(-Volume), 1)",
+            "(Price >= 10) + (Price <= 9) + (Price == 10) + (Price != 10) + (Price < 11)", "if(IsKosher, 1, 2)"] {
+            let expr = Expr::parse(source).unwrap();
+            let sql = format!("SELECT {} FROM ({}) p", score_sql(&expr), row);
+            let value: f64 = con.query_row(&sql, rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+            assert_eq!(expr.evaluate(product.as_object().unwrap()), value, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_site_filters() {
+        let opts = SiteOpts {
+            postal_code: String::from("113 5"),
+            q: String::from("Odengatan"),
+            is_agent: Some(false),
+            ..SiteOpts::default()
+        };
+        let (query, params) = SiteQueryBuilder::build(&opts);
+        assert_eq!(vec![String::from("1135%"), String::from("%Odengatan%")], params);
+        assert!(query.contains("REPLACE(postal_code, ' ', '') LIKE ?1"));
+        assert!(query.contains("(address LIKE ?2 OR alias LIKE ?2)"));
+        assert!(query.contains("AND is_agent = 0"));
+        assert!(!query.contains("is_store ="));
+    }
+
+    #[test]
+    fn test_postgres_site_filters() {
+        let opts = SiteOpts {
+            city: String::from("stockholm"),
+            q: String::from("Odengatan"),
+            is_store: Some(true),
+            ..SiteOpts::default()
+        };
+        let (query, _) = SiteQueryBuilder::build_for(&opts, Dialect::Postgres);
+        assert!(query.contains("lower(city) = lower($1)"));
+        assert!(query.contains("(address ILIKE $2 OR alias ILIKE $2)"));
+        assert!(query.contains("AND is_store = true"));
+        assert!(!query.contains('?'));
+    }
+
+    #[test]
+    fn test_projected_columns() {
+        let fields = crate::domain::models::projection::projection("Price,Type", "").unwrap().unwrap();
+        let (query, _) = QueryBuilder::build_projected(opts(""), &fields, Dialect::Sqlite);
+        assert!(query.starts_with("SELECT p.price, p.a_type FROM products p"));
+        assert!(query.ends_with("ORDER BY apk DESC LIMIT ?3;"));
+    }
+
+    #[test]
+    fn test_request_values_are_bound() {
+        let injected = "01' OR '1'='1";
+        let (query, params) = QueryBuilder::build_with_sites(ProductOpts { category: String::from(injected), ..opts(injected) },
+                                                             vec![injected.into()], vec![injected.into()], Dialect::Sqlite);
+        assert!(!query.contains('\''), "{}", query);
+        assert_eq!(4, params.iter().filter(|p| **p == Param::Text(String::from(injected))).count());
+    }
+}
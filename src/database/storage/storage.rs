@@ -1,18 +1,56 @@
-use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::{Connection, NO_PARAMS, OptionalExtension, Row};
+use crate::domain::models::detail::ProductLookup;
 use crate::domain::models::product::{Product, ProductOpts, MinimalSite};
-use crate::domain::models::site::{Site, Position};
+use crate::domain::models::projection::{ProductField, FieldKind};
+use crate::domain::models::refresh::{LoadStats, junction_rows, out_of_stock};
+use crate::domain::models::stock::StockLevel;
+use crate::domain::models::site::{Site, Position, SiteStats, CategoryBest, SiteOpts, OpeningTime, median};
+use crate::domain::models::ids::{ProductId, SiteId};
+use chrono::{NaiveDateTime, Utc};
+use crate::domain::models::serialization_helpers::{parse_date, parse_datetime, parse_time, DATETIME_FORMAT,
+                                                   DATE_FORMAT, TIME_FORMAT};
+use std::collections::{HashMap, HashSet};
 use crate::domain::result::Result;
 use crate::database::storage::query_utils;
+use crate::database::storage::query_utils::{Dialect, Param};
 use std::time::SystemTime;
+use serde_json::{Map, Value};
+use futures::SinkExt;
+use futures::channel::mpsc;
+use crate::database::backend::ProductStream;
 
-pub async fn insert_products(products: &Vec<Product>, mut con: Connection) -> Result<()> {
+/// Replaces the products, sites and store assortments in one transaction and bumps the snapshot version in it,
+/// readers keep seeing the previous snapshot until it's committed.
+pub async fn load_snapshot(products: &[Product], sites: &[Site], mapping: &[MinimalSite], mut con: Connection)
+    -> Result<LoadStats> {
     let start = SystemTime::now();
-    info!("Starting transaction to insert {} products", products.len());
+    info!("Starting transaction to load {} products and {} sites", products.len(), sites.len());
+    tune_for_load(&con)?;
     let transaction = con.transaction()?;
-    transaction.execute_batch("DELETE FROM products;")?;
-    for product in products {
-        transaction.execute(
-            "
+    // Read before the junction table is cleared
+    let previous = select_last_seen(&out_of_stock(mapping), &transaction)?;
+    // Clearing the children first skips a cascading delete per product and site
+    transaction.execute_batch("
+        DELETE FROM sites_products;
+        DELETE FROM opening_hours;
+        DELETE FROM sites;
+        DELETE FROM products;")?;
+    insert_products(products, &transaction)?;
+    insert_sites(sites, &transaction)?;
+    let stats = insert_junctions(mapping, &previous, &transaction)?;
+    transaction.execute("UPDATE snapshot SET version = version + 1, ts = CURRENT_TIMESTAMP WHERE id = 0", NO_PARAMS)?;
+    transaction.commit().map_err(|e| -> rusqlite::Error {
+        warn!("{}", e);
+        e
+    })?;
+    info!("Committed snapshot in {} millis: {}", SystemTime::now().duration_since(start)?.as_millis(), stats);
+    Ok(stats)
+}
+
+fn insert_products(products: &[Product], con: &Connection) -> Result<()> {
+    let start = SystemTime::now();
+    {
+        let mut stmt = con.prepare_cached("
               INSERT INTO products (
               product_id,
               product_number,
@@ -55,14 +93,23 @@ pub async fn insert_products(products: &Vec<Product>, mut con: Connection) -> Re
               is_news,
               apk,
               apk_recycling,
-              link)
+              link,
+              apk_rank,
+              apk_percentile,
+              category_rank,
+              category_percentile,
+              sub_category_rank,
+              sub_category_percentile,
+              volume_bucket,
+              volume_bucket_rank,
+              volume_bucket_percentile)
         VALUES (
               ?1,
               ?2,
               ?3,
               ?4,
               ?5,
-              ?5,
+              ?6,
               ?7,
               ?8,
               ?9,
@@ -98,114 +145,135 @@ pub async fn insert_products(products: &Vec<Product>, mut con: Connection) -> Re
               ?39,
               ?40,
               ?41,
-              ?42
-        )", params![
-        product.product_id.as_str(),
-        product.product_number.as_str(),
-        product.product_name_bold.as_str(),
-        product.product_name_thin.as_str(),
-        product.category.as_str().to_lowercase(),
-        product.product_number_short.as_str(),
-        product.producer_name.as_str(),
-        product.supplier_name.as_str(),
-        product.is_kosher,
-        product.bottle_text_short.as_str(),
-        product.restricted_parcel_quantity,
-        product.seal.as_str(),
-        product.is_organic,
-        product.is_ethical,
-        product.ethical_label.as_str(),
-        product.is_web_launch,
-        product.sell_start_date.as_str(),
-        product.is_completely_out_of_stock,
-        product.is_temporary_out_of_stock,
-        product.alcohol_percentage,
-        product.volume,
-        product.price,
-        product.country.as_str(),
-        product.origin_level1.as_str(),
-        product.origin_level2.as_str(),
-        product.vintage,
-        product.sub_category.as_str(),
-        product.a_type.as_str(),
-        product.style.as_str(),
-        product.assortment_text.as_str(),
-        product.beverage_description_short.as_str(),
-        product.usage_text.as_str(),
-        product.taste.as_str(),
-        product.assortment.as_str(),
-        product.is_manufacturing_country,
-        product.recycle_fee,
-        product.is_regional_retricted,
-        product.is_in_store_search_assortment.as_str(),
-        product.is_news,
-        product.apk,
-        product.apk_recycling,
-        product.link
-        ])?;
+              ?42,
+              ?43,
+              ?44,
+              ?45,
+              ?46,
+              ?47,
+              ?48,
+              ?49,
+              ?50,
+              ?51
+        )")?;
+        for product in products {
+            stmt.execute(params![
+                product.product_id.as_str(),
+                product.product_number.as_str(),
+                product.product_name_bold.as_str(),
+                product.product_name_thin.as_deref(),
+                product.category.as_str().to_lowercase(),
+                product.product_number_short.as_str(),
+                product.producer_name.as_deref(),
+                product.supplier_name.as_deref(),
+                product.is_kosher,
+                product.bottle_text_short.as_str(),
+                product.restricted_parcel_quantity,
+                product.seal.as_deref(),
+                product.is_organic,
+                product.is_ethical,
+                product.ethical_label.as_deref(),
+                product.is_web_launch,
+                product.sell_start_date.map(|d| d.format(DATETIME_FORMAT).to_string()),
+                product.is_completely_out_of_stock,
+                product.is_temporary_out_of_stock,
+                product.alcohol_percentage,
+                product.volume,
+                product.price,
+                product.country.as_str(),
+                product.origin_level1.as_deref(),
+                product.origin_level2.as_deref(),
+                product.vintage,
+                product.sub_category.as_deref(),
+                product.a_type.as_deref(),
+                product.style.as_deref(),
+                product.assortment_text.as_str(),
+                product.beverage_description_short.as_deref(),
+                product.usage_text.as_deref(),
+                product.taste.as_deref(),
+                product.assortment.as_str(),
+                product.is_manufacturing_country,
+                product.recycle_fee,
+                product.is_regional_retricted,
+                product.is_in_store_search_assortment.as_str(),
+                product.is_news,
+                product.apk,
+                product.apk_recycling,
+                product.link,
+                product.apk_rank,
+                product.apk_percentile,
+                product.category_rank,
+                product.category_percentile,
+                product.sub_category_rank,
+                product.sub_category_percentile,
+                product.volume_bucket.as_str(),
+                product.volume_bucket_rank,
+                product.volume_bucket_percentile
+            ])?;
+        }
     }
-    transaction.commit().map_err(|e| -> rusqlite::Error {
-        warn!("{}", e);
-        e
-    })?;
-    info!("Committed transaction of {} products in {} seconds", products.len(),
-          SystemTime::now().duration_since(start)?.as_secs());
+    info!("Inserted {} products in {} millis", products.len(), SystemTime::now().duration_since(start)?.as_millis());
     Ok(())
 }
 
-pub async fn select_all_products(opts:ProductOpts, con: Connection) -> Result<Vec<Product>> {
-    let query = query_utils::QueryBuilder::build(opts);
-    select_all(query.as_str(), con).await
+pub async fn select_products_with_sites(opts: ProductOpts, include_sites: Vec<SiteId>, exclude_sites: Vec<SiteId>,
+                                        con: Connection) -> Result<Vec<Product>> {
+    let (query, params) = query_utils::QueryBuilder::build_with_sites(opts, include_sites, exclude_sites, Dialect::Sqlite);
+    select_all(query.as_str(), &params, con).await
 }
 
+/// Products buffered between the thread reading them and the stream.
+const STREAM_BUFFER: usize = 256;
 
-pub async fn select_all(query: &str, con: Connection) -> Result<Vec<Product>> {
-    let mut stmt = con.prepare(query)?;
-    let source = stmt.query_map(NO_PARAMS, |row| {
-        Ok(Product {
-            product_id: row.get(0)?,
-            product_number: row.get(1)?,
-            product_name_bold: row.get(2)?,
-            product_name_thin: row.get(3)?,
-            category: row.get(4)?,
-            product_number_short: row.get(5)?,
-            producer_name: row.get(6)?,
-            supplier_name: row.get(7)?,
-            is_kosher: row.get(8)?,
-            bottle_text_short: row.get(9)?,
-            restricted_parcel_quantity: row.get(10)?,
-            seal: row.get(11)?,
-            is_organic: row.get(12)?,
-            is_ethical: row.get(13)?,
-            ethical_label: row.get(14)?,
-            is_web_launch: row.get(15)?,
-            sell_start_date: row.get(16)?,
-            is_completely_out_of_stock: row.get(17)?,
-            is_temporary_out_of_stock: row.get(18)?,
-            alcohol_percentage: row.get(19)?,
-            volume: row.get(20)?,
-            price: row.get(21)?,
-            country: row.get(22)?,
-            origin_level1: row.get(23)?,
-            origin_level2: row.get(24)?,
-            vintage: row.get(25)?,
-            sub_category: row.get(26)?,
-            a_type: row.get(27)?,
-            style: row.get(28)?,
-            assortment_text: row.get(29)?,
-            beverage_description_short: row.get(30)?,
-            usage_text: row.get(31)?,
-            taste: row.get(32)?,
-            assortment: row.get(33)?,
-            is_manufacturing_country: row.get(34)?,
-            recycle_fee: row.get(35)?,
-            is_regional_retricted: row.get(36)?,
-            is_in_store_search_assortment: row.get(37)?,
-            is_news: row.get(38)?,
-            apk: row.get(39)?,
-            apk_recycling: row.get(40)?,
-            link: row.get(41)?,
-        })
+/// Top products matching `opts`, read on a thread of their own that waits while the buffer is full
+/// and stops once the stream is dropped.
+pub fn stream_products(opts: ProductOpts, con: Connection) -> Result<ProductStream> {
+    let (query, params) = query_utils::QueryBuilder::build_with_sites(opts, Vec::new(), Vec::new(), Dialect::Sqlite);
+    let (mut sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    std::thread::Builder::new()
+        .name(String::from("sqlite-stream"))
+        .spawn(move || {
+            let mut send = |product: Result<Product>| futures::executor::block_on(sender.send(product)).is_ok();
+            let mut stmt = match con.prepare(&query) {
+                Ok(stmt) => stmt,
+                Err(e) => {
+                    send(Err(e.into()));
+                    return;
+                }
+            };
+            let rows = match stmt.query_map(&params, product_from_row) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    send(Err(e.into()));
+                    return;
+                }
+            };
+            for row in rows {
+                if !send(row.map_err(|e| e.into())) {
+                    break;
+                }
+            }
+        })?;
+    Ok(Box::pin(receiver))
+}
+
+/// Top products matching `opts` as JSON objects holding only `fields`, in that order.
+pub async fn select_product_fields(opts: ProductOpts, fields: &[&ProductField], con: Connection) -> Result<Vec<Value>> {
+    let (query, params) = query_utils::QueryBuilder::build_projected(opts, fields, Dialect::Sqlite);
+    let mut stmt = con.prepare(&query)?;
+    let source = stmt.query_map(&params, |row| {
+        let mut object = Map::with_capacity(fields.len());
+        for (i, field) in fields.iter().enumerate() {
+            // Only the columns of `Option` fields hold NULL, they serialize as null too
+            let value = match field.kind {
+                FieldKind::Text => Value::from(row.get::<_, Option<String>>(i)?),
+                FieldKind::Bool => Value::from(row.get::<_, bool>(i)?),
+                FieldKind::Int => Value::from(row.get::<_, Option<i64>>(i)?),
+                FieldKind::Real => Value::from(row.get::<_, f64>(i)?),
+            };
+            object.insert(field.name.to_string(), value);
+        }
+        Ok(Value::Object(object))
     })?;
     let mut unpacked = Vec::new();
     for prod in source {
@@ -214,14 +282,82 @@ pub async fn select_all(query: &str, con: Connection) -> Result<Vec<Product>> {
     Ok(unpacked)
 }
 
-pub async fn insert_sites(sites: &Vec<Site>, mut con: Connection) -> Result<()> {
+pub async fn select_all(query: &str, params: &[Param], con: Connection) -> Result<Vec<Product>> {
+    let mut stmt = con.prepare(query)?;
+    let source = stmt.query_map(params, product_from_row)?;
+    let mut unpacked = Vec::new();
+    for prod in source {
+        unpacked.push(prod?);
+    }
+    Ok(unpacked)
+}
+
+pub async fn select_product(lookup: &ProductLookup, con: Connection) -> Result<Option<Product>> {
+    let query = format!("SELECT {} FROM products p WHERE p.{} = ?1", query_utils::product_columns(), lookup.column());
+    Ok(con.query_row(&query, params![lookup.value()], product_from_row).optional()?)
+}
+
+/// Columns selected by `query_utils::product_columns`.
+fn product_from_row(row: &Row) -> rusqlite::Result<Product> {
+    Ok(Product {
+        product_id: row.get(0)?,
+        product_number: row.get(1)?,
+        product_name_bold: row.get(2)?,
+        product_name_thin: row.get(3)?,
+        category: row.get(4)?,
+        product_number_short: row.get(5)?,
+        producer_name: row.get(6)?,
+        supplier_name: row.get(7)?,
+        is_kosher: row.get(8)?,
+        bottle_text_short: row.get(9)?,
+        restricted_parcel_quantity: row.get(10)?,
+        seal: row.get(11)?,
+        is_organic: row.get(12)?,
+        is_ethical: row.get(13)?,
+        ethical_label: row.get(14)?,
+        is_web_launch: row.get(15)?,
+        sell_start_date: row.get::<_, Option<String>>(16)?.as_deref().and_then(parse_datetime),
+        is_completely_out_of_stock: row.get(17)?,
+        is_temporary_out_of_stock: row.get(18)?,
+        alcohol_percentage: row.get(19)?,
+        volume: row.get(20)?,
+        price: row.get(21)?,
+        country: row.get(22)?,
+        origin_level1: row.get(23)?,
+        origin_level2: row.get(24)?,
+        vintage: row.get(25)?,
+        sub_category: row.get(26)?,
+        a_type: row.get(27)?,
+        style: row.get(28)?,
+        assortment_text: row.get(29)?,
+        beverage_description_short: row.get(30)?,
+        usage_text: row.get(31)?,
+        taste: row.get(32)?,
+        assortment: row.get(33)?,
+        is_manufacturing_country: row.get(34)?,
+        recycle_fee: row.get(35)?,
+        is_regional_retricted: row.get(36)?,
+        is_in_store_search_assortment: row.get(37)?,
+        is_news: row.get(38)?,
+        apk: row.get(39)?,
+        apk_recycling: row.get(40)?,
+        link: row.get(41)?,
+        apk_rank: row.get(42)?,
+        apk_percentile: row.get(43)?,
+        category_rank: row.get(44)?,
+        category_percentile: row.get(45)?,
+        sub_category_rank: row.get(46)?,
+        sub_category_percentile: row.get(47)?,
+        volume_bucket: row.get(48)?,
+        volume_bucket_rank: row.get(49)?,
+        volume_bucket_percentile: row.get(50)?,
+    })
+}
+
+fn insert_sites(sites: &[Site], con: &Connection) -> Result<()> {
     let start = SystemTime::now();
-    info!("Starting transaction to insert {} sites", sites.len());
-    let transaction = con.transaction()?;
-    transaction.execute_batch("DELETE FROM sites;")?;
-    for site in sites {
-        transaction.execute(
-            "
+    {
+        let mut site_stmt = con.prepare_cached("
               INSERT INTO sites (
               site_id,
               is_tasting_store,
@@ -239,14 +375,16 @@ pub async fn insert_sites(sites: &Vec<Site>, mut con: Connection) -> Result<()>
               email,
               services,
               depot,
-              name)
+              name,
+              lat,
+              long)
         VALUES (
               ?1,
               ?2,
               ?3,
               ?4,
               ?5,
-              ?5,
+              ?6,
               ?7,
               ?8,
               ?9,
@@ -257,39 +395,55 @@ pub async fn insert_sites(sites: &Vec<Site>, mut con: Connection) -> Result<()>
               ?14,
               ?15,
               ?16,
-              ?17
-        )", params![
-           site.site_id.as_str(),
-           site.is_tasting_store,
-           site.alias.as_str(),
-           site.address.as_str(),
-           site.display_name.as_str(),
-           site.postal_code.as_str(),
-           site.city.as_str(),
-           site.county.as_str(),
-           site.country.as_str(),
-           site.is_store,
-           site.is_agent,
-           site.is_active_for_agent_order,
-           site.phone.as_str(),
-           site.email.as_str(),
-           site.services.as_str(),
-           site.depot.as_str(),
-           site.name.as_str(),
-        ])?;
+              ?17,
+              ?18,
+              ?19
+        )")?;
+        let mut opening_stmt = con.prepare_cached("
+                INSERT OR REPLACE INTO opening_hours (site_key, date, is_open, reason, open_from, open_to)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        for site in sites {
+            site_stmt.execute(params![
+                site.site_id.as_str(),
+                site.is_tasting_store,
+                site.alias.as_deref(),
+                site.address.as_str(),
+                site.display_name.as_deref(),
+                site.postal_code.as_str(),
+                site.city.as_str(),
+                site.county.as_str(),
+                site.country.as_str(),
+                site.is_store,
+                site.is_agent,
+                site.is_active_for_agent_order,
+                site.phone.as_deref(),
+                site.email.as_deref(),
+                site.services.as_deref(),
+                site.depot.as_deref(),
+                site.name,
+                site.position.lat,
+                site.position.long,
+            ])?;
+            for opening in &site.opening_hours {
+                opening_stmt.execute(params![
+                    site.site_id.as_str(),
+                    opening.date.format(DATE_FORMAT).to_string(),
+                    opening.is_open,
+                    opening.reason.as_str(),
+                    opening.open_from.map(|t| t.format(TIME_FORMAT).to_string()),
+                    opening.open_to.map(|t| t.format(TIME_FORMAT).to_string()),
+                ])?;
+            }
+        }
     }
-    transaction.commit().map_err(|e| -> rusqlite::Error {
-        warn!("{}", e);
-        e
-    })?;
-    info!("Committed transaction of {} sites in {} seconds", sites.len(),
-          SystemTime::now().duration_since(start)?.as_secs());
+    info!("Inserted {} sites in {} millis", sites.len(), SystemTime::now().duration_since(start)?.as_millis());
     Ok(())
 }
 
-pub async fn select_all_sites(con: Connection) -> Result<Vec<Site>> {
-    let mut stmt = con.prepare("SELECT * FROM sites WHERE is_store=true")?;
-    let source = stmt.query_map(NO_PARAMS, |row| {
+pub async fn select_sites(opts: &SiteOpts, con: Connection) -> Result<Vec<Site>> {
+    let (query, params) = query_utils::SiteQueryBuilder::build(opts);
+    let mut stmt = con.prepare(&query)?;
+    let source = stmt.query_map(params, |row| {
         Ok(Site {
             site_id: row.get(0)?,
             is_tasting_store: row.get(1)?,
@@ -309,44 +463,413 @@ pub async fn select_all_sites(con: Connection) -> Result<Vec<Site>> {
             depot: row.get(15)?,
             name: row.get(16)?,
             opening_hours: Vec::new(),
-            position: Position {lat: 0.0, long: 0.0}
+            position: Position {
+                lat: row.get::<_, Option<f64>>(17)?.unwrap_or(0.0),
+                long: row.get::<_, Option<f64>>(18)?.unwrap_or(0.0),
+            }
         })
     })?;
+    let mut opening_hours = select_opening_hours(&con)?;
     let mut unpacked = Vec::new();
     for site in source {
-        unpacked.push(site?);
+        let mut site = site?;
+        site.opening_hours = opening_hours.remove(&site.site_id).unwrap_or_default();
+        unpacked.push(site);
     }
     Ok(unpacked)
 }
 
-pub async fn insert_junctions(junctions: &Vec<MinimalSite>, mut con: Connection) -> Result<()> {
+/// Rows stored before dates were typed hold the full upstream datetime, only the date part is read.
+fn select_opening_hours(con: &Connection) -> Result<HashMap<SiteId, Vec<OpeningTime>>> {
+    let mut stmt = con.prepare("
+        SELECT site_key, is_open, reason, date, open_from, open_to FROM opening_hours ORDER BY site_key, date")?;
+    let source = stmt.query_map(NO_PARAMS, |row| {
+        let time = |i: usize| -> rusqlite::Result<_> {
+            Ok(row.get::<_, Option<String>>(i)?.as_deref().and_then(parse_time))
+        };
+        let site_id: SiteId = row.get(0)?;
+        let is_open = row.get(1)?;
+        let reason = row.get::<_, Option<String>>(2)?.unwrap_or_default();
+        let (open_from, open_to) = (time(4)?, time(5)?);
+        Ok(parse_date(&row.get::<_, String>(3)?)
+            .map(|date| (site_id, OpeningTime { is_open, reason, date, open_from, open_to })))
+    })?;
+    let mut by_site: HashMap<SiteId, Vec<OpeningTime>> = HashMap::new();
+    for opening in source {
+        if let Some((site_id, opening)) = opening? {
+            by_site.entry(site_id).or_default().push(opening);
+        }
+    }
+    Ok(by_site)
+}
+
+pub async fn site_exists(site_id: &SiteId, con: Connection) -> Result<bool> {
+    let found = con.query_row("SELECT 1 FROM sites WHERE site_id = ?1", params![site_id], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+pub async fn select_site_stats(site_id: &SiteId, con: Connection) -> Result<SiteStats> {
+    let mut stmt = con.prepare("
+        SELECT p.apk FROM products p
+        JOIN sites_products sp ON sp.product_key = p.product_id
+        WHERE sp.site_key = ?1
+        ORDER BY p.apk")?;
+    let source = stmt.query_map(params![site_id], |row| row.get::<_, f64>(0))?;
+    let mut apks = Vec::new();
+    for apk in source {
+        apks.push(apk?);
+    }
+    // SQLite returns the bare columns from the row holding the MAX
+    let mut stmt = con.prepare("
+        SELECT p.category, p.product_id, p.product_name_bold, MAX(p.apk) FROM products p
+        JOIN sites_products sp ON sp.product_key = p.product_id
+        WHERE sp.site_key = ?1
+        GROUP BY p.category
+        ORDER BY p.category")?;
+    let source = stmt.query_map(params![site_id], |row| {
+        Ok(CategoryBest {
+            category: row.get(0)?,
+            product_id: row.get(1)?,
+            product_name_bold: row.get(2)?,
+            apk: row.get(3)?,
+        })
+    })?;
+    let mut best_per_category = Vec::new();
+    for best in source {
+        best_per_category.push(best?);
+    }
+    Ok(SiteStats {
+        site_id: site_id.clone(),
+        assortment_size: apks.len() as i64,
+        median_apk: median(&apks),
+        best_per_category,
+    })
+}
+
+pub async fn select_snapshot_version(con: Connection) -> Result<i64> {
+    Ok(con.query_row("SELECT version FROM snapshot WHERE id = 0", NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Junction rows are checked against the loaded products and sites, rows pointing at either that doesn't exist
+/// are counted and skipped rather than failing the whole load.
+/// `previous` holds the `last_seen` of listings that are out of stock now, see `refresh::out_of_stock`.
+fn insert_junctions(junctions: &[MinimalSite], previous: &HashMap<(String, String), NaiveDateTime>,
+                    con: &Connection) -> Result<LoadStats> {
     let start = SystemTime::now();
-    info!("Starting transaction to insert {} sites_products", junctions.len());
-    let transaction = con.transaction()?;
-    transaction.execute_batch("DELETE FROM sites_products;")?;
-    for site in junctions {
-        for prod in &site.products {
-            let res = transaction.execute("
-                INSERT INTO sites_products (product_key, site_key)
-                VALUES (
-                ?1,
-                ?2
-                )", params![
-                    prod.product_id,
-                    site.site_id
-                    ]
-            );
-            if res.is_err() {
-                debug!("Caught error inserting minimal_site={:?} {:?}", site, res.unwrap_err())
+    let products = select_keys("SELECT product_id FROM products", con)?;
+    let sites = select_keys("SELECT site_id FROM sites", con)?;
+    let mut stats = LoadStats::default();
+    {
+        let mut stmt = con.prepare_cached("
+                INSERT OR IGNORE INTO sites_products (product_key, site_key, stock, shelf, last_seen)
+                VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        // Inserting in primary key order appends to the b-tree instead of splitting pages all over it
+        let seen_at = Utc::now().naive_utc();
+        for row in junction_rows(junctions, &products, &sites, seen_at, previous, &mut stats) {
+            let last_seen = row.last_seen.map(|t| t.format(DATETIME_FORMAT).to_string());
+            match stmt.execute(params![row.product_id, row.site_id, row.stock, row.shelf, last_seen]) {
+                Ok(0) => stats.duplicate += 1,
+                Ok(_) => stats.inserted += 1,
+                Err(e) => {
+                    debug!("Caught error inserting product_id={} site_id={}: {}", row.product_id, row.site_id, e);
+                    stats.failed += 1;
+                }
             }
         }
+    }
+    info!("Inserted junctions for {} sites in {} millis", junctions.len(),
+          SystemTime::now().duration_since(start)?.as_millis());
+    Ok(stats)
+}
 
+/// `last_seen` of the currently stored listings among `keys`, `(product_id, site_id)` pairs.
+/// The keys go through a temp table so it's a single join however many listings are out of stock.
+fn select_last_seen(keys: &[(&str, &str)], con: &Connection) -> Result<HashMap<(String, String), NaiveDateTime>> {
+    con.execute_batch("
+        CREATE TEMP TABLE IF NOT EXISTS out_of_stock (
+                product_key VARCHAR not null,
+                site_key VARCHAR not null,
+                PRIMARY KEY (product_key, site_key)
+        ) WITHOUT ROWID;
+        DELETE FROM temp.out_of_stock;")?;
+    {
+        let mut insert = con.prepare_cached("INSERT OR IGNORE INTO temp.out_of_stock (product_key, site_key) VALUES (?1, ?2)")?;
+        for (product_id, site_id) in keys {
+            insert.execute(params![product_id, site_id])?;
+        }
     }
-    transaction.commit().map_err(|e| -> rusqlite::Error {
-        warn!("{}", e);
-        e
-    })?;
-    info!("Committed transaction of {} products in {} seconds", junctions.len(),
-          SystemTime::now().duration_since(start)?.as_secs());
+    let mut stmt = con.prepare("
+        SELECT sp.product_key, sp.site_key, sp.last_seen FROM sites_products sp
+        JOIN temp.out_of_stock k ON sp.product_key = k.product_key AND sp.site_key = k.site_key
+        WHERE sp.last_seen IS NOT NULL")?;
+    let source = stmt.query_map(NO_PARAMS, |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?,
+                                                     row.get::<_, String>(2)?)))?;
+    let mut seen = HashMap::new();
+    for row in source {
+        let (product_id, site_id, last_seen) = row?;
+        if let Some(last_seen) = parse_datetime(&last_seen) {
+            seen.insert((product_id, site_id), last_seen);
+        }
+    }
+    Ok(seen)
+}
+
+/// Listings in one store, optionally only those with at least `min_stock` units.
+pub async fn select_stock(site_id: &SiteId, min_stock: i64, con: Connection) -> Result<Vec<StockLevel>> {
+    let mut stmt = con.prepare("
+        SELECT site_key, product_key, stock, shelf, last_seen FROM sites_products
+        WHERE site_key = ?1 AND (?2 <= 0 OR stock >= ?2)
+        ORDER BY product_key")?;
+    let source = stmt.query_map(params![site_id, min_stock], stock_from_row)?;
+    let mut stock = Vec::new();
+    for level in source {
+        stock.push(level?);
+    }
+    Ok(stock)
+}
+
+/// Listings of one product in every store carrying it.
+pub async fn select_listings(product_id: &ProductId, con: Connection) -> Result<Vec<StockLevel>> {
+    let mut stmt = con.prepare("
+        SELECT site_key, product_key, stock, shelf, last_seen FROM sites_products
+        WHERE product_key = ?1
+        ORDER BY site_key")?;
+    let source = stmt.query_map(params![product_id], stock_from_row)?;
+    let mut listings = Vec::new();
+    for level in source {
+        listings.push(level?);
+    }
+    Ok(listings)
+}
+
+fn stock_from_row(row: &Row) -> rusqlite::Result<StockLevel> {
+    Ok(StockLevel {
+        site_id: row.get(0)?,
+        product_id: row.get(1)?,
+        stock: row.get(2)?,
+        shelf: row.get(3)?,
+        last_seen: row.get::<_, Option<String>>(4)?.as_deref().and_then(parse_datetime),
+    })
+}
+
+fn select_keys(query: &str, con: &Connection) -> Result<HashSet<String>> {
+    let mut stmt = con.prepare(query)?;
+    let source = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))?;
+    let mut keys = HashSet::new();
+    for key in source {
+        keys.insert(key?);
+    }
+    Ok(keys)
+}
+
+/// Loads are one large transaction every few hours, trade some durability for speed on this connection.
+fn tune_for_load(con: &Connection) -> Result<()> {
+    con.execute_batch("
+        PRAGMA synchronous = NORMAL;
+        PRAGMA temp_store = MEMORY;
+        PRAGMA cache_size = -65536;
+    ")?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::storage::test_utils::{temp_db, load};
+    use crate::domain::models::fixtures::{product, site, minimal_site, catalogue};
+    use chrono::NaiveDate;
+
+    fn opts(site_id: &str) -> ProductOpts {
+        ProductOpts {
+            count: 10,
+            include_recycling: false,
+            exists_in_store: true,
+            max_volume: 10000.0,
+            site_id: site_id.into(),
+            category: String::new(),
+            ..ProductOpts::default()
+        }
+    }
+
+    async fn seeded(name: &str) -> String {
+        let path = temp_db(name).await;
+        let products = vec![
+            product("1", "öl", 20.0, 500.0, 5.0),
+            product("2", "öl", 15.0, 330.0, 5.0),
+            product("3", "vin", 100.0, 750.0, 13.0),
+            product("4", "sprit", 300.0, 700.0, 40.0),
+            // Order-only, not in any store's assortment
+            product("5", "vin", 90.0, 750.0, 14.0),
+        ];
+        let sites = vec![site("0102", "Centrum"), site("0104", "Söder")];
+        let mapping = vec![minimal_site("0102", &["1", "2", "3"]), minimal_site("0104", &["3", "4"])];
+        load(&path, &products, &sites, &mapping).await;
+        path
+    }
+
+    #[tokio::test]
+    async fn test_site_stats() {
+        let path = seeded("test_site_stats.db").await;
+        let stats = select_site_stats(&"0102".into(), Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(3, stats.assortment_size);
+        assert_eq!(product("2", "öl", 15.0, 330.0, 5.0).apk, stats.median_apk);
+        let categories: Vec<(&str, &str)> = stats.best_per_category.iter()
+            .map(|b| (b.category.as_str(), b.product_id.as_str()))
+            .collect();
+        assert_eq!(vec![("vin", "3"), ("öl", "1")], categories);
+        assert!(site_exists(&"0104".into(), Connection::open(&path).unwrap()).await.unwrap());
+        assert!(!site_exists(&"9999".into(), Connection::open(&path).unwrap()).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_products_outside_assortment() {
+        let path = seeded("test_products_outside_assortment.db").await;
+        let ids = |products: Vec<Product>| products.into_iter().map(|p| p.product_id.to_string()).collect::<Vec<String>>();
+        let everything = select_products_with_sites(ProductOpts { exists_in_store: false, ..opts("") }, Vec::new(),
+                                                    Vec::new(), Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(5, everything.len());
+        assert!(ids(everything).contains(&String::from("5")));
+        let in_stores = select_products_with_sites(opts(""), Vec::new(), Vec::new(), Connection::open(&path).unwrap())
+            .await.unwrap();
+        assert_eq!(4, in_stores.len());
+        assert!(!ids(in_stores).contains(&String::from("5")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_projected_products() {
+        let path = seeded("test_projected_products.db").await;
+        let fields = crate::domain::models::projection::projection("", "compact").unwrap().unwrap();
+        let full = select_products_with_sites(opts(""), Vec::new(), Vec::new(), Connection::open(&path).unwrap())
+            .await.unwrap();
+        let compact = select_product_fields(opts(""), &fields, Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(full.len(), compact.len());
+        for (product, projected) in full.iter().zip(compact) {
+            let expected: Map<String, Value> = fields.iter()
+                .map(|f| (f.name.to_string(), serde_json::to_value(product).unwrap()[f.name].clone()))
+                .collect();
+            assert_eq!(Value::Object(expected), projected);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_select_sites_includes_agents() {
+        let path = temp_db("test_select_sites.db").await;
+        let mut agent = site("A001", "");
+        agent.is_store = false;
+        agent.is_agent = true;
+        let mut store = site("0102", "Centrum");
+        store.postal_code = String::from("113 50");
+        store.display_name = Some(String::from("Odengatan 1"));
+        store.opening_hours = vec![OpeningTime {
+            is_open: false,
+            reason: String::from("Juldagen"),
+            date: NaiveDate::from_ymd(2020, 12, 25),
+            open_from: None,
+            open_to: None,
+        }];
+        load(&path, &[], &[store, agent], &[]).await;
+        let all = select_sites(&SiteOpts::default(), Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(2, all.len());
+        let by_postal = select_sites(&SiteOpts { postal_code: String::from("1135"), ..SiteOpts::default() },
+                                     Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(1, by_postal.len());
+        assert_eq!("113 50", by_postal[0].postal_code);
+        assert_eq!("Juldagen", by_postal[0].opening_hours[0].reason);
+        let agents = select_sites(&SiteOpts { is_agent: Some(true), ..SiteOpts::default() },
+                                  Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!("A001", agents[0].site_id);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_sites() {
+        let path = seeded("test_compare_sites.db").await;
+        let ids = |products: Vec<Product>| products.into_iter().map(|p| p.product_id.to_string()).collect::<Vec<String>>();
+        let only_a = select_products_with_sites(opts("0102"), Vec::new(), vec!["0104".into()],
+                                                Connection::open(&path).unwrap()).await.unwrap();
+        let only_b = select_products_with_sites(opts("0104"), Vec::new(), vec!["0102".into()],
+                                                Connection::open(&path).unwrap()).await.unwrap();
+        let both = select_products_with_sites(opts("0102"), vec!["0104".into()], Vec::new(),
+                                              Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(vec!["1", "2"], ids(only_a));
+        assert_eq!(vec!["4"], ids(only_b));
+        assert_eq!(vec!["3"], ids(both));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn query_plan(path: &str, (query, params): (String, Vec<Param>)) -> Vec<String> {
+        let con = Connection::open(path).unwrap();
+        let mut stmt = con.prepare(&format!("EXPLAIN QUERY PLAN {}", query)).unwrap();
+        let rows = stmt.query_map(&params, |row| row.get::<_, String>(3)).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    /// A plain `SCAN <table>` visits every row, scanning through an index in sort order is fine since it stops at the limit.
+    fn assert_no_full_scan(plan: &[String]) {
+        for step in plan {
+            let full_scan = step.starts_with("SCAN") && !step.contains("USING INDEX") && !step.contains("USING COVERING INDEX");
+            assert!(!full_scan, "Full scan in plan {:?}", plan);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_top_queries_use_indexes() {
+        let path = seeded("test_top_queries_use_indexes.db").await;
+        let global = vec![
+            ProductOpts { exists_in_store: false, ..opts("") },
+            opts(""),
+            ProductOpts { include_recycling: true, ..opts("") },
+            ProductOpts { category: String::from("öl"), ..opts("") },
+            ProductOpts { category: String::from("öl"), include_recycling: true, ..opts("") },
+        ];
+        for shape in global {
+            let plan = query_plan(&path, query_utils::QueryBuilder::build_with_sites(shape, Vec::new(), Vec::new(),
+                                                                                   Dialect::Sqlite));
+            assert_no_full_scan(&plan);
+            // Read in apk order straight from the index, no sort before the limit applies
+            assert!(!plan.iter().any(|s| s.contains("TEMP B-TREE FOR ORDER BY")), "Sort in plan {:?}", plan);
+        }
+        // Store scoped queries start from the store's assortment and sort that, which is a few thousand rows at most
+        let store_scoped = vec![
+            opts("0102"),
+            ProductOpts { category: String::from("öl"), ..opts("0102") },
+            ProductOpts { include_recycling: true, ..opts("0102") },
+        ];
+        for shape in store_scoped {
+            let plan = query_plan(&path, query_utils::QueryBuilder::build_with_sites(shape, Vec::new(), Vec::new(),
+                                                                                   Dialect::Sqlite));
+            assert_no_full_scan(&plan);
+            assert!(plan.iter().any(|s| s.contains("idx_sites_products_site (site_key=?)")), "Site lookup in plan {:?}", plan);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_junction_rejects_are_counted() {
+        let path = temp_db("test_junction_rejects.db").await;
+        let mapping = vec![minimal_site("0102", &["1", "1", "2"]), minimal_site("9999", &["1"])];
+        let stats = load_snapshot(&[product("1", "öl", 20.0, 500.0, 5.0)], &[site("0102", "Centrum")], &mapping,
+                                  Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(LoadStats { inserted: 1, unknown_product: 1, unknown_site: 1, duplicate: 1, failed: 0 }, stats);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Load benchmark on a realistically sized snapshot, run with
+    /// `cargo test --release bench_load_snapshot -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_load_snapshot() {
+        let path = temp_db("bench_load_snapshot.db").await;
+        let (products, sites, mapping) = catalogue(20_000, 400, 2_000);
+        for round in 0..3 {
+            let start = std::time::Instant::now();
+            let stats = load_snapshot(&products, &sites, &mapping, Connection::open(&path).unwrap()).await.unwrap();
+            println!("round {}: total={:?} {}", round, start.elapsed(), stats);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
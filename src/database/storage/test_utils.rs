@@ -0,0 +1,21 @@
+use rusqlite::Connection;
+use crate::domain::models::product::{Product, MinimalSite};
+use crate::domain::models::site::Site;
+use super::init::{init_product_db, init_site_db, init_junction_db, init_watchlist_db, init_snapshot_db};
+use super::storage::load_snapshot;
+
+/// Creates a fresh database file in the temp dir with every table initialized, returns its path.
+pub async fn temp_db(name: &str) -> String {
+    let path = std::env::temp_dir().join(name).to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    init_product_db(Connection::open(&path).unwrap()).await.unwrap();
+    init_site_db(Connection::open(&path).unwrap()).await.unwrap();
+    init_junction_db(Connection::open(&path).unwrap()).await.unwrap();
+    init_watchlist_db(Connection::open(&path).unwrap()).await.unwrap();
+    init_snapshot_db(Connection::open(&path).unwrap()).await.unwrap();
+    path
+}
+
+pub async fn load(path: &str, products: &[Product], sites: &[Site], mapping: &[MinimalSite]) {
+    load_snapshot(products, sites, mapping, Connection::open(path).unwrap()).await.unwrap();
+}
@@ -0,0 +1,164 @@
+use rusqlite::{Connection, NO_PARAMS};
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::watchlist::{Watchlist, WatchItem, NewWatchlist, NotifierTarget, NotifierKind, WatchState};
+use crate::domain::result::Result;
+
+pub async fn insert_watchlist(watchlist: &NewWatchlist, mut con: Connection) -> Result<i64> {
+    let transaction = con.transaction()?;
+    transaction.execute("
+        INSERT INTO watchlists (name, site_id, notifier_kind, notifier_target)
+        VALUES (?1, ?2, ?3, ?4)", params![
+            watchlist.name.as_str(),
+            watchlist.site_id.as_str(),
+            watchlist.notifier.kind.as_str(),
+            watchlist.notifier.target.as_str(),
+        ])?;
+    let id = transaction.last_insert_rowid();
+    // Seed from the current snapshot so the next refresh only reports actual changes
+    for product_id in &watchlist.products {
+        transaction.execute("
+            INSERT OR IGNORE INTO watchlist_items (watchlist_key, product_id, last_price, last_in_store)
+            SELECT ?1, ?2,
+                (SELECT price FROM products WHERE product_id = ?2),
+                EXISTS(
+                    SELECT 1 FROM sites_products sp WHERE sp.product_key = ?2
+                    AND (?3 = '' OR sp.site_key = ?3)
+                )", params![id, product_id.as_str(), watchlist.site_id.as_str()])?;
+    }
+    transaction.commit()?;
+    Ok(id)
+}
+
+pub async fn select_watchlist(watchlist_id: i64, con: Connection) -> Result<Option<Watchlist>> {
+    Ok(select_watchlists(&[watchlist_id], con).await?.pop())
+}
+
+/// The watchlists among `watchlist_ids` with their items in one query, by id. Ids without a watchlist are skipped.
+pub async fn select_watchlists(watchlist_ids: &[i64], con: Connection) -> Result<Vec<Watchlist>> {
+    let mut stmt = con.prepare("
+        SELECT w.watchlist_id, w.name, w.site_id, w.notifier_kind, w.notifier_target,
+               wi.product_id, wi.last_price, wi.last_in_store
+        FROM watchlists w
+        LEFT JOIN watchlist_items wi ON wi.watchlist_key = w.watchlist_id
+        WHERE w.watchlist_id IN (SELECT value FROM json_each(?1))
+        ORDER BY w.watchlist_id, wi.product_id")?;
+    let source = stmt.query_map(params![serde_json::to_string(watchlist_ids)?], |row| {
+        let item = match row.get::<_, Option<ProductId>>(5)? {
+            Some(product_id) => Some(WatchItem {
+                product_id,
+                last_price: row.get(6)?,
+                last_in_store: row.get(7)?,
+            }),
+            None => None,
+        };
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, SiteId>(2)?,
+            row.get::<_, String>(3)?, row.get::<_, String>(4)?, item))
+    })?;
+    let mut watchlists: Vec<Watchlist> = Vec::new();
+    for row in source {
+        let (watchlist_id, name, site_id, kind, target, item) = row?;
+        if watchlists.last().map(|w| w.watchlist_id) != Some(watchlist_id) {
+            watchlists.push(Watchlist {
+                watchlist_id,
+                name,
+                site_id,
+                notifier: NotifierTarget { kind: NotifierKind::parse(&kind)?, target },
+                items: Vec::new(),
+            });
+        }
+        if let (Some(watchlist), Some(item)) = (watchlists.last_mut(), item) {
+            watchlist.items.push(item);
+        }
+    }
+    Ok(watchlists)
+}
+
+pub async fn delete_watchlist(watchlist_id: i64, mut con: Connection) -> Result<bool> {
+    let transaction = con.transaction()?;
+    transaction.execute("DELETE FROM watchlist_items WHERE watchlist_key = ?1", params![watchlist_id])?;
+    let deleted = transaction.execute("DELETE FROM watchlists WHERE watchlist_id = ?1", params![watchlist_id])?;
+    transaction.commit()?;
+    Ok(deleted > 0)
+}
+
+/// Joins every watched product with the current snapshot, scoped to the watchlist's store if it has one.
+pub async fn select_watch_states(con: Connection) -> Result<Vec<WatchState>> {
+    let mut stmt = con.prepare("
+        SELECT wi.watchlist_key, wi.product_id, wi.last_price, wi.last_in_store, p.price,
+               p.product_name_bold, p.product_name_thin, p.link,
+               EXISTS(
+                    SELECT 1 FROM sites_products sp WHERE sp.product_key = wi.product_id
+                    AND (w.site_id = '' OR sp.site_key = w.site_id)
+               )
+        FROM watchlist_items wi
+        JOIN watchlists w ON w.watchlist_id = wi.watchlist_key
+        LEFT JOIN products p ON p.product_id = wi.product_id")?;
+    let source = stmt.query_map(NO_PARAMS, |row| {
+        let bold: Option<String> = row.get(5)?;
+        let thin: Option<String> = row.get(6)?;
+        let name = match (bold, thin) {
+            (Some(b), Some(t)) if !t.is_empty() => format!("{} {}", b, t),
+            (Some(b), _) => b,
+            _ => String::new(),
+        };
+        Ok(WatchState {
+            watchlist_id: row.get(0)?,
+            product_id: row.get(1)?,
+            last_price: row.get(2)?,
+            last_in_store: row.get(3)?,
+            price: row.get(4)?,
+            name,
+            link: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+            in_store: row.get(8)?,
+        })
+    })?;
+    let mut unpacked = Vec::new();
+    for state in source {
+        unpacked.push(state?);
+    }
+    Ok(unpacked)
+}
+
+/// A product that left the catalogue keeps its last price, a drop is still reported when it comes back.
+pub async fn update_watch_states(states: &[WatchState], mut con: Connection) -> Result<()> {
+    let transaction = con.transaction()?;
+    for state in states {
+        transaction.execute("
+            UPDATE watchlist_items SET last_price = COALESCE(?1, last_price), last_in_store = ?2
+            WHERE watchlist_key = ?3 AND product_id = ?4", params![
+                state.price,
+                state.in_store,
+                state.watchlist_id,
+                state.product_id.as_str(),
+            ])?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::storage::test_utils::temp_db;
+
+    #[tokio::test]
+    async fn test_watchlist_roundtrip() {
+        let path = temp_db("test_watchlist_roundtrip.db").await;
+        let new = NewWatchlist {
+            name: String::from("beers"),
+            site_id: "0102".into(),
+            notifier: NotifierTarget { kind: NotifierKind::Stdout, target: String::new() },
+            products: vec!["1001".into(), "1002".into()],
+        };
+        let id = insert_watchlist(&new, Connection::open(&path).unwrap()).await.unwrap();
+        let found = select_watchlist(id, Connection::open(&path).unwrap()).await.unwrap().unwrap();
+        assert_eq!("beers", found.name);
+        assert_eq!(2, found.items.len());
+        let states = select_watch_states(Connection::open(&path).unwrap()).await.unwrap();
+        assert_eq!(2, states.len());
+        assert!(states.iter().all(|s| s.price.is_none() && !s.in_store));
+        assert!(delete_watchlist(id, Connection::open(&path).unwrap()).await.unwrap());
+        assert!(select_watchlist(id, Connection::open(&path).unwrap()).await.unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
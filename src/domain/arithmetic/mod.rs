@@ -1,3 +1,5 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use crate::domain::models::product::Product;
 
 static DENS: f64 = 789 as f64;
@@ -14,4 +16,107 @@ pub fn get_recyc_apk(product: &Product) -> f64 {
         return product.volume * product.alcohol_percentage * DENS / ((product.price + product.recycle_fee) * 1000.0 * 100.0); // volume in ml, percent in absolute
     }
     return 0.0;
-}
\ No newline at end of file
+}
+
+/// Grams of pure alcohol in one bottle.
+pub fn alcohol_grams(product: &Product) -> f64 {
+    product.volume * product.alcohol_percentage * DENS / (1000.0 * 100.0)
+}
+
+/// Upper volume bound in ml and label of each bucket products are ranked within, the last one is open ended.
+pub const VOLUME_BUCKETS: &[(f64, &str)] = &[
+    (330.0, "0-330"),
+    (500.0, "331-500"),
+    (750.0, "501-750"),
+    (1000.0, "751-1000"),
+    (f64::INFINITY, "1001+"),
+];
+
+pub fn volume_bucket(volume: f64) -> &'static str {
+    VOLUME_BUCKETS.iter()
+        .find(|(max, _)| volume <= *max)
+        .map(|(_, label)| *label)
+        .unwrap_or(VOLUME_BUCKETS[VOLUME_BUCKETS.len() - 1].1)
+}
+
+/// Sets every product's ranks and percentiles by apk, overall, within its category, its sub category and its
+/// volume bucket. Categories are compared ignoring case, as they're stored lowercased.
+pub fn rank_products(products: &mut [Product]) {
+    for product in products.iter_mut() {
+        product.volume_bucket = volume_bucket(product.volume).to_string();
+    }
+    for (i, (rank, percentile)) in ranks(products, |_| String::new()).into_iter().enumerate() {
+        products[i].apk_rank = rank;
+        products[i].apk_percentile = percentile;
+    }
+    for (i, (rank, percentile)) in ranks(products, |p| p.category.to_lowercase()).into_iter().enumerate() {
+        products[i].category_rank = rank;
+        products[i].category_percentile = percentile;
+    }
+    for (i, (rank, percentile)) in ranks(products, |p| p.sub_category.as_deref().unwrap_or("").to_lowercase()).into_iter().enumerate() {
+        products[i].sub_category_rank = rank;
+        products[i].sub_category_percentile = percentile;
+    }
+    for (i, (rank, percentile)) in ranks(products, |p| p.volume_bucket.clone()).into_iter().enumerate() {
+        products[i].volume_bucket_rank = rank;
+        products[i].volume_bucket_percentile = percentile;
+    }
+}
+
+/// Rank and percentile of each product within the group `key` puts it in, in the order of `products`.
+fn ranks<F: Fn(&Product) -> String>(products: &[Product], key: F) -> Vec<(i32, f64)> {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, product) in products.iter().enumerate() {
+        groups.entry(key(product)).or_default().push(i);
+    }
+    let mut ranked = vec![(0, 0.0); products.len()];
+    for members in groups.values_mut() {
+        members.sort_by(|a, b| products[*b].apk.partial_cmp(&products[*a].apk).unwrap_or(Ordering::Equal));
+        let total = members.len() as f64;
+        let mut rank = 0;
+        for (position, i) in members.iter().enumerate() {
+            if position == 0 || products[*i].apk != products[members[position - 1]].apk {
+                rank = position as i32 + 1;
+            }
+            ranked[*i] = (rank, 100.0 * rank as f64 / total);
+        }
+    }
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::product;
+
+    #[test]
+    fn test_volume_bucket() {
+        assert_eq!("0-330", volume_bucket(330.0));
+        assert_eq!("331-500", volume_bucket(330.5));
+        assert_eq!("501-750", volume_bucket(750.0));
+        assert_eq!("1001+", volume_bucket(3000.0));
+    }
+
+    #[test]
+    fn test_rank_products() {
+        let mut tied = product("4", "Öl", 20.0, 500.0, 5.0);
+        tied.sub_category = Some(String::from("Ale"));
+        let mut products = vec![
+            product("1", "öl", 20.0, 500.0, 5.0),
+            product("2", "öl", 15.0, 330.0, 5.0),
+            product("3", "vin", 100.0, 750.0, 13.0),
+            tied,
+        ];
+        rank_products(&mut products);
+        let ranked: Vec<(i32, i32, i32, i32)> = products.iter()
+            .map(|p| (p.apk_rank, p.category_rank, p.sub_category_rank, p.volume_bucket_rank))
+            .collect();
+        // 1 and 4 share the highest apk and skip rank 2, 3 is the only wine and 4 the only ale
+        assert_eq!(vec![(1, 1, 1, 1), (3, 3, 2, 1), (4, 1, 3, 1), (1, 1, 1, 1)], ranked);
+        assert_eq!(25.0, products[0].apk_percentile);
+        assert_eq!(100.0, products[2].apk_percentile);
+        assert_eq!(100.0 / 3.0, products[3].category_percentile);
+        assert_eq!(100.0, products[1].category_percentile);
+        assert_eq!(("331-500", "0-330"), (products[0].volume_bucket.as_str(), products[1].volume_bucket.as_str()));
+    }
+}
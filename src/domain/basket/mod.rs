@@ -0,0 +1,410 @@
+//! Baskets maximizing a score within a budget, a bounded knapsack that also limits the number of bottles and
+//! can require bottles of given categories.
+//!
+//! The search is exact. Products a basket could always swap for cheaper bottles worth at least as much, of the
+//! same category, are left out first. The rest are searched branch and bound, best value per krona first,
+//! so the first baskets found are good ones. A search running past `NODE_LIMIT` steps keeps the best basket
+//! found and reports it isn't known to be optimal.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::domain::arithmetic;
+use crate::domain::models::basket::{Basket, BasketItem, BasketOpts};
+use crate::domain::models::ids::ProductId;
+use crate::domain::models::product::Product;
+use crate::domain::result::Result;
+use crate::domain::scoring;
+
+/// Steps a search may take, enough to prove the best basket for budgets and bottle counts people shop with.
+pub const NODE_LIMIT: u64 = 200_000;
+
+const EPSILON: f64 = 1e-9;
+
+/// A product as the search sees it.
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Price and deposit in öre, so sums are exact.
+    pub cost: i64,
+    /// Per bottle.
+    pub value: f64,
+    /// Lowercased.
+    pub category: String,
+    /// Most bottles of it a basket may hold.
+    pub units: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// In öre.
+    pub budget: i64,
+    pub max_bottles: u32,
+    /// Lowercased, at most 64.
+    pub categories: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Solution {
+    /// Index into the items and number of bottles of it, in the order of the items.
+    pub quantities: Vec<(usize, u32)>,
+    pub value: f64,
+    pub optimal: bool,
+}
+
+/// The basket with the highest total value within `limits`, `None` if no basket covers the categories.
+pub fn solve(items: &[Item], limits: &Limits) -> Option<Solution> {
+    let mut candidates: Vec<(usize, u32)> = items.iter().enumerate()
+        .filter(|(_, item)| item.cost > 0 && item.cost <= limits.budget)
+        .map(|(i, item)| (i, item.units.min(limits.max_bottles).min((limits.budget / item.cost) as u32)))
+        .filter(|(_, units)| *units > 0)
+        .collect();
+    candidates = undominated(items, candidates, limits.max_bottles);
+    candidates.sort_by(|(a, _), (b, _)| {
+        let density = |i: usize| items[i].value / items[i].cost as f64;
+        density(*b).partial_cmp(&density(*a)).unwrap_or(Ordering::Equal).then(a.cmp(b))
+    });
+
+    let mut search = Search::new(items, candidates, limits);
+    search.step(0, limits.budget, limits.max_bottles, 0.0, 0);
+    let optimal = search.nodes <= NODE_LIMIT;
+    let best = search.best?;
+    let mut quantities: Vec<(usize, u32)> = search.order.iter().zip(best)
+        .filter(|(_, q)| *q > 0)
+        .map(|((i, _), q)| (*i, q))
+        .collect();
+    quantities.sort_unstable();
+    Some(Solution { quantities, value: search.best_value, optimal })
+}
+
+/// Leaves out every candidate with at least `max_bottles` bottles of other candidates of its category costing
+/// no more and worth no less, a basket holding it can hold one of those instead. Counting only the candidates
+/// kept is enough, whatever dominates a left out one also dominates what it dominates.
+fn undominated(items: &[Item], mut candidates: Vec<(usize, u32)>, max_bottles: u32) -> Vec<(usize, u32)> {
+    candidates.sort_by(|(a, _), (b, _)| {
+        let (a, b) = (&items[*a], &items[*b]);
+        a.category.cmp(&b.category)
+            .then(a.cost.cmp(&b.cost))
+            .then(b.value.partial_cmp(&a.value).unwrap_or(Ordering::Equal))
+    });
+    let mut kept: Vec<(usize, u32)> = Vec::new();
+    let mut category_start = 0;
+    for (i, units) in candidates {
+        if category_start < kept.len() && items[kept[category_start].0].category != items[i].category {
+            category_start = kept.len();
+        }
+        let dominating: u32 = kept[category_start..].iter()
+            .filter(|(k, _)| items[*k].value >= items[i].value)
+            .map(|(_, units)| units)
+            .sum();
+        if dominating < max_bottles {
+            kept.push((i, units));
+        }
+    }
+    kept
+}
+
+struct Search<'a> {
+    items: &'a [Item],
+    /// Candidates and their units, best value per krona first.
+    order: Vec<(usize, u32)>,
+    /// Bit of the required category each candidate is in, 0 if none.
+    masks: Vec<u64>,
+    all: u64,
+    /// Cheapest candidate of each required category from each position on, `i64::MAX` if there's none.
+    cheapest: Vec<Vec<i64>>,
+    /// Highest value of a bottle from each position on, 0 if none is positive.
+    best_unit: Vec<f64>,
+    current: Vec<u32>,
+    best: Option<Vec<u32>>,
+    best_value: f64,
+    nodes: u64,
+}
+
+impl<'a> Search<'a> {
+    fn new(items: &'a [Item], order: Vec<(usize, u32)>, limits: &Limits) -> Search<'a> {
+        let masks: Vec<u64> = order.iter()
+            .map(|(i, _)| limits.categories.iter()
+                .position(|c| *c == items[*i].category)
+                .map_or(0, |bit| 1 << bit))
+            .collect();
+        let mut cheapest = vec![vec![i64::MAX; limits.categories.len()]; order.len() + 1];
+        let mut best_unit: Vec<f64> = vec![0.0; order.len() + 1];
+        for position in (0..order.len()).rev() {
+            let item = &items[order[position].0];
+            cheapest[position] = cheapest[position + 1].clone();
+            if let Some(c) = limits.categories.iter().position(|c| *c == item.category) {
+                cheapest[position][c] = cheapest[position][c].min(item.cost);
+            }
+            best_unit[position] = best_unit[position + 1].max(item.value);
+        }
+        Search {
+            items,
+            current: vec![0; order.len()],
+            order,
+            masks,
+            all: (0..limits.categories.len()).fold(0, |all, bit| all | 1 << bit),
+            cheapest,
+            best_unit,
+            best: None,
+            best_value: f64::NEG_INFINITY,
+            nodes: 0,
+        }
+    }
+
+    /// Tries every number of bottles of the candidate at `position`, the ones before it are in `current`.
+    fn step(&mut self, position: usize, budget: i64, bottles: u32, value: f64, covered: u64) {
+        self.nodes += 1;
+        if self.nodes > NODE_LIMIT {
+            return;
+        }
+        if covered == self.all && value > self.best_value + EPSILON {
+            self.best = Some(self.current.clone());
+            self.best_value = value;
+        }
+        if position == self.order.len() || !self.can_cover(position, budget, bottles, covered) {
+            return;
+        }
+        if self.best.is_some() && value + self.bound(position, budget, bottles) <= self.best_value + EPSILON {
+            return;
+        }
+        let (i, units) = self.order[position];
+        let item = &self.items[i];
+        let mut most = units.min(bottles).min((budget / item.cost) as u32);
+        // More than one bottle of something worth nothing only ever covers its category again
+        if item.value <= 0.0 {
+            most = most.min(1);
+        }
+        let quantities: Vec<u32> = if item.value > 0.0 { (0..=most).rev().collect() } else { (0..=most).collect() };
+        for q in quantities {
+            self.current[position] = q;
+            let covered = if q > 0 { covered | self.masks[position] } else { covered };
+            self.step(position + 1, budget - q as i64 * item.cost, bottles - q, value + q as f64 * item.value,
+                      covered);
+        }
+        self.current[position] = 0;
+    }
+
+    /// Whether the candidates from `position` on can still cover the missing categories.
+    fn can_cover(&self, position: usize, budget: i64, bottles: u32, covered: u64) -> bool {
+        let mut needed: i64 = 0;
+        let mut missing = 0;
+        for (bit, cost) in self.cheapest[position].iter().enumerate() {
+            if covered & 1 << bit == 0 {
+                if *cost == i64::MAX {
+                    return false;
+                }
+                needed = needed.saturating_add(*cost);
+                missing += 1;
+            }
+        }
+        needed <= budget && missing <= bottles
+    }
+
+    /// Most value the candidates from `position` on can add, spending the budget on the best value per krona
+    /// allowing parts of bottles, or filling the bottles with the best bottle, whichever is less.
+    fn bound(&self, position: usize, budget: i64, bottles: u32) -> f64 {
+        let mut left = budget;
+        let mut by_budget = 0.0;
+        for (i, units) in &self.order[position..] {
+            let item = &self.items[*i];
+            if left == 0 || item.value <= 0.0 {
+                break;
+            }
+            let spent = left.min(*units as i64 * item.cost);
+            by_budget += spent as f64 * item.value / item.cost as f64;
+            left -= spent;
+        }
+        by_budget.min(bottles as f64 * self.best_unit[position])
+    }
+}
+
+/// Öre, rounded as the till would.
+fn cost(product: &Product) -> i64 {
+    ((product.price + product.recycle_fee) * 100.0).round() as i64
+}
+
+/// The best basket of `products` for `opts`, `None` if none covers the required categories. `stock` holds the
+/// bottles in the store for products whose stock is reported, see `BasketOpts::site_id`.
+pub fn optimize(products: Vec<Product>, stock: &HashMap<ProductId, i32>, opts: &BasketOpts) -> Result<Option<Basket>> {
+    let score = if opts.score.is_empty() { None } else { Some(scoring::SCORES.get(&opts.score)?) };
+    let items: Vec<Item> = products.iter()
+        .map(|product| {
+            let mut units = if product.restricted_parcel_quantity > 0 {
+                product.restricted_parcel_quantity as u32
+            } else {
+                opts.max_bottles
+            };
+            if let Some(in_store) = stock.get(&product.product_id) {
+                units = units.min((*in_store).max(0) as u32);
+            }
+            Item {
+                cost: cost(product),
+                value: score.map_or_else(|| arithmetic::alcohol_grams(product), |s| s.evaluate(product)),
+                category: product.category.to_lowercase(),
+                units,
+            }
+        })
+        .collect();
+    let limits = Limits {
+        budget: (opts.budget * 100.0).round() as i64,
+        max_bottles: opts.max_bottles,
+        categories: opts.required_categories(),
+    };
+    let solution = match solve(&items, &limits) {
+        Some(solution) => solution,
+        None => return Ok(None),
+    };
+
+    let mut chosen: Vec<BasketItem> = Vec::with_capacity(solution.quantities.len());
+    let mut products: Vec<Option<Product>> = products.into_iter().map(Some).collect();
+    for (i, quantity) in &solution.quantities {
+        let product = products[*i].take().expect("Each product is chosen once");
+        chosen.push(BasketItem {
+            quantity: *quantity,
+            cost: (*quantity as i64 * items[*i].cost) as f64 / 100.0,
+            alcohol_grams: *quantity as f64 * arithmetic::alcohol_grams(&product),
+            product,
+        });
+    }
+    chosen.sort_by(|a, b| b.cost.partial_cmp(&a.cost).unwrap_or(Ordering::Equal));
+    let total_cost: i64 = solution.quantities.iter().map(|(i, q)| *q as i64 * items[*i].cost).sum();
+    Ok(Some(Basket {
+        bottles: chosen.iter().map(|item| item.quantity).sum(),
+        total_cost: total_cost as f64 / 100.0,
+        total_alcohol_grams: chosen.iter().map(|item| item.alcohol_grams).sum(),
+        total_score: solution.value,
+        optimal: solution.optimal,
+        items: chosen,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::product;
+
+    fn item(cost: i64, value: f64, category: &str, units: u32) -> Item {
+        Item { cost, value, category: category.to_string(), units }
+    }
+
+    fn limits(budget: i64, max_bottles: u32, categories: &[&str]) -> Limits {
+        Limits { budget, max_bottles, categories: categories.iter().map(|c| c.to_string()).collect() }
+    }
+
+    /// Every basket, for checking the search against.
+    fn brute_force(items: &[Item], limits: &Limits) -> Option<f64> {
+        fn walk(items: &[Item], limits: &Limits, position: usize, quantities: &mut Vec<u32>, best: &mut Option<f64>) {
+            if position == items.len() {
+                let cost: i64 = items.iter().zip(quantities.iter()).map(|(i, q)| i.cost * *q as i64).sum();
+                let bottles: u32 = quantities.iter().sum();
+                let covers = limits.categories.iter().all(|c| items.iter().zip(quantities.iter())
+                    .any(|(i, q)| *q > 0 && i.category == *c));
+                if cost <= limits.budget && bottles <= limits.max_bottles && covers {
+                    let value = items.iter().zip(quantities.iter()).map(|(i, q)| i.value * *q as f64).sum();
+                    if best.iter().all(|b| value > *b) {
+                        *best = Some(value);
+                    }
+                }
+                return;
+            }
+            for q in 0..=items[position].units {
+                quantities.push(q);
+                walk(items, limits, position + 1, quantities, best);
+                quantities.pop();
+            }
+        }
+        let mut best = None;
+        walk(items, limits, 0, &mut Vec::new(), &mut best);
+        best
+    }
+
+    #[test]
+    fn test_solve_matches_brute_force() {
+        // A small deterministic generator, the cases cover ties, worthless bottles and categories with one option
+        let mut seed: u64 = 7;
+        let mut next = |modulo: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % modulo
+        };
+        let categories = ["öl", "vin", "sprit"];
+        for _ in 0..300 {
+            let items: Vec<Item> = (0..next(6) + 1)
+                .map(|_| item(next(40) as i64 * 100 + 900, next(30) as f64 - 3.0,
+                              categories[next(3) as usize], next(3) as u32 + 1))
+                .collect();
+            let required: Vec<&str> = categories.iter().copied().filter(|_| next(4) == 0).collect();
+            let limits = limits(next(150) as i64 * 100, next(5) as u32 + 1, &required);
+            let expected = brute_force(&items, &limits);
+            let solution = solve(&items, &limits);
+            assert_eq!(expected.is_some(), solution.is_some());
+            if let (Some(expected), Some(solution)) = (expected, solution) {
+                assert!((expected - solution.value).abs() < 1e-9, "{} != {}", expected, solution.value);
+                assert!(solution.optimal);
+                let chosen: f64 = solution.quantities.iter().map(|(i, q)| items[*i].value * *q as f64).sum();
+                assert!((chosen - solution.value).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_solve_limits() {
+        let items = vec![
+            item(2000, 40.0, "öl", 10),
+            item(20000, 300.0, "sprit", 1),
+            item(12000, 10.0, "vin", 10),
+        ];
+        // The beer is the best per krona but only six bottles fit
+        let best = solve(&items, &limits(30000, 6, &[])).unwrap();
+        assert_eq!(vec![(0, 5), (1, 1)], best.quantities);
+        // A wine is required, the spirit doesn't fit beside it
+        let wine = solve(&items, &limits(30000, 6, &["vin"])).unwrap();
+        assert_eq!(vec![(0, 5), (2, 1)], wine.quantities);
+        assert!(solve(&items, &limits(30000, 6, &["cider"])).is_none());
+        assert!(solve(&items, &limits(1000, 6, &[])).unwrap().quantities.is_empty());
+    }
+
+    #[test]
+    fn test_optimize() {
+        // 19.725 grams of alcohol for 20 kr a can, at most 2 cans per customer
+        let mut beer = product("1", "Öl", 19.0, 500.0, 5.0);
+        beer.recycle_fee = 1.0;
+        beer.restricted_parcel_quantity = 2;
+        let mut spirit = product("2", "sprit", 300.0, 700.0, 40.0);
+        spirit.recycle_fee = 0.0;
+        let wine = product("3", "vin", 119.0, 750.0, 13.0);
+        let products = vec![beer, spirit, wine];
+        let opts = BasketOpts { budget: 340.0, max_bottles: 6, ..BasketOpts::default() };
+
+        let basket = optimize(products.clone(), &HashMap::new(), &opts).unwrap().unwrap();
+        let ids: Vec<(String, u32)> = basket.items.iter().map(|i| (i.product.product_id.to_string(), i.quantity)).collect();
+        assert_eq!(vec![(String::from("2"), 1), (String::from("1"), 2)], ids);
+        assert_eq!(340.0, basket.total_cost);
+        assert_eq!(3, basket.bottles);
+        assert!((basket.total_alcohol_grams - (220.92 + 2.0 * 19.725)).abs() < 1e-9);
+        assert_eq!(basket.total_alcohol_grams, basket.total_score);
+        assert!(basket.optimal);
+
+        // The store is out of the spirit
+        let stock: HashMap<ProductId, i32> = vec![(ProductId::from("2"), 0)].into_iter().collect();
+        let basket = optimize(products.clone(), &stock, &opts).unwrap().unwrap();
+        let ids: Vec<&str> = basket.items.iter().map(|i| i.product.product_id.as_str()).collect();
+        assert_eq!(vec!["3", "1"], ids);
+
+        let by_apk = BasketOpts { score: String::from("apk"), ..opts.clone() };
+        let basket = optimize(products.clone(), &HashMap::new(), &by_apk).unwrap().unwrap();
+        assert!((basket.total_score - basket.items.iter().map(|i| i.product.apk * i.quantity as f64).sum::<f64>()).abs() < 1e-9);
+
+        let unknown = BasketOpts { score: String::from("unknown"), ..opts };
+        assert!(optimize(products, &HashMap::new(), &unknown).is_err());
+    }
+
+    #[test]
+    fn test_optimize_catalogue() {
+        let (products, _, _) = crate::domain::models::fixtures::catalogue(5000, 0, 0);
+        let opts = BasketOpts { budget: 1000.0, max_bottles: 24, categories: String::from("vin, Öl"),
+            ..BasketOpts::default() };
+        let basket = optimize(products, &HashMap::new(), &opts).unwrap().unwrap();
+        assert!(basket.optimal);
+        assert!(basket.total_cost <= 1000.0 && basket.bottles <= 24);
+        assert!(basket.items.iter().any(|i| i.product.category == "vin"));
+        assert!(basket.items.iter().any(|i| i.product.category == "öl"));
+    }
+}
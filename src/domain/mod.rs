@@ -1,5 +1,8 @@
 pub mod models;
 pub mod arithmetic;
+pub mod basket;
 pub mod result;
+pub mod scoring;
+pub mod slug;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,148 @@
+use serde::{Serialize, Deserialize};
+use super::ids::SiteId;
+use super::product::{Product, ProductOpts};
+use crate::domain::result::{Result, ErrorKind};
+use crate::domain::scoring;
+
+/// Most bottles a basket may be asked to hold.
+pub const MAX_BOTTLES: u32 = 100;
+
+/// Most categories a basket may be required to cover.
+pub const MAX_CATEGORIES: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BasketOpts {
+    /// Most the basket may cost in SEK, deposits included.
+    pub budget: f64,
+
+    #[serde(default="default_max_bottles")]
+    pub max_bottles: u32,
+
+    /// Comma separated categories the basket needs at least one bottle of.
+    #[serde(default)]
+    pub categories: String,
+
+    /// Only products listed in this store, no more bottles of each than it has in stock if stock is reported.
+    #[serde(default)]
+    pub site_id: SiteId,
+
+    /// Name of the score to maximize the sum of, see `scoring::Scores`. Grams of alcohol if empty.
+    #[serde(default)]
+    pub score: String,
+}
+
+fn default_max_bottles() -> u32 {
+    12
+}
+
+impl Default for BasketOpts {
+    fn default() -> Self {
+        BasketOpts {
+            budget: 0.0,
+            max_bottles: default_max_bottles(),
+            categories: String::new(),
+            site_id: SiteId::default(),
+            score: String::new(),
+        }
+    }
+}
+
+impl BasketOpts {
+    pub fn normalize(mut self) -> BasketOpts {
+        self.site_id = self.site_id.as_str().trim().into();
+        self.score = self.score.trim().to_string();
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |name: &str, value: String| Err(ErrorKind::InvalidParameter(name.to_string(), value).into());
+        if !self.budget.is_finite() || self.budget <= 0.0 {
+            return invalid("budget", self.budget.to_string());
+        }
+        if self.max_bottles == 0 || self.max_bottles > MAX_BOTTLES {
+            return invalid("max_bottles", self.max_bottles.to_string());
+        }
+        if self.required_categories().len() > MAX_CATEGORIES {
+            return invalid("categories", self.categories.clone());
+        }
+        if !self.score.is_empty() {
+            scoring::SCORES.get(&self.score)?;
+        }
+        Ok(())
+    }
+
+    /// Every product the basket may hold, those listed in the store if there is one.
+    pub fn product_opts(&self) -> ProductOpts {
+        ProductOpts {
+            count: i64::MAX as usize,
+            max_volume: f64::MAX,
+            site_id: self.site_id.clone(),
+            ..ProductOpts::default()
+        }
+    }
+
+    /// Lowercased like the stored categories, without duplicates.
+    pub fn required_categories(&self) -> Vec<String> {
+        let mut categories: Vec<String> = Vec::new();
+        for category in self.categories.split(',').map(|c| c.trim().to_lowercase()).filter(|c| !c.is_empty()) {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        categories
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BasketItem {
+    #[serde(flatten)]
+    pub product: Product,
+    #[serde(rename="Quantity")]
+    pub quantity: u32,
+    /// Price and deposit of every bottle of the product.
+    #[serde(rename="Cost")]
+    pub cost: f64,
+    #[serde(rename="AlcoholGrams")]
+    pub alcohol_grams: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Basket {
+    #[serde(rename="Items")]
+    pub items: Vec<BasketItem>,
+    #[serde(rename="Bottles")]
+    pub bottles: u32,
+    #[serde(rename="TotalCost")]
+    pub total_cost: f64,
+    #[serde(rename="TotalAlcoholGrams")]
+    pub total_alcohol_grams: f64,
+    /// Sum of the maximized score, equal to `TotalAlcoholGrams` without a `score`.
+    #[serde(rename="TotalScore")]
+    pub total_score: f64,
+    /// `false` if the search was cut short, the basket is the best one found.
+    #[serde(rename="Optimal")]
+    pub optimal: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let opts = |budget: f64, max_bottles: u32, categories: &str| BasketOpts {
+            budget,
+            max_bottles,
+            categories: categories.to_string(),
+            ..BasketOpts::default()
+        };
+        assert!(opts(500.0, 12, "").validate().is_ok());
+        assert!(opts(0.0, 12, "").validate().is_err());
+        assert!(opts(f64::NAN, 12, "").validate().is_err());
+        assert!(opts(500.0, 0, "").validate().is_err());
+        assert!(opts(500.0, MAX_BOTTLES + 1, "").validate().is_err());
+        assert!(opts(500.0, 12, "a,b,c,d,e,f,g,h,i").validate().is_err());
+        assert!(BasketOpts { score: String::from("unknown"), ..opts(500.0, 12, "") }.validate().is_err());
+        assert_eq!(vec!["öl", "vin"], opts(500.0, 12, " Öl, vin,,öl ").required_categories());
+    }
+}
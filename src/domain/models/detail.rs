@@ -0,0 +1,43 @@
+use serde::Serialize;
+use std::fmt;
+use super::ids::{ProductId, ProductNumber};
+use super::product::Product;
+use super::stock::StockLevel;
+
+/// How a single product is looked up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProductLookup {
+    Id(ProductId),
+    Number(ProductNumber),
+}
+
+impl ProductLookup {
+    pub fn column(&self) -> &'static str {
+        match self {
+            ProductLookup::Id(_) => "product_id",
+            ProductLookup::Number(_) => "product_number",
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            ProductLookup::Id(id) => id.as_str(),
+            ProductLookup::Number(number) => number.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for ProductLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.column(), self.value())
+    }
+}
+
+/// A product with the stores listing it, the product's own fields, ranks included, are at the top level.
+#[derive(Debug, Serialize, Clone)]
+pub struct ProductDetail {
+    #[serde(flatten)]
+    pub product: Product,
+    #[serde(rename="Stores")]
+    pub stores: Vec<StockLevel>,
+}
@@ -0,0 +1,206 @@
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{DeserializeOwned, Visitor, Error as DeError};
+use serde::de::value::Error as ValueError;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Formatter;
+use std::marker::PhantomData;
+use crate::domain::result::{Result, ErrorKind};
+
+/// Parse errors kept per report, the rest are only counted.
+const MAX_ERRORS: usize = 5;
+
+/// How an upstream payload compared to the model it's parsed into.
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct DriftReport {
+    #[serde(rename="Model")]
+    pub model: String,
+    #[serde(rename="Records")]
+    pub records: usize,
+    #[serde(rename="Skipped")]
+    pub skipped: usize,
+    /// Fields sent upstream that the model doesn't know, with the number of records carrying them.
+    #[serde(rename="UnknownFields")]
+    pub unknown_fields: BTreeMap<String, usize>,
+    /// Fields the model expects, with the number of records lacking them.
+    #[serde(rename="MissingFields")]
+    pub missing_fields: BTreeMap<String, usize>,
+    #[serde(rename="Errors")]
+    pub errors: Vec<String>,
+}
+
+impl DriftReport {
+    pub fn has_drift(&self) -> bool {
+        self.skipped > 0 || !self.unknown_fields.is_empty() || !self.missing_fields.is_empty()
+    }
+}
+
+impl std::fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DriftReport(model={}, records={}, skipped={}, unknown_fields={:?}, missing_fields={:?})",
+               self.model, self.records, self.skipped, self.unknown_fields.keys().collect::<Vec<_>>(),
+               self.missing_fields.keys().collect::<Vec<_>>())
+    }
+}
+
+/// Parses a JSON array record by record, a record that doesn't fit `T` is skipped and counted instead of failing
+/// the whole payload, unless every record fails.
+/// `derived` are fields of `T` that are computed locally and never sent upstream.
+pub fn parse_records<T: DeserializeOwned>(body: &str, model: &str, derived: &[&str]) -> Result<(Vec<T>, DriftReport)> {
+    parse_values(serde_json::from_str(body)?, model, derived)
+}
+
+/// `parse_records` for records already split out of their payload.
+pub fn parse_values<T: DeserializeOwned>(records: Vec<Value>, model: &str, derived: &[&str])
+    -> Result<(Vec<T>, DriftReport)> {
+    let mut parser = RecordParser::new(model, derived);
+    let parsed = records.into_iter().filter_map(|r| parser.parse(r)).collect();
+    Ok((parsed, parser.finish()?))
+}
+
+/// Parses records of one model one at a time, for payloads spread over several responses.
+pub struct RecordParser<T> {
+    report: DriftReport,
+    known: HashSet<&'static str>,
+    expected: Vec<&'static str>,
+    parsed: usize,
+    model: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> RecordParser<T> {
+    pub fn new(model: &str, derived: &[&str]) -> Self {
+        let fields = struct_fields::<T>();
+        RecordParser {
+            report: DriftReport { model: model.to_string(), ..DriftReport::default() },
+            known: fields.iter().copied().collect(),
+            expected: fields.iter().copied().filter(|f| !derived.contains(f)).collect(),
+            parsed: 0,
+            model: PhantomData,
+        }
+    }
+
+    /// `None` if the record doesn't fit `T`, it's counted as skipped.
+    pub fn parse(&mut self, record: Value) -> Option<T> {
+        let (known, expected, report) = (&self.known, &self.expected, &mut self.report);
+        if let Value::Object(map) = &record {
+            for key in map.keys().filter(|k| !known.contains(k.as_str())) {
+                *report.unknown_fields.entry(key.clone()).or_default() += 1;
+            }
+            for field in expected.iter().filter(|f| !map.contains_key(**f)) {
+                *report.missing_fields.entry(field.to_string()).or_default() += 1;
+            }
+        }
+        let index = report.records;
+        report.records += 1;
+        match serde_json::from_value::<T>(record) {
+            Ok(value) => {
+                self.parsed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                report.skipped += 1;
+                if report.errors.len() < MAX_ERRORS {
+                    report.errors.push(format!("record {}: {}", index, e));
+                }
+                None
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<DriftReport> {
+        let report = self.report;
+        if report.has_drift() {
+            warn!("Upstream drift: {}", report);
+        }
+        // Loading nothing would wipe the snapshot, keep the previous one instead
+        if self.parsed == 0 && report.records > 0 {
+            return Err(ErrorKind::UpstreamRejected(report.model, report.records).into());
+        }
+        Ok(report)
+    }
+}
+
+/// Serialized field names of a struct, read from its `Deserialize` impl so serde renames are respected.
+pub fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Only answers `deserialize_struct`, recording the field names it's asked for.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        Err(ValueError::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V)
+        -> std::result::Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(ValueError::custom("only reading field names"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit unit_struct
+        newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::domain::models::fixtures;
+    use crate::domain::models::product::{Product, DERIVED_FIELDS};
+    use crate::domain::models::projection::PRODUCT_FIELDS;
+    use crate::domain::models::site::Site;
+
+    #[test]
+    fn test_struct_fields() {
+        let fields = struct_fields::<Product>();
+        let projected: Vec<&str> = PRODUCT_FIELDS.iter().map(|f| f.name).collect();
+        assert_eq!(projected, fields);
+        assert!(fields.contains(&"Usage"));
+        assert!(fields.contains(&"ApkRecycling"));
+        assert!(struct_fields::<Site>().contains(&"OpeningHours"));
+    }
+
+    #[test]
+    fn test_skips_bad_records() {
+        let record = |id: &str| {
+            let mut value = serde_json::to_value(fixtures::product(id, "öl", 20.0, 330.0, 5.0)).unwrap();
+            let map = value.as_object_mut().unwrap();
+            for derived in DERIVED_FIELDS {
+                map.remove(*derived);
+            }
+            value
+        };
+        let mut bad = record("2");
+        bad["AlcoholPercentage"] = Value::Null;
+        let mut renamed = record("3");
+        let taste = renamed.as_object_mut().unwrap().remove("Taste").unwrap();
+        renamed["TasteClocks"] = taste;
+        let body = json!([record("1"), bad, renamed]).to_string();
+
+        let (products, report) = parse_records::<Product>(&body, "Product", DERIVED_FIELDS).unwrap();
+        assert_eq!(vec!["1", "3"], products.iter().map(|p| p.product_id.as_str()).collect::<Vec<&str>>());
+        assert_eq!(3, report.records);
+        assert_eq!(1, report.skipped);
+        assert!(report.errors[0].starts_with("record 1: invalid type: null"));
+        assert_eq!(vec![(&String::from("TasteClocks"), &1)], report.unknown_fields.iter().collect::<Vec<_>>());
+        assert_eq!(vec![(&String::from("Taste"), &1)], report.missing_fields.iter().collect::<Vec<_>>());
+        assert!(report.has_drift());
+
+        let (_, clean) = parse_records::<Product>(&json!([record("1")]).to_string(), "Product", DERIVED_FIELDS)
+            .unwrap();
+        assert!(!clean.has_drift());
+        assert!(parse_records::<Product>("{\"not\": \"a list\"}", "Product", DERIVED_FIELDS).is_err());
+        let (_, empty) = parse_records::<Product>("[]", "Product", DERIVED_FIELDS).unwrap();
+        assert_eq!(0, empty.records);
+        let all_bad = json!([{"ProductId": "1"}]).to_string();
+        assert!(parse_records::<Product>(&all_bad, "Product", DERIVED_FIELDS).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::NaiveDate;
+use super::ids::ProductId;
+use super::product::{Product, MinimalSite, MinimalProduct};
+use super::site::{Site, Position};
+use crate::domain::arithmetic::{get_apk, get_recyc_apk};
+
+pub fn product(product_id: &str, category: &str, price: f64, volume: f64, alcohol_percentage: f64) -> Product {
+    let mut p = Product {
+        product_id: product_id.into(),
+        product_number: format!("{}01", product_id).into(),
+        product_name_bold: format!("Product {}", product_id),
+        product_name_thin: None,
+        category: category.to_string(),
+        product_number_short: product_id.to_string(),
+        producer_name: None,
+        supplier_name: None,
+        is_kosher: false,
+        bottle_text_short: String::new(),
+        restricted_parcel_quantity: 0,
+        seal: None,
+        is_organic: false,
+        is_ethical: false,
+        ethical_label: None,
+        is_web_launch: false,
+        sell_start_date: Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0)),
+        is_completely_out_of_stock: false,
+        is_temporary_out_of_stock: false,
+        alcohol_percentage,
+        volume,
+        price,
+        country: String::from("Sverige"),
+        origin_level1: None,
+        origin_level2: None,
+        vintage: None,
+        sub_category: None,
+        a_type: None,
+        style: None,
+        assortment_text: String::new(),
+        beverage_description_short: None,
+        usage_text: None,
+        taste: None,
+        assortment: String::from("FS"),
+        is_manufacturing_country: false,
+        recycle_fee: 1.0,
+        is_regional_retricted: false,
+        is_in_store_search_assortment: String::new(),
+        is_news: false,
+        apk: 0.0,
+        apk_recycling: 0.0,
+        link: String::new(),
+        ..Product::default()
+    };
+    p.apk = get_apk(&p);
+    p.apk_recycling = get_recyc_apk(&p);
+    p
+}
+
+pub fn site(site_id: &str, name: &str) -> Site {
+    Site {
+        site_id: site_id.into(),
+        is_tasting_store: false,
+        alias: None,
+        address: String::new(),
+        display_name: None,
+        postal_code: String::new(),
+        city: String::new(),
+        county: String::new(),
+        country: String::new(),
+        is_store: true,
+        is_agent: false,
+        is_active_for_agent_order: false,
+        phone: None,
+        email: None,
+        services: None,
+        opening_hours: Vec::new(),
+        depot: None,
+        name: Some(name.to_string()),
+        position: Position { lat: 0.0, long: 0.0 },
+    }
+}
+
+pub fn minimal_site(site_id: &str, product_ids: &[&str]) -> MinimalSite {
+    MinimalSite {
+        site_id: site_id.into(),
+        products: product_ids.iter()
+            .map(|id| MinimalProduct {
+                product_id: (*id).into(),
+                product_number: format!("{}01", id).into(),
+                stock: None,
+                shelf: None,
+            })
+            .collect(),
+    }
+}
+
+/// A deterministic catalogue shaped like the real one, `per_site` products listed in each of `sites` stores.
+pub fn catalogue(products: usize, sites: usize, per_site: usize) -> (Vec<Product>, Vec<Site>, Vec<MinimalSite>) {
+    let categories = ["öl", "vin", "sprit", "cider och blanddrycker", "alkoholfritt"];
+    let all: Vec<Product> = (0..products)
+        .map(|i| {
+            let mut p = product(&format!("{}", 100_000 + i), categories[i % categories.len()],
+                                20.0 + (i % 500) as f64, 330.0 + (i % 4) as f64 * 250.0, 2.0 + (i % 40) as f64);
+            p.taste = Some(String::from("Fruktig smak med inslag av äpple, päron och citrus."));
+            p.usage_text = Some(String::from("Serveras vid 8-10°C som sällskapsdryck eller till lättare rätter."));
+            p
+        })
+        .collect();
+    let stores: Vec<Site> = (0..sites)
+        .map(|i| site(&format!("{:04}", i), &format!("Butik {}", i)))
+        .collect();
+    let mapping = stores.iter().enumerate()
+        .map(|(i, s)| {
+            let ids: Vec<ProductId> = (0..per_site)
+                .map(|j| all[(i * 37 + j * 7) % products].product_id.clone())
+                .collect();
+            let refs: Vec<&str> = ids.iter().map(|id| id.as_str()).collect();
+            minimal_site(s.site_id.as_str(), &refs)
+        })
+        .collect();
+    (all, stores, mapping)
+}
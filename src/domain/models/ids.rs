@@ -0,0 +1,139 @@
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+#[cfg(feature = "postgres")]
+use tokio_postgres::types as postgres_types;
+
+/// An identifier that is a plain string upstream and in storage, typed so different ids can't be mixed up.
+/// Serializes and is stored as the bare string.
+macro_rules! string_id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new<S: Into<String>>(id: S) -> Self {
+                $name(id.into())
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.0.is_empty()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                $name::new(id)
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                $name(id)
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialEq<$name> for &str {
+            fn eq(&self, other: &$name) -> bool {
+                *self == other.0
+            }
+        }
+
+        impl ToSql for $name {
+            fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+                ToSql::to_sql(&self.0)
+            }
+        }
+
+        impl FromSql for $name {
+            fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                <String as FromSql>::column_result(value).map($name)
+            }
+        }
+
+        #[cfg(feature = "postgres")]
+        impl postgres_types::ToSql for $name {
+            fn to_sql(&self, ty: &postgres_types::Type, out: &mut bytes::BytesMut)
+                -> Result<postgres_types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+                postgres_types::ToSql::to_sql(&self.0, ty, out)
+            }
+
+            fn accepts(ty: &postgres_types::Type) -> bool {
+                <String as postgres_types::ToSql>::accepts(ty)
+            }
+
+            postgres_types::to_sql_checked!();
+        }
+
+        #[cfg(feature = "postgres")]
+        impl<'a> postgres_types::FromSql<'a> for $name {
+            fn from_sql(ty: &postgres_types::Type, raw: &'a [u8])
+                -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+                <String as postgres_types::FromSql>::from_sql(ty, raw).map($name)
+            }
+
+            fn accepts(ty: &postgres_types::Type) -> bool {
+                <String as postgres_types::FromSql>::accepts(ty)
+            }
+        }
+    };
+}
+
+string_id!(
+    /// Systembolaget's internal product id, the primary key of a product.
+    ProductId
+);
+
+string_id!(
+    /// The article number shown to customers, part of the product link.
+    ProductNumber
+);
+
+string_id!(
+    /// Store or agent id, e.g. `0102`.
+    SiteId
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, NO_PARAMS};
+
+    #[test]
+    fn test_ids_are_bare_strings() {
+        let id = SiteId::new("0102");
+        assert_eq!("\"0102\"", serde_json::to_string(&id).unwrap());
+        assert_eq!(id, serde_json::from_str::<SiteId>("\"0102\"").unwrap());
+        assert!(serde_json::from_str::<SiteId>("null").is_err());
+        assert_eq!(id, "0102");
+
+        let con = Connection::open_in_memory().unwrap();
+        let stored: ProductId = con.query_row("SELECT ?1", &[&ProductId::new("42")], |row| row.get(0)).unwrap();
+        assert_eq!(ProductId::new("42"), stored);
+        assert!(con.query_row("SELECT NULL", NO_PARAMS, |row| row.get::<_, ProductId>(0)).is_err());
+    }
+}
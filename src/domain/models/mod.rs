@@ -1,3 +1,13 @@
+pub mod drift;
+pub mod ids;
 pub mod product;
+pub mod projection;
 pub mod site;
+pub mod stock;
+pub mod detail;
+pub mod basket;
 pub mod serialization_helpers;
+pub mod watchlist;
+pub mod refresh;
+#[cfg(test)]
+pub mod fixtures;
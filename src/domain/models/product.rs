@@ -1,49 +1,53 @@
 use serde::{Serialize, Deserialize};
-use serde::export::Formatter;
-use super::serialization_helpers::nullable_string;
-use regex::Regex;
-use unidecode;
+use std::fmt::Formatter;
+use chrono::NaiveDateTime;
+use super::ids::{ProductId, ProductNumber, SiteId};
+use super::serialization_helpers::{nullable_string, optional_datetime, optional_string};
+use super::projection::{self, ProductField};
+use crate::domain::result;
+use crate::domain::scoring;
 
-lazy_static! {
-    static ref RE: Regex = Regex::new("[^A-Za-z0-9 ]").unwrap();
-}
+/// Fields of `Product` computed when a snapshot is loaded, upstream never sends them.
+pub const DERIVED_FIELDS: &[&str] = &["Apk", "ApkRecycling", "Link", "ApkRank", "ApkPercentile", "CategoryRank",
+    "CategoryPercentile", "SubCategoryRank", "SubCategoryPercentile", "VolumeBucket", "VolumeBucketRank",
+    "VolumeBucketPercentile"];
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Product {
-    #[serde(rename="ProductId", deserialize_with = "nullable_string")]
-    pub product_id: String,
-    #[serde(rename="ProductNumber", deserialize_with="nullable_string")]
-    pub product_number: String,
-    #[serde(rename="ProductNameBold", deserialize_with="nullable_string")]
+    #[serde(rename="ProductId")]
+    pub product_id: ProductId,
+    #[serde(rename="ProductNumber")]
+    pub product_number: ProductNumber,
+    #[serde(rename="ProductNameBold", default, deserialize_with="nullable_string")]
     pub product_name_bold: String,
-    #[serde(rename="ProductNameThin", deserialize_with="nullable_string")]
-    pub product_name_thin: String,
-    #[serde(rename="Category", deserialize_with="nullable_string")]
+    #[serde(rename="ProductNameThin", default, deserialize_with="optional_string")]
+    pub product_name_thin: Option<String>,
+    #[serde(rename="Category", default, deserialize_with="nullable_string")]
     pub category: String,
-    #[serde(rename="ProductNumberShort", deserialize_with="nullable_string")]
+    #[serde(rename="ProductNumberShort", default, deserialize_with="nullable_string")]
     pub product_number_short: String,
-    #[serde(rename="ProducerName", deserialize_with="nullable_string")]
-    pub producer_name: String,
-    #[serde(rename="SupplierName", deserialize_with="nullable_string")]
-    pub supplier_name: String,
+    #[serde(rename="ProducerName", default, deserialize_with="optional_string")]
+    pub producer_name: Option<String>,
+    #[serde(rename="SupplierName", default, deserialize_with="optional_string")]
+    pub supplier_name: Option<String>,
     #[serde(rename="IsKosher")]
     pub is_kosher: bool,
-    #[serde(rename="BottleTextShort", deserialize_with="nullable_string")]
+    #[serde(rename="BottleTextShort", default, deserialize_with="nullable_string")]
     pub bottle_text_short: String,
     #[serde(rename="RestrictedParcelQuantity")]
     pub restricted_parcel_quantity: i32,
-    #[serde(rename="Seal", deserialize_with="nullable_string")]
-    pub seal: String,
+    #[serde(rename="Seal", default, deserialize_with="optional_string")]
+    pub seal: Option<String>,
     #[serde(rename="IsOrganic")]
     pub is_organic: bool,
     #[serde(rename="IsEthical")]
     pub is_ethical: bool,
-    #[serde(rename="EthicalLabel", deserialize_with="nullable_string")]
-    pub ethical_label: String,
+    #[serde(rename="EthicalLabel", default, deserialize_with="optional_string")]
+    pub ethical_label: Option<String>,
     #[serde(rename="IsWebLaunch")]
     pub is_web_launch: bool,
-    #[serde(rename="SellStartDate", deserialize_with="nullable_string")]
-    pub sell_start_date: String,
+    #[serde(rename="SellStartDate", default, deserialize_with="optional_datetime")]
+    pub sell_start_date: Option<NaiveDateTime>,
     #[serde(rename="IsCompletelyOutOfStock")]
     pub is_completely_out_of_stock: bool,
     #[serde(rename="IsTemporaryOutOfStock")]
@@ -54,29 +58,30 @@ pub struct Product {
     pub volume: f64,
     #[serde(rename="Price")]
     pub price: f64,
-    #[serde(rename="Country", deserialize_with="nullable_string")]
+    #[serde(rename="Country", default, deserialize_with="nullable_string")]
     pub country: String,
-    #[serde(rename="OriginLevel1", deserialize_with="nullable_string")]
-    pub origin_level1: String,
-    #[serde(rename="OriginLevel2", deserialize_with="nullable_string")]
-    pub origin_level2: String,
-    #[serde(rename="Vintage")]
-    pub vintage: i32,
-    #[serde(rename="SubCategory", deserialize_with="nullable_string")]
-    pub sub_category: String,
-    #[serde(rename="Type", deserialize_with="nullable_string")]
-    pub a_type: String,
-    #[serde(rename="Style", deserialize_with="nullable_string")]
-    pub style: String,
-    #[serde(rename="AssortmentText", deserialize_with="nullable_string")]
+    #[serde(rename="OriginLevel1", default, deserialize_with="optional_string")]
+    pub origin_level1: Option<String>,
+    #[serde(rename="OriginLevel2", default, deserialize_with="optional_string")]
+    pub origin_level2: Option<String>,
+    /// `None` for non-vintage products, upstream sends 0 or null for those.
+    #[serde(rename="Vintage", default, deserialize_with="optional_vintage")]
+    pub vintage: Option<i32>,
+    #[serde(rename="SubCategory", default, deserialize_with="optional_string")]
+    pub sub_category: Option<String>,
+    #[serde(rename="Type", default, deserialize_with="optional_string")]
+    pub a_type: Option<String>,
+    #[serde(rename="Style", default, deserialize_with="optional_string")]
+    pub style: Option<String>,
+    #[serde(rename="AssortmentText", default, deserialize_with="nullable_string")]
     pub assortment_text: String,
-    #[serde(rename="BeverageDescriptionShort", deserialize_with="nullable_string")]
-    pub beverage_description_short: String,
-    #[serde(rename="Usage", deserialize_with="nullable_string")]
-    pub usage_text: String,
-    #[serde(rename="Taste", deserialize_with="nullable_string")]
-    pub taste: String,
-    #[serde(rename="Assortment", deserialize_with="nullable_string")]
+    #[serde(rename="BeverageDescriptionShort", default, deserialize_with="optional_string")]
+    pub beverage_description_short: Option<String>,
+    #[serde(rename="Usage", default, deserialize_with="optional_string")]
+    pub usage_text: Option<String>,
+    #[serde(rename="Taste", default, deserialize_with="optional_string")]
+    pub taste: Option<String>,
+    #[serde(rename="Assortment", default, deserialize_with="nullable_string")]
     pub assortment: String,
     #[serde(rename="IsManufacturingCountry")]
     pub is_manufacturing_country: bool,
@@ -84,7 +89,7 @@ pub struct Product {
     pub recycle_fee: f64,
     #[serde(rename="IsRegionalRestricted")]
     pub is_regional_retricted: bool,
-    #[serde(rename="IsInStoreSearchAssortment", deserialize_with="nullable_string")]
+    #[serde(rename="IsInStoreSearchAssortment", default, deserialize_with="nullable_string")]
     pub is_in_store_search_assortment: String,
     #[serde(rename="IsNews")]
     pub is_news: bool,
@@ -100,21 +105,33 @@ pub struct Product {
     #[serde(rename="Link")]
     #[serde(default)]
     pub link: String,
-}
 
-impl Product {
-    pub fn construct_link(&self) -> String {
-        let cat_no_se_signs = remove_swe_signs_and_replace_spaces(&self.category);
-        let name_no_space = remove_swe_signs_and_replace_spaces(&self.product_name_bold);
-        format!("https://systembolaget.se/dryck/{}/{}-{}", cat_no_se_signs,
-                name_no_space, self.product_number)
-    }
+    /// Ranks by apk, 1 is the highest and equal apks share a rank. Percentiles are the rank as a share of the
+    /// ranked group, counting from the top, 3.0 is the top 3%. All of them are set when a snapshot is loaded.
+    #[serde(rename="ApkRank", default)]
+    pub apk_rank: i32,
+    #[serde(rename="ApkPercentile", default)]
+    pub apk_percentile: f64,
+    #[serde(rename="CategoryRank", default)]
+    pub category_rank: i32,
+    #[serde(rename="CategoryPercentile", default)]
+    pub category_percentile: f64,
+    #[serde(rename="SubCategoryRank", default)]
+    pub sub_category_rank: i32,
+    #[serde(rename="SubCategoryPercentile", default)]
+    pub sub_category_percentile: f64,
+    /// See `arithmetic::VOLUME_BUCKETS`.
+    #[serde(rename="VolumeBucket", default)]
+    pub volume_bucket: String,
+    #[serde(rename="VolumeBucketRank", default)]
+    pub volume_bucket_rank: i32,
+    #[serde(rename="VolumeBucketPercentile", default)]
+    pub volume_bucket_percentile: f64,
 }
 
-fn remove_swe_signs_and_replace_spaces(source: &str) -> String {
-    let signs = unidecode::unidecode(&source.to_lowercase());
-    return RE.replace_all(&signs, "")
-        .replace(" ", "-");
+fn optional_vintage<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error> where D: serde::Deserializer<'de> {
+    let opt: Option<i32> = Option::deserialize(deserializer)?;
+    Ok(opt.filter(|v| *v != 0))
 }
 
 impl std::fmt::Display for Product {
@@ -122,13 +139,13 @@ impl std::fmt::Display for Product {
         write!(f,
         "Product(product_id={}, product_number={}, product_name_bold={}, product_name_thin={}, category={}, \
         alcohol_percentage={}, volume={}, price={}, apk={}, apk_recycling={})",
-        self.product_id.as_str(), self.product_number, self.product_name_bold.as_str(),
-        self.product_name_thin.as_str(), self.category.as_str(),
+        self.product_id, self.product_number, self.product_name_bold.as_str(),
+        self.product_name_thin.as_deref().unwrap_or(""), self.category.as_str(),
         self.alcohol_percentage, self.volume, self.price, self.apk, self.apk_recycling)
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct ProductOpts {
     pub count: usize,
 
@@ -140,17 +157,114 @@ pub struct ProductOpts {
     pub max_volume: f64,
 
     #[serde(default)]
-    pub site_id: String,
+    pub site_id: SiteId,
+
+    #[serde(default)]
+    pub category: String,
+
+    /// Only applies to store scoped queries, see `Site::is_open_at`.
+    #[serde(default)]
+    pub open_now: bool,
+
+    #[serde(default)]
+    pub open_at: String,
+
+    /// Only applies to store scoped queries, products with fewer units in the store are left out.
+    /// 0 keeps every listed product, including those listed but out of stock and those whose stock isn't reported.
+    #[serde(default)]
+    pub min_stock: i64,
+
+    /// Comma separated `Product` fields to return, takes precedence over `view`.
+    #[serde(default)]
+    pub fields: String,
+
+    /// `full` or `compact`, see `projection::projection`.
+    #[serde(default)]
+    pub view: String,
+
+    /// Name of the score to rank by, see `scoring::Scores`. Takes precedence over `include_recycling`.
+    #[serde(default)]
+    pub score: String,
+}
+
+impl ProductOpts {
+    /// Category is stored lowercased, so it's matched that way.
+    pub fn normalize(mut self) -> ProductOpts {
+        self.category = self.category.trim().to_lowercase();
+        self.site_id = self.site_id.as_str().trim().into();
+        self.view = self.view.trim().to_lowercase();
+        self.score = self.score.trim().to_string();
+        self
+    }
+
+    /// Fields to select, `None` for the whole product.
+    pub fn projection(&self) -> result::Result<Option<Vec<&'static ProductField>>> {
+        projection::projection(&self.fields, &self.view)
+    }
+
+    /// Fails for a score that isn't registered.
+    pub fn validate_score(&self) -> result::Result<()> {
+        scoring::SCORES.get(&self.score).map(|_| ())
+    }
+
+    /// Identity of a normalized query, `None` if the result depends on the current time and can't be cached.
+    pub fn cache_key(&self) -> Option<String> {
+        if self.open_now || !self.open_at.is_empty() {
+            return None;
+        }
+        Some(format!("count={}&include_recycling={}&exists_in_store={}&max_volume={}&site_id={}&category={}\
+                     &min_stock={}&fields={}&view={}&score={}",
+                     self.count, self.include_recycling, self.exists_in_store, self.max_volume,
+                     self.site_id, self.category, self.min_stock, self.fields, self.view, self.score))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SiteCompareOpts {
+    pub a: SiteId,
+    pub b: SiteId,
+
+    #[serde(default="default_compare_count")]
+    pub count: usize,
+
+    #[serde(default)]
+    pub include_recycling: bool,
+
+    #[serde(default="default_max_volume")]
+    pub max_volume: f64,
 
     #[serde(default)]
     pub category: String,
 }
 
+impl SiteCompareOpts {
+    /// Product query for one side of the comparison, scoped to `site_id`.
+    pub fn product_opts(&self, site_id: &SiteId) -> ProductOpts {
+        ProductOpts {
+            count: self.count,
+            include_recycling: self.include_recycling,
+            exists_in_store: true,
+            max_volume: self.max_volume,
+            site_id: site_id.clone(),
+            category: self.category.clone(),
+            ..ProductOpts::default()
+        }
+    }
+}
+
+fn default_compare_count() -> usize {
+    100
+}
+
+fn default_max_volume() -> f64 {
+    f64::MAX
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MinimalSite {
 
     #[serde(rename="SiteId")]
-    pub site_id: String,
+    pub site_id: SiteId,
 
     #[serde(rename="Products")]
     pub products: Vec<MinimalProduct>,
@@ -161,9 +275,15 @@ pub struct MinimalSite {
 pub struct MinimalProduct {
 
     #[serde(rename="ProductId")]
-    pub product_id: String,
+    pub product_id: ProductId,
     #[serde(rename="ProductNumber")]
-    pub product_number: String,
+    pub product_number: ProductNumber,
+    /// Units in the store, `Some(0)` if it's listed but out of stock and `None` if the upstream only reports
+    /// that it's listed.
+    #[serde(rename="Stock", default)]
+    pub stock: Option<i32>,
+    #[serde(rename="Shelf", default, deserialize_with="optional_string")]
+    pub shelf: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -172,18 +292,62 @@ pub struct SiteResponse {
     #[serde(rename="SiteName")]
     pub site_name: String,
     #[serde(rename="SiteId")]
-    pub site_id: String,
+    pub site_id: SiteId,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cache_key_normalized() {
+        let opts = |category: &str| ProductOpts {
+            count: 10,
+            max_volume: 500.0,
+            category: category.to_string(),
+            ..ProductOpts::default()
+        }.normalize();
+        assert_eq!(opts("öl").cache_key(), opts(" Öl ").cache_key());
+        assert_ne!(opts("öl").cache_key(), opts("vin").cache_key());
+        assert_eq!(None, ProductOpts { open_now: true, ..opts("öl") }.cache_key());
+        assert_ne!(opts("öl").cache_key(), ProductOpts { view: String::from("compact"), ..opts("öl") }.cache_key());
+        assert_ne!(opts("öl").cache_key(), ProductOpts { min_stock: 1, ..opts("öl") }.cache_key());
+        assert_ne!(opts("öl").cache_key(), ProductOpts { score: String::from("apk_recycling"), ..opts("öl") }.cache_key());
+        assert!(ProductOpts { score: String::from("unknown"), ..opts("öl") }.validate_score().is_err());
+        assert!(opts("öl").validate_score().is_ok());
+    }
 
     #[test]
-    fn test_format_str() {
-        let src = "Sju komma två'an roséviner";
-        let expect = "sju-komma-tvaan-roseviner";
-        assert_eq!(expect, remove_swe_signs_and_replace_spaces(src));
+    fn test_missing_and_bad_values() {
+        let product = |vintage: serde_json::Value, sell_start: serde_json::Value| {
+            let mut source = serde_json::to_value(crate::domain::models::fixtures::product("1", "vin", 1.0, 1.0, 1.0))
+                .unwrap();
+            source["Vintage"] = vintage;
+            source["SellStartDate"] = sell_start;
+            serde_json::from_value::<Product>(source)
+        };
+        let plain = product(json!(null), json!(null)).unwrap();
+        assert_eq!(ProductId::new("1"), plain.product_id);
+        assert_eq!(None, plain.vintage);
+        assert_eq!(None, plain.sell_start_date);
+        assert_eq!(None, product(json!(0), json!("")).unwrap().vintage);
+        let dated = product(json!(2018), json!("2020-01-01T00:00:00")).unwrap();
+        assert_eq!(Some(2018), dated.vintage);
+        assert_eq!(Some(chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0)), dated.sell_start_date);
+        assert_eq!(json!("2020-01-01T00:00:00"), serde_json::to_value(&dated).unwrap()["SellStartDate"]);
+        assert!(product(json!("2018"), json!(null)).is_err());
+        assert!(product(json!(null), json!("someday")).is_err());
+
+        // Missing text stays missing instead of turning into an empty string
+        let mut source = serde_json::to_value(&plain).unwrap();
+        source["ProducerName"] = json!(null);
+        source["Taste"] = json!("Fruktig");
+        source.as_object_mut().unwrap().remove("SubCategory");
+        let described: Product = serde_json::from_value(source).unwrap();
+        assert_eq!((None, None), (described.producer_name.as_deref(), described.sub_category.as_deref()));
+        assert_eq!(Some("Fruktig"), described.taste.as_deref());
+        assert_eq!(json!(null), serde_json::to_value(&described).unwrap()["ProducerName"]);
     }
 
 }
@@ -0,0 +1,138 @@
+use crate::domain::result::{Result, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    Text,
+    Bool,
+    Int,
+    Real,
+}
+
+/// A `Product` field as named in the JSON output, with the column it's stored in.
+#[derive(Debug, PartialEq)]
+pub struct ProductField {
+    pub name: &'static str,
+    pub column: &'static str,
+    pub kind: FieldKind,
+}
+
+const fn field(name: &'static str, column: &'static str, kind: FieldKind) -> ProductField {
+    ProductField { name, column, kind }
+}
+
+/// Every `Product` field in declaration order, product rows are always selected with their columns in this order.
+pub const PRODUCT_FIELDS: &[ProductField] = &[
+    field("ProductId", "product_id", FieldKind::Text),
+    field("ProductNumber", "product_number", FieldKind::Text),
+    field("ProductNameBold", "product_name_bold", FieldKind::Text),
+    field("ProductNameThin", "product_name_thin", FieldKind::Text),
+    field("Category", "category", FieldKind::Text),
+    field("ProductNumberShort", "product_number_short", FieldKind::Text),
+    field("ProducerName", "producer_name", FieldKind::Text),
+    field("SupplierName", "supplier_name", FieldKind::Text),
+    field("IsKosher", "is_kosher", FieldKind::Bool),
+    field("BottleTextShort", "bottle_text_short", FieldKind::Text),
+    field("RestrictedParcelQuantity", "restricted_parcel_quantity", FieldKind::Int),
+    field("Seal", "seal", FieldKind::Text),
+    field("IsOrganic", "is_organic", FieldKind::Bool),
+    field("IsEthical", "is_ethical", FieldKind::Bool),
+    field("EthicalLabel", "ethical_label", FieldKind::Text),
+    field("IsWebLaunch", "is_web_launch", FieldKind::Bool),
+    field("SellStartDate", "sell_start_date", FieldKind::Text),
+    field("IsCompletelyOutOfStock", "is_completely_out_of_stock", FieldKind::Bool),
+    field("IsTemporaryOutOfStock", "is_temporary_out_of_stock", FieldKind::Bool),
+    field("AlcoholPercentage", "alcohol_percentage", FieldKind::Real),
+    field("Volume", "volume", FieldKind::Real),
+    field("Price", "price", FieldKind::Real),
+    field("Country", "country", FieldKind::Text),
+    field("OriginLevel1", "origin_level1", FieldKind::Text),
+    field("OriginLevel2", "origin_level2", FieldKind::Text),
+    field("Vintage", "vintage", FieldKind::Int),
+    field("SubCategory", "sub_category", FieldKind::Text),
+    field("Type", "a_type", FieldKind::Text),
+    field("Style", "style", FieldKind::Text),
+    field("AssortmentText", "assortment_text", FieldKind::Text),
+    field("BeverageDescriptionShort", "beverage_description_short", FieldKind::Text),
+    field("Usage", "usage_text", FieldKind::Text),
+    field("Taste", "taste", FieldKind::Text),
+    field("Assortment", "assortment", FieldKind::Text),
+    field("IsManufacturingCountry", "is_manufacturing_country", FieldKind::Bool),
+    field("RecycleFee", "recycle_fee", FieldKind::Real),
+    field("IsRegionalRestricted", "is_regional_retricted", FieldKind::Bool),
+    field("IsInStoreSearchAssortment", "is_in_store_search_assortment", FieldKind::Text),
+    field("IsNews", "is_news", FieldKind::Bool),
+    field("Apk", "apk", FieldKind::Real),
+    field("ApkRecycling", "apk_recycling", FieldKind::Real),
+    field("Link", "link", FieldKind::Text),
+    field("ApkRank", "apk_rank", FieldKind::Int),
+    field("ApkPercentile", "apk_percentile", FieldKind::Real),
+    field("CategoryRank", "category_rank", FieldKind::Int),
+    field("CategoryPercentile", "category_percentile", FieldKind::Real),
+    field("SubCategoryRank", "sub_category_rank", FieldKind::Int),
+    field("SubCategoryPercentile", "sub_category_percentile", FieldKind::Real),
+    field("VolumeBucket", "volume_bucket", FieldKind::Text),
+    field("VolumeBucketRank", "volume_bucket_rank", FieldKind::Int),
+    field("VolumeBucketPercentile", "volume_bucket_percentile", FieldKind::Real),
+];
+
+/// What a ranking list needs, `view=compact`.
+const COMPACT_FIELDS: &[&str] = &["ProductId", "ProductNameBold", "ProductNameThin", "Category", "Price", "Volume",
+    "AlcoholPercentage", "Apk", "ApkRecycling", "Link"];
+
+pub fn product_field(name: &str) -> Option<&'static ProductField> {
+    PRODUCT_FIELDS.iter().find(|f| f.name == name)
+}
+
+/// Fields to select for a comma separated `fields` list or a named `view`, `fields` wins if both are given.
+/// `None` means the full product.
+pub fn projection(fields: &str, view: &str) -> Result<Option<Vec<&'static ProductField>>> {
+    let wanted: Vec<&str> = fields.split(',')
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .collect();
+    if !wanted.is_empty() {
+        let mut selected = Vec::with_capacity(wanted.len());
+        for name in wanted {
+            let field = product_field(name)
+                .ok_or_else(|| ErrorKind::InvalidParameter(String::from("fields"), name.to_string()))?;
+            if !selected.contains(&field) {
+                selected.push(field);
+            }
+        }
+        return Ok(Some(selected));
+    }
+    match view.trim() {
+        "" | "full" => Ok(None),
+        "compact" => Ok(Some(COMPACT_FIELDS.iter().filter_map(|name| product_field(name)).collect())),
+        other => Err(ErrorKind::InvalidParameter(String::from("view"), other.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::product::Product;
+    use serde_json::Value;
+
+    #[test]
+    fn test_fields_match_product() {
+        let serialized = match serde_json::to_value(Product::default()).unwrap() {
+            Value::Object(map) => map.keys().cloned().collect::<Vec<String>>(),
+            _ => panic!("Product doesn't serialize to an object"),
+        };
+        let names: Vec<String> = PRODUCT_FIELDS.iter().map(|f| f.name.to_string()).collect();
+        assert_eq!(serialized, names);
+    }
+
+    #[test]
+    fn test_projection() {
+        assert_eq!(None, projection("", "").unwrap());
+        assert_eq!(None, projection(" ", "full").unwrap());
+        let compact = projection("", "compact").unwrap().unwrap();
+        assert_eq!(COMPACT_FIELDS.len(), compact.len());
+        let picked = projection("Price, ProductId,Price", "compact").unwrap().unwrap();
+        assert_eq!(vec!["price", "product_id"], picked.iter().map(|f| f.column).collect::<Vec<&str>>());
+        assert!(projection("Price,Nope", "").is_err());
+        assert!(projection("", "tiny").is_err());
+    }
+}
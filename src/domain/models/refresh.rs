@@ -0,0 +1,155 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Formatter;
+use super::drift::DriftReport;
+use super::product::MinimalSite;
+
+/// Outcome of loading the store assortment junction rows.
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct LoadStats {
+    #[serde(rename="Inserted")]
+    pub inserted: usize,
+    #[serde(rename="UnknownProduct")]
+    pub unknown_product: usize,
+    #[serde(rename="UnknownSite")]
+    pub unknown_site: usize,
+    #[serde(rename="Duplicate")]
+    pub duplicate: usize,
+    #[serde(rename="Failed")]
+    pub failed: usize,
+}
+
+impl LoadStats {
+    pub fn rejected(&self) -> usize {
+        self.unknown_product + self.unknown_site + self.duplicate + self.failed
+    }
+}
+
+/// Outcome of the last refresh, one drift report per upstream payload.
+#[derive(Debug, Serialize, Clone)]
+pub struct RefreshSummary {
+    #[serde(rename="FinishedAt")]
+    pub finished_at: DateTime<Utc>,
+    #[serde(rename="SnapshotVersion")]
+    pub snapshot_version: i64,
+    #[serde(rename="Drift")]
+    pub drift: bool,
+    #[serde(rename="Upstream")]
+    pub upstream: Vec<DriftReport>,
+    #[serde(rename="Load")]
+    pub load: LoadStats,
+}
+
+impl RefreshSummary {
+    pub fn new(upstream: Vec<DriftReport>, load: LoadStats, snapshot_version: i64) -> Self {
+        RefreshSummary {
+            finished_at: Utc::now(),
+            snapshot_version,
+            drift: upstream.iter().any(|r| r.has_drift()),
+            upstream,
+            load,
+        }
+    }
+}
+
+impl std::fmt::Display for RefreshSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let skipped: Vec<String> = self.upstream.iter().map(|r| format!("{}={}", r.model, r.skipped)).collect();
+        write!(f, "RefreshSummary(snapshot_version={}, drift={}, skipped=[{}], load={})",
+               self.snapshot_version, self.drift, skipped.join(", "), self.load)
+    }
+}
+
+/// A store assortment row as it's stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JunctionRow<'a> {
+    pub product_id: &'a str,
+    pub site_id: &'a str,
+    pub stock: Option<i32>,
+    pub shelf: Option<&'a str>,
+    pub last_seen: Option<NaiveDateTime>,
+}
+
+/// `(product_id, site_id)` of the listings reported without stock, their `last_seen` is carried over from the
+/// previous snapshot so it keeps telling when the store last had them.
+pub fn out_of_stock(mapping: &[MinimalSite]) -> Vec<(&str, &str)> {
+    mapping.iter()
+        .flat_map(|site| site.products.iter()
+            .filter(|p| p.stock == Some(0))
+            .map(move |p| (p.product_id.as_str(), site.site_id.as_str())))
+        .collect()
+}
+
+/// Junction rows whose product and site are both loaded, sorted by primary key.
+/// Rows pointing at an unknown product or site are counted in `stats` and dropped.
+/// Listings in stock, or whose stock isn't reported, are seen at `seen_at`, the others keep their `previous` time.
+pub fn junction_rows<'a>(mapping: &'a [MinimalSite], products: &HashSet<String>, sites: &HashSet<String>,
+                         seen_at: NaiveDateTime, previous: &HashMap<(String, String), NaiveDateTime>,
+                         stats: &mut LoadStats) -> Vec<JunctionRow<'a>> {
+    let mut rows = Vec::new();
+    for site in mapping {
+        if !sites.contains(site.site_id.as_str()) {
+            debug!("Skipping {} junctions for unknown site_id={}", site.products.len(), site.site_id);
+            stats.unknown_site += site.products.len();
+            continue;
+        }
+        for prod in &site.products {
+            if !products.contains(prod.product_id.as_str()) {
+                stats.unknown_product += 1;
+                continue;
+            }
+            let last_seen = if prod.stock == Some(0) {
+                previous.get(&(prod.product_id.to_string(), site.site_id.to_string())).copied()
+            } else {
+                Some(seen_at)
+            };
+            rows.push(JunctionRow {
+                product_id: prod.product_id.as_str(),
+                site_id: site.site_id.as_str(),
+                stock: prod.stock,
+                shelf: prod.shelf.as_deref(),
+                last_seen,
+            });
+        }
+    }
+    rows.sort_unstable_by(|a, b| (a.product_id, a.site_id).cmp(&(b.product_id, b.site_id)));
+    rows
+}
+
+impl std::fmt::Display for LoadStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LoadStats(inserted={}, rejected={}, unknown_product={}, unknown_site={}, duplicate={}, failed={})",
+               self.inserted, self.rejected(), self.unknown_product, self.unknown_site, self.duplicate, self.failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::domain::models::fixtures::minimal_site;
+
+    #[test]
+    fn test_last_seen_kept_while_out_of_stock() {
+        let mut centrum = minimal_site("0102", &["2", "1", "3", "9"]);
+        centrum.products[0].stock = Some(0);
+        centrum.products[1].stock = Some(4);
+        centrum.products[2].stock = Some(0);
+        let mapping = vec![centrum];
+        assert_eq!(vec![("2", "0102"), ("3", "0102")], out_of_stock(&mapping));
+
+        let keys = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<HashSet<String>>();
+        let earlier = NaiveDate::from_ymd(2020, 12, 1).and_hms(10, 0, 0);
+        let now = NaiveDate::from_ymd(2020, 12, 2).and_hms(10, 0, 0);
+        let previous = vec![((String::from("2"), String::from("0102")), earlier)].into_iter().collect();
+        let mut stats = LoadStats::default();
+        let rows = junction_rows(&mapping, &keys(&["1", "2", "3"]), &keys(&["0102"]), now, &previous, &mut stats);
+        let seen: Vec<(&str, Option<i32>, Option<NaiveDateTime>)> = rows.iter()
+            .map(|r| (r.product_id, r.stock, r.last_seen))
+            .collect();
+        // 2 had stock in an earlier snapshot, 3 never had any
+        assert_eq!(vec![("1", Some(4), Some(now)), ("2", Some(0), Some(earlier)), ("3", Some(0), None)], seen);
+        assert_eq!(1, stats.unknown_product);
+    }
+}
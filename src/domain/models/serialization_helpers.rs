@@ -1,5 +1,107 @@
-use serde::{Deserializer, Deserialize};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserializer, Deserialize, Serialize};
+use serde::de::Error;
+use serde_json::Value;
+
 pub fn nullable_string<'de, D>(deserializer: D) -> Result<String, D::Error> where D: Deserializer<'de> {
     let opt = Option::deserialize(deserializer)?;
     Ok(opt.unwrap_or("".to_string()))
 }
+
+/// `None` for null or an empty string, anything else must be text.
+pub fn optional_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error> where D: Deserializer<'de> {
+    let opt: Option<String> = Option::deserialize(deserializer)?;
+    Ok(opt.filter(|s| !s.is_empty()))
+}
+
+/// Upstream dates are sent as midnight datetimes, `2020-10-16T00:00:00`, a bare date is accepted as well.
+pub fn date_or_datetime<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error> where D: Deserializer<'de> {
+    let source = String::deserialize(deserializer)?;
+    parse_date(&source).ok_or_else(|| D::Error::custom(format!("invalid date {:?}", source)))
+}
+
+/// Null or an empty string is a missing datetime, a malformed one is an error.
+pub fn optional_datetime<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error>
+    where D: Deserializer<'de> {
+    match optional_string(deserializer)? {
+        None => Ok(None),
+        Some(source) => parse_datetime(&source)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid datetime {:?}", source))),
+    }
+}
+
+/// Hours as `10:00:00` or `10:00`, null or an empty string is a missing time.
+pub fn optional_time<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error> where D: Deserializer<'de> {
+    match optional_string(deserializer)? {
+        None => Ok(None),
+        Some(source) => parse_time(&source)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid time {:?}", source))),
+    }
+}
+
+/// Text formats dates and times are stored in, the same as they serialize to.
+pub const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+pub const TIME_FORMAT: &str = "%H:%M:%S";
+
+pub fn parse_date(source: &str) -> Option<NaiveDate> {
+    source.get(..10).and_then(|d| NaiveDate::parse_from_str(d, DATE_FORMAT).ok())
+}
+
+pub fn parse_datetime(source: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(source, "%Y-%m-%dT%H:%M:%S%.f").ok()
+        .or_else(|| parse_date(source).filter(|_| source.len() == 10).map(|d| d.and_hms(0, 0, 0)))
+}
+
+pub fn parse_time(source: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(source, TIME_FORMAT)
+        .or_else(|_| NaiveTime::parse_from_str(source, "%H:%M"))
+        .ok()
+}
+
+/// Serializes `items` keeping only the comma separated `fields`, every field is kept if `fields` is empty.
+pub fn select_fields<T: Serialize>(items: &[T], fields: &str) -> serde_json::Result<Vec<Value>> {
+    let wanted: Vec<&str> = fields.split(',')
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .collect();
+    let mut selected = Vec::with_capacity(items.len());
+    for item in items {
+        let mut value = serde_json::to_value(item)?;
+        if let (false, Value::Object(map)) = (wanted.is_empty(), &mut value) {
+            map.retain(|k, _| wanted.contains(&k.as_str()));
+        }
+        selected.push(value);
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::site;
+
+    #[test]
+    fn test_select_fields() {
+        let sites = vec![site("0102", "Centrum")];
+        let all = select_fields(&sites, "").unwrap();
+        assert!(all[0].get("Position").is_some());
+        let some = select_fields(&sites, "SiteId, Name,Unknown").unwrap();
+        assert_eq!(serde_json::json!([{"SiteId": "0102", "Name": "Centrum"}]), Value::Array(some));
+    }
+
+    #[test]
+    fn test_parse_dates_and_times() {
+        assert_eq!(Some(NaiveDate::from_ymd(2020, 10, 16)), parse_date("2020-10-16T00:00:00"));
+        assert_eq!(Some(NaiveDate::from_ymd(2020, 10, 16)), parse_date("2020-10-16"));
+        assert_eq!(None, parse_date("16/10/2020"));
+        assert_eq!(Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0)), parse_datetime("2020-01-01"));
+        assert_eq!(Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(12, 0, 0)), parse_datetime("2020-01-01T12:00:00"));
+        assert_eq!(None, parse_datetime("2020-01-01 garbage"));
+        assert_eq!(Some(NaiveTime::from_hms(10, 0, 0)), parse_time("10:00"));
+        assert_eq!(Some(NaiveTime::from_hms(22, 30, 15)), parse_time("22:30:15"));
+        assert_eq!(None, parse_time("late"));
+    }
+}
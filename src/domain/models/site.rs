@@ -1,25 +1,32 @@
 use serde::{Serialize, Deserialize, Deserializer};
-use crate::domain::models::serialization_helpers::nullable_string;
+use crate::domain::models::ids::{ProductId, SiteId};
+use crate::domain::models::serialization_helpers::{nullable_string, optional_string, date_or_datetime, optional_time};
+use crate::domain::models::product::Product;
+use crate::domain::result;
+use crate::domain::result::ErrorKind;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc, Duration};
+use chrono_tz::Europe::Stockholm;
+use chrono_tz::Tz;
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
+#[derive(Debug, Serialize, Clone, Deserialize, Default)]
 pub struct Site {
-    #[serde(rename="SiteId", deserialize_with="nullable_string")]
-    pub site_id: String,
+    #[serde(rename="SiteId")]
+    pub site_id: SiteId,
     #[serde(rename="IsTastingStore")]
     pub is_tasting_store: bool,
-    #[serde(rename="Alias", deserialize_with="nullable_string")]
-    pub alias: String,
-    #[serde(rename="Address", deserialize_with="nullable_string")]
+    #[serde(rename="Alias", default, deserialize_with="optional_string")]
+    pub alias: Option<String>,
+    #[serde(rename="Address", default, deserialize_with="nullable_string")]
     pub address: String,
-    #[serde(rename="DisplayName", deserialize_with="nullable_string")]
-    pub display_name: String,
-    #[serde(rename="PostalCode", deserialize_with="nullable_string")]
+    #[serde(rename="DisplayName", default, deserialize_with="optional_string")]
+    pub display_name: Option<String>,
+    #[serde(rename="PostalCode", default, deserialize_with="nullable_string")]
     pub postal_code: String,
-    #[serde(rename="City", deserialize_with="nullable_string")]
+    #[serde(rename="City", default, deserialize_with="nullable_string")]
     pub city: String,
-    #[serde(rename="County", deserialize_with="nullable_string")]
+    #[serde(rename="County", default, deserialize_with="nullable_string")]
     pub county: String,
-    #[serde(rename="Country", deserialize_with="nullable_string")]
+    #[serde(rename="Country", default, deserialize_with="nullable_string")]
     pub country: String,
     #[serde(rename="IsStore")]
     pub is_store: bool,
@@ -27,19 +34,20 @@ pub struct Site {
     pub is_agent: bool,
     #[serde(rename="IsActiveForAgentOrder")]
     pub is_active_for_agent_order: bool,
-    #[serde(rename="Phone", deserialize_with="nullable_string")]
-    pub phone: String,
-    #[serde(rename="Email", deserialize_with="nullable_string")]
-    pub email: String,
-    #[serde(rename="Services", deserialize_with="nullable_string")]
-    pub services: String,
-    #[serde(rename="OpeningHours", deserialize_with="empty_opening")]
+    #[serde(rename="Phone", default, deserialize_with="optional_string")]
+    pub phone: Option<String>,
+    #[serde(rename="Email", default, deserialize_with="optional_string")]
+    pub email: Option<String>,
+    #[serde(rename="Services", default, deserialize_with="optional_string")]
+    pub services: Option<String>,
+    #[serde(rename="OpeningHours", default, deserialize_with="empty_opening")]
     pub opening_hours: Vec<OpeningTime>,
-    #[serde(rename="Depot", deserialize_with="nullable_string")]
-    pub depot: String,
-    #[serde(rename="Name", deserialize_with="nullable_string")]
-    pub name: String,
-    #[serde(rename="Position", deserialize_with="empty_position")]
+    #[serde(rename="Depot", default, deserialize_with="optional_string")]
+    pub depot: Option<String>,
+    /// Agents and some stores have no name.
+    #[serde(rename="Name", default, deserialize_with="optional_string")]
+    pub name: Option<String>,
+    #[serde(rename="Position", default, deserialize_with="empty_position")]
     pub position: Position,
 }
 
@@ -47,17 +55,17 @@ pub struct Site {
 pub struct OpeningTime {
     #[serde(rename="IsOpen")]
     pub is_open: bool,
-    #[serde(rename="Reason", deserialize_with="nullable_string")]
+    #[serde(rename="Reason", default, deserialize_with="nullable_string")]
     pub reason: String,
-    #[serde(rename="Date", deserialize_with="nullable_string")]
-    pub date: String,
-    #[serde(rename="OpenFrom", deserialize_with="nullable_string")]
-    pub open_from: String,
-    #[serde(rename="OpenTo", deserialize_with="nullable_string")]
-    pub open_to: String,
+    #[serde(rename="Date", deserialize_with="date_or_datetime")]
+    pub date: NaiveDate,
+    #[serde(rename="OpenFrom", default, deserialize_with="optional_time")]
+    pub open_from: Option<NaiveTime>,
+    #[serde(rename="OpenTo", default, deserialize_with="optional_time")]
+    pub open_to: Option<NaiveTime>,
 }
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
+#[derive(Debug, Serialize, Clone, Deserialize, Default)]
 pub struct Position {
     #[serde(rename="Lat")]
     pub lat: f64,
@@ -65,6 +73,181 @@ pub struct Position {
     pub long: f64,
 }
 
+impl Site {
+    /// Whether the site is open at `time`, evaluated in Swedish local time.
+    /// Days without an opening entry are treated as closed.
+    pub fn is_open_at<T: TimeZone>(&self, time: &DateTime<T>) -> bool {
+        let local = time.with_timezone(&Stockholm).naive_local();
+        self.opening_hours.iter()
+            .filter_map(|o| o.interval())
+            .any(|(from, to)| from <= local && local < to)
+    }
+
+    /// The upstream reason for the site being closed on the local date of `time`, eg. a holiday.
+    pub fn closed_reason_at<T: TimeZone>(&self, time: &DateTime<T>) -> Option<&str> {
+        let date = time.with_timezone(&Stockholm).naive_local().date();
+        self.opening_hours.iter()
+            .find(|o| !o.is_open && o.date == date && !o.reason.is_empty())
+            .map(|o| o.reason.as_str())
+    }
+
+    /// The first opening strictly after `time`, `None` if no later opening is known.
+    pub fn next_opening<T: TimeZone>(&self, time: &DateTime<T>) -> Option<DateTime<Tz>> {
+        let local = time.with_timezone(&Stockholm).naive_local();
+        self.opening_hours.iter()
+            .filter_map(|o| o.interval())
+            .map(|(from, _)| from)
+            .filter(|from| *from > local)
+            .min()
+            .and_then(|from| Stockholm.from_local_datetime(&from).earliest())
+    }
+}
+
+/// A site as of the `open_now`/`open_at` time of a site query.
+#[derive(Debug, Serialize, Clone)]
+pub struct SiteOpening {
+    #[serde(flatten)]
+    pub site: Site,
+    #[serde(rename="IsOpen")]
+    pub is_open: bool,
+    /// Set when the site is closed for a reason the upstream gives, eg. a holiday.
+    #[serde(rename="ClosedReason")]
+    pub closed_reason: Option<String>,
+    /// The first opening after the time, in Swedish local time.
+    #[serde(rename="NextOpening")]
+    pub next_opening: Option<DateTime<FixedOffset>>,
+}
+
+impl SiteOpening {
+    pub fn at<T: TimeZone>(site: Site, time: &DateTime<T>) -> SiteOpening {
+        let is_open = site.is_open_at(time);
+        let closed_reason = if is_open { None } else { site.closed_reason_at(time).map(String::from) };
+        let next_opening = site.next_opening(time).map(|t| t.with_timezone(&t.offset().fix()));
+        SiteOpening { site, is_open, closed_reason, next_opening }
+    }
+}
+
+impl OpeningTime {
+    /// Local opening interval, a closing time at or before the opening time means closing after midnight.
+    fn interval(&self) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        if !self.is_open {
+            return None;
+        }
+        let from = self.date.and_time(self.open_from?);
+        let mut to = self.date.and_time(self.open_to?);
+        if to <= from {
+            to += Duration::days(1);
+        }
+        Some((from, to))
+    }
+}
+
+/// Resolves the `open_now`/`open_at` query parameters, `open_at` is either unix seconds or RFC 3339.
+pub fn open_time(open_now: bool, open_at: &str) -> result::Result<Option<DateTime<Utc>>> {
+    if !open_at.is_empty() {
+        let parsed = match open_at.parse::<i64>() {
+            Ok(secs) => Utc.timestamp_opt(secs, 0).single(),
+            Err(_) => DateTime::parse_from_rfc3339(open_at).ok().map(|t| t.with_timezone(&Utc)),
+        };
+        return parsed
+            .map(Some)
+            .ok_or_else(|| ErrorKind::InvalidParameter(String::from("open_at"), open_at.to_string()).into());
+    }
+    if open_now {
+        Ok(Some(Utc::now()))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SiteOpts {
+    #[serde(default)]
+    pub site_id: SiteId,
+
+    #[serde(default)]
+    pub city: String,
+
+    #[serde(default)]
+    pub county: String,
+
+    #[serde(default)]
+    pub postal_code: String,
+
+    pub is_store: Option<bool>,
+
+    pub is_agent: Option<bool>,
+
+    pub is_tasting_store: Option<bool>,
+
+    /// Free text matched against address and alias.
+    #[serde(default)]
+    pub q: String,
+
+    #[serde(default)]
+    pub open_now: bool,
+
+    #[serde(default)]
+    pub open_at: String,
+
+    /// Keeps sites closed at the `open_now`/`open_at` time, with their `ClosedReason` and `NextOpening`.
+    #[serde(default)]
+    pub include_closed: bool,
+
+    /// Comma separated serialized field names, eg. `SiteId,Name,City`, empty for every field.
+    #[serde(default)]
+    pub fields: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SiteStats {
+    #[serde(rename="SiteId")]
+    pub site_id: SiteId,
+    #[serde(rename="AssortmentSize")]
+    pub assortment_size: i64,
+    #[serde(rename="MedianApk")]
+    pub median_apk: f64,
+    #[serde(rename="BestPerCategory")]
+    pub best_per_category: Vec<CategoryBest>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CategoryBest {
+    #[serde(rename="Category")]
+    pub category: String,
+    #[serde(rename="ProductId")]
+    pub product_id: ProductId,
+    #[serde(rename="ProductNameBold")]
+    pub product_name_bold: String,
+    #[serde(rename="Apk")]
+    pub apk: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SiteComparison {
+    #[serde(rename="SiteA")]
+    pub site_a: SiteId,
+    #[serde(rename="SiteB")]
+    pub site_b: SiteId,
+    #[serde(rename="OnlyA")]
+    pub only_a: Vec<Product>,
+    #[serde(rename="OnlyB")]
+    pub only_b: Vec<Product>,
+    #[serde(rename="Both")]
+    pub both: Vec<Product>,
+}
+
+/// Median of an already sorted slice, 0 for an empty store.
+pub fn median(sorted: &[f64]) -> f64 {
+    let len = sorted.len();
+    if len == 0 {
+        0.0
+    } else if len.is_multiple_of(2) {
+        (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
+    } else {
+        sorted[len / 2]
+    }
+}
 
 fn empty_opening<'de, D>(deserializer: D) -> Result<Vec<OpeningTime>, D::Error> where D: Deserializer<'de> {
     let opt = Option::deserialize(deserializer)?;
@@ -74,4 +257,109 @@ fn empty_opening<'de, D>(deserializer: D) -> Result<Vec<OpeningTime>, D::Error>
 fn empty_position<'de, D>(deserializer: D) -> Result<Position, D::Error> where D: Deserializer<'de> {
     let opt = Option::deserialize(deserializer)?;
     Ok(opt.unwrap_or(Position {lat: 0.0, long: 0.0}))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opening(date: &str, is_open: bool, reason: &str, open_from: &str, open_to: &str) -> OpeningTime {
+        serde_json::from_value(serde_json::json!({
+            "IsOpen": is_open,
+            "Reason": reason,
+            "Date": format!("{}T00:00:00", date),
+            "OpenFrom": open_from,
+            "OpenTo": open_to,
+        })).unwrap()
+    }
+
+    fn local(date: &str, time: &str) -> DateTime<Tz> {
+        let naive = NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S").unwrap();
+        Stockholm.from_local_datetime(&naive).unwrap()
+    }
+
+    fn store(opening_hours: Vec<OpeningTime>) -> Site {
+        let mut site = crate::domain::models::fixtures::site("0102", "Centrum");
+        site.opening_hours = opening_hours;
+        site
+    }
+
+    #[test]
+    fn test_open_within_hours() {
+        let site = store(vec![opening("2020-10-16", true, "", "10:00:00", "19:00:00")]);
+        assert!(!site.is_open_at(&local("2020-10-16", "09:59:59")));
+        assert!(site.is_open_at(&local("2020-10-16", "10:00:00")));
+        assert!(site.is_open_at(&local("2020-10-16", "18:59:59")));
+        assert!(!site.is_open_at(&local("2020-10-16", "19:00:00")));
+        // No entry for the day means closed
+        assert!(!site.is_open_at(&local("2020-10-17", "12:00:00")));
+    }
+
+    #[test]
+    fn test_holiday() {
+        let site = store(vec![
+            opening("2020-12-24", false, "Julafton", "00:00:00", "00:00:00"),
+            opening("2020-12-25", false, "Juldagen", "00:00:00", "00:00:00"),
+            opening("2020-12-26", true, "", "10:00:00", "15:00:00"),
+        ]);
+        let christmas = local("2020-12-25", "12:00:00");
+        assert!(!site.is_open_at(&christmas));
+        assert_eq!(Some("Juldagen"), site.closed_reason_at(&christmas));
+        assert_eq!(None, site.closed_reason_at(&local("2020-12-26", "12:00:00")));
+        assert_eq!(Some(local("2020-12-26", "10:00:00")), site.next_opening(&christmas));
+
+        let closed = serde_json::to_value(SiteOpening::at(site.clone(), &christmas)).unwrap();
+        assert_eq!(serde_json::json!(false), closed["IsOpen"]);
+        assert_eq!(serde_json::json!("Juldagen"), closed["ClosedReason"]);
+        assert_eq!(serde_json::json!("2020-12-26T10:00:00+01:00"), closed["NextOpening"]);
+        assert_eq!(serde_json::json!("0102"), closed["SiteId"]);
+        let open = SiteOpening::at(site, &local("2020-12-26", "12:00:00"));
+        assert!(open.is_open && open.closed_reason.is_none() && open.next_opening.is_none());
+    }
+
+    #[test]
+    fn test_midnight_edges() {
+        let site = store(vec![
+            opening("2020-10-16", true, "", "10:00", "00:00"),
+            opening("2020-10-17", true, "", "22:00", "02:00"),
+        ]);
+        assert!(site.is_open_at(&local("2020-10-16", "23:59:59")));
+        assert!(!site.is_open_at(&local("2020-10-17", "00:00:00")));
+        assert!(site.is_open_at(&local("2020-10-18", "01:59:59")));
+        assert!(!site.is_open_at(&local("2020-10-18", "02:00:00")));
+        // 21:30 UTC is 23:30 in Stockholm summer time, 22:00 UTC is local midnight
+        assert!(site.is_open_at(&Utc.ymd(2020, 10, 16).and_hms(21, 30, 0)));
+        assert!(!site.is_open_at(&Utc.ymd(2020, 10, 16).and_hms(22, 0, 0)));
+        assert_eq!(Some(local("2020-10-17", "22:00:00")), site.next_opening(&local("2020-10-17", "00:00:00")));
+        assert_eq!(None, site.next_opening(&local("2020-10-17", "22:00:00")));
+    }
+
+    #[test]
+    fn test_open_time() {
+        assert_eq!(None, open_time(false, "").unwrap());
+        assert!(open_time(true, "").unwrap().is_some());
+        assert_eq!(Some(Utc.ymd(2020, 10, 16).and_hms(21, 30, 0)), open_time(false, "1602883800").unwrap());
+        assert_eq!(Some(Utc.ymd(2020, 10, 16).and_hms(21, 30, 0)), open_time(true, "2020-10-16T23:30:00+02:00").unwrap());
+        assert!(open_time(false, "tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_missing_opening_hours() {
+        let mut closed = opening("2020-12-24", false, "Julafton", "", "");
+        assert_eq!(None, closed.open_from);
+        assert!(!store(vec![closed.clone()]).is_open_at(&local("2020-12-24", "12:00:00")));
+        // An open day without hours can't be placed
+        closed.is_open = true;
+        assert!(!store(vec![closed]).is_open_at(&local("2020-12-24", "12:00:00")));
+        assert!(serde_json::from_value::<OpeningTime>(serde_json::json!({
+            "IsOpen": true, "Reason": null, "Date": "24/12", "OpenFrom": "10:00", "OpenTo": "15:00"
+        })).is_err());
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(0.0, median(&[]));
+        assert_eq!(2.0, median(&[1.0, 2.0, 3.0]));
+        assert_eq!(2.5, median(&[1.0, 2.0, 3.0, 4.0]));
+    }
+}
@@ -0,0 +1,28 @@
+use chrono::NaiveDateTime;
+use serde::{Serialize, Deserialize};
+use super::ids::{ProductId, SiteId};
+
+/// One product's listing in one store.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct StockLevel {
+    #[serde(rename="SiteId")]
+    pub site_id: SiteId,
+    #[serde(rename="ProductId")]
+    pub product_id: ProductId,
+    /// `Some(0)` if the product is listed but out of stock, `None` if the upstream doesn't report stock.
+    #[serde(rename="Stock")]
+    pub stock: Option<i32>,
+    #[serde(rename="Shelf")]
+    pub shelf: Option<String>,
+    /// The last refresh that saw the product in stock here, or listed if stock isn't reported.
+    /// Kept across refreshes while the product stays listed without stock, `None` if it never had any.
+    #[serde(rename="LastSeen")]
+    pub last_seen: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct StockOpts {
+    /// See `ProductOpts::min_stock`.
+    #[serde(default)]
+    pub min_stock: i64,
+}
@@ -0,0 +1,206 @@
+use serde::{Serialize, Deserialize};
+use super::ids::{ProductId, SiteId};
+use crate::domain::result::{Result, ErrorKind};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NotifierTarget {
+    #[serde(rename="Kind")]
+    pub kind: NotifierKind,
+    #[serde(rename="Target")]
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum NotifierKind {
+    #[serde(rename="webhook")]
+    Webhook,
+    #[serde(rename="file")]
+    File,
+    #[serde(rename="stdout")]
+    Stdout,
+}
+
+impl NotifierKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifierKind::Webhook => "webhook",
+            NotifierKind::File => "file",
+            NotifierKind::Stdout => "stdout",
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<NotifierKind> {
+        match source {
+            "webhook" => Ok(NotifierKind::Webhook),
+            "file" => Ok(NotifierKind::File),
+            "stdout" => Ok(NotifierKind::Stdout),
+            _ => Err(ErrorKind::InvalidNotifier(source.to_string()).into())
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Watchlist {
+    #[serde(rename="WatchlistId")]
+    pub watchlist_id: i64,
+    #[serde(rename="Name")]
+    pub name: String,
+    #[serde(rename="SiteId")]
+    pub site_id: SiteId,
+    #[serde(rename="Notifier")]
+    pub notifier: NotifierTarget,
+    #[serde(rename="Items")]
+    pub items: Vec<WatchItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatchItem {
+    #[serde(rename="ProductId")]
+    pub product_id: ProductId,
+    #[serde(rename="LastPrice")]
+    pub last_price: Option<f64>,
+    #[serde(rename="LastInStore")]
+    pub last_in_store: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewWatchlist {
+    #[serde(rename="Name")]
+    pub name: String,
+    #[serde(rename="SiteId")]
+    #[serde(default)]
+    pub site_id: SiteId,
+    #[serde(rename="Notifier")]
+    pub notifier: NotifierTarget,
+    #[serde(rename="Products")]
+    #[serde(default)]
+    pub products: Vec<ProductId>,
+}
+
+/// Snapshot of a watched product after a refresh, `price` is `None` when the product is no longer in the catalogue.
+#[derive(Debug, Clone)]
+pub struct WatchState {
+    pub watchlist_id: i64,
+    pub product_id: ProductId,
+    pub last_price: Option<f64>,
+    pub last_in_store: bool,
+    pub price: Option<f64>,
+    pub in_store: bool,
+    pub name: String,
+    pub link: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(tag="Event")]
+pub enum WatchEvent {
+    PriceDrop {
+        #[serde(rename="ProductId")]
+        product_id: ProductId,
+        #[serde(rename="Name")]
+        name: String,
+        #[serde(rename="Link")]
+        link: String,
+        #[serde(rename="OldPrice")]
+        old_price: f64,
+        #[serde(rename="NewPrice")]
+        new_price: f64,
+    },
+    InStore {
+        #[serde(rename="ProductId")]
+        product_id: ProductId,
+        #[serde(rename="Name")]
+        name: String,
+        #[serde(rename="Link")]
+        link: String,
+        #[serde(rename="SiteId")]
+        site_id: SiteId,
+    },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Notification {
+    #[serde(rename="WatchlistId")]
+    pub watchlist_id: i64,
+    #[serde(rename="WatchlistName")]
+    pub watchlist_name: String,
+    #[serde(rename="Events")]
+    pub events: Vec<WatchEvent>,
+}
+
+impl WatchState {
+    /// Compares the state recorded at the previous refresh (or at creation) with the current one.
+    /// A product without a previous price can't have dropped in price, only appeared in store.
+    pub fn evaluate(&self, site_id: &SiteId) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+        if let (Some(old_price), Some(new_price)) = (self.last_price, self.price) {
+            if new_price < old_price {
+                events.push(WatchEvent::PriceDrop {
+                    product_id: self.product_id.clone(),
+                    name: self.name.clone(),
+                    link: self.link.clone(),
+                    old_price,
+                    new_price,
+                });
+            }
+        }
+        if self.in_store && !self.last_in_store {
+            events.push(WatchEvent::InStore {
+                product_id: self.product_id.clone(),
+                name: self.name.clone(),
+                link: self.link.clone(),
+                site_id: site_id.clone(),
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(last_price: Option<f64>, last_in_store: bool, price: Option<f64>, in_store: bool) -> WatchState {
+        WatchState {
+            watchlist_id: 1,
+            product_id: "1001".into(),
+            last_price,
+            last_in_store,
+            price,
+            in_store,
+            name: String::from("Norrlands Guld"),
+            link: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_price_drop() {
+        let events = state(Some(20.0), true, Some(18.5), true).evaluate(&"".into());
+        assert_eq!(1, events.len());
+        match &events[0] {
+            WatchEvent::PriceDrop { old_price, new_price, .. } => {
+                assert_eq!(20.0, *old_price);
+                assert_eq!(18.5, *new_price);
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert!(state(Some(20.0), true, Some(21.0), true).evaluate(&"".into()).is_empty());
+    }
+
+    #[test]
+    fn test_appears_in_store() {
+        let events = state(Some(20.0), false, Some(20.0), true).evaluate(&"0102".into());
+        assert_eq!(vec![WatchEvent::InStore {
+            product_id: "1001".into(),
+            name: String::from("Norrlands Guld"),
+            link: String::new(),
+            site_id: "0102".into(),
+        }], events);
+    }
+
+    #[test]
+    fn test_missing_price_is_not_a_drop() {
+        assert!(state(None, false, Some(20.0), false).evaluate(&"".into()).is_empty());
+        assert!(state(Some(20.0), false, None, false).evaluate(&"".into()).is_empty());
+    }
+}
@@ -30,6 +30,9 @@ error_chain! {
         Rusqlite(::rusqlite::Error);
         Time(::std::time::SystemTimeError);
         Json(::serde_json::Error);
+        Yaml(::serde_yaml::Error);
+        Xlsx(::rust_xlsxwriter::XlsxError);
+        Postgres(::tokio_postgres::Error) #[cfg(feature = "postgres")];
     }
 
     // Define additional `ErrorKind` variants.  Define custom responses with the
@@ -46,6 +49,46 @@ error_chain! {
             description("unknown toolchain version"), // note the ,
             display("unknown toolchain version: '{}'", v), // trailing comma is allowed
         }
+
+        InvalidNotifier(k: String) {
+            description("invalid notifier kind")
+            display("invalid notifier kind: '{}'", k)
+        }
+
+        InvalidParameter(name: String, value: String) {
+            description("invalid request parameter")
+            display("invalid value for parameter '{}': '{}'", name, value)
+        }
+
+        InvalidFormula(formula: String, reason: String) {
+            description("invalid score formula")
+            display("invalid formula '{}': {}", formula, reason)
+        }
+
+        NotFound(what: String) {
+            description("not found")
+            display("no {} found", what)
+        }
+
+        UnsupportedBackend(b: String) {
+            description("unsupported storage backend")
+            display("storage backend '{}' is not available in this build", b)
+        }
+
+        Notify(t: String) {
+            description("failed to deliver notification")
+            display("failed to deliver notification to '{}'", t)
+        }
+
+        UpstreamRejected(model: String, records: usize) {
+            description("every upstream record was rejected")
+            display("all {} upstream {} records were rejected", records, model)
+        }
+
+        UpstreamTruncated(search: String, pages: usize) {
+            description("upstream search has more pages than allowed")
+            display("upstream search '{}' continues past {} pages", search, pages)
+        }
     }
 
     // If this annotation is left off, a variant `Msg(s: String)` will be added, and `From`
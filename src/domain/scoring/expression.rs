@@ -0,0 +1,309 @@
+//! Arithmetic over the numeric `Product` fields, e.g. `Apk * if(AlcoholPercentage > 40, 40 / AlcoholPercentage, 1)`.
+//!
+//! Fields are named as in the JSON output. Booleans are 1 or 0, a missing vintage is 0, comparisons are 1 or 0 and
+//! dividing by zero gives 0, so every formula has a value for every product. The functions are `min(a, b)`,
+//! `max(a, b)`, `abs(a)` and `if(condition, then, else)`, where any condition other than 0 is true.
+use serde_json::{Map, Value};
+use crate::domain::models::projection::{product_field, FieldKind, ProductField};
+use crate::domain::result::{Result, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Min,
+    Max,
+    Abs,
+    If,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<(Function, usize)> {
+        match name {
+            "min" => Some((Function::Min, 2)),
+            "max" => Some((Function::Max, 2)),
+            "abs" => Some((Function::Abs, 1)),
+            "if" => Some((Function::If, 3)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    /// Always a `Real`, `Int` or `Bool` field.
+    Field(&'static ProductField),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    /// Holds exactly the arguments the function takes.
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr> {
+        let mut parser = Parser { source, tokens: tokenize(source)?, position: 0 };
+        let expr = parser.comparison()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(parser.error(&format!("unexpected {:?}", token))),
+        }
+    }
+
+    /// Value for a product serialized to a JSON object.
+    pub fn evaluate(&self, product: &Map<String, Value>) -> f64 {
+        let truth = |b: bool| if b { 1.0 } else { 0.0 };
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Field(field) => match product.get(field.name) {
+                Some(Value::Bool(b)) => truth(*b),
+                Some(Value::Number(n)) => n.as_f64().unwrap_or(0.0),
+                _ => 0.0,
+            },
+            Expr::Neg(e) => -e.evaluate(product),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.evaluate(product), b.evaluate(product));
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => if b == 0.0 { 0.0 } else { a / b },
+                    Op::Lt => truth(a < b),
+                    Op::Le => truth(a <= b),
+                    Op::Gt => truth(a > b),
+                    Op::Ge => truth(a >= b),
+                    Op::Eq => truth(a == b),
+                    Op::Ne => truth(a != b),
+                }
+            }
+            Expr::Call(function, args) => {
+                let arg = |i: usize| args[i].evaluate(product);
+                match function {
+                    Function::Min => arg(0).min(arg(1)),
+                    Function::Max => arg(0).max(arg(1)),
+                    Function::Abs => arg(0).abs(),
+                    Function::If => if arg(0) != 0.0 { arg(1) } else { arg(2) },
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Op(Op),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_digit() || **c == '.').count();
+                let text: String = chars[i..i + len].iter().collect();
+                let number = text.parse::<f64>()
+                    .map_err(|_| invalid(source, &format!("invalid number {:?}", text)))?;
+                (Token::Number(number), len)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').count();
+                (Token::Name(chars[i..i + len].iter().collect()), len)
+            }
+            '+' => (Token::Op(Op::Add), 1),
+            '-' => (Token::Op(Op::Sub), 1),
+            '*' => (Token::Op(Op::Mul), 1),
+            '/' => (Token::Op(Op::Div), 1),
+            '<' if next == Some('=') => (Token::Op(Op::Le), 2),
+            '<' => (Token::Op(Op::Lt), 1),
+            '>' if next == Some('=') => (Token::Op(Op::Ge), 2),
+            '>' => (Token::Op(Op::Gt), 1),
+            '=' if next == Some('=') => (Token::Op(Op::Eq), 2),
+            '!' if next == Some('=') => (Token::Op(Op::Ne), 2),
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            ',' => (Token::Comma, 1),
+            other => return Err(invalid(source, &format!("unexpected {:?}", other))),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+fn invalid(source: &str, reason: &str) -> crate::domain::result::Error {
+    ErrorKind::InvalidFormula(source.to_string(), reason.to_string()).into()
+}
+
+/// Recursive descent, loosest first: one optional comparison, then `+ -`, then `* /`, then unary minus.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, reason: &str) -> crate::domain::result::Error {
+        invalid(self.source, reason)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            Some(token) => Err(self.error(&format!("expected {:?}, found {:?}", expected, token))),
+            None => Err(self.error(&format!("expected {:?} at the end", expected))),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let left = self.additive()?;
+        match self.peek() {
+            Some(Token::Op(op)) if !matches!(op, Op::Add | Op::Sub | Op::Mul | Op::Div) => {
+                let op = *op;
+                self.position += 1;
+                Ok(Expr::Binary(op, Box::new(left), Box::new(self.additive()?)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn additive(&mut self) -> Result<Expr> {
+        let mut expr = self.term()?;
+        while let Some(Token::Op(op @ (Op::Add | Op::Sub))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(Token::Op(op @ (Op::Mul | Op::Div))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if let Some(Token::Op(Op::Sub)) = self.peek() {
+            self.position += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Open) => {
+                let expr = self.comparison()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Open) => {
+                let (function, arity) = Function::parse(&name)
+                    .ok_or_else(|| self.error(&format!("unknown function {}", name)))?;
+                self.position += 1;
+                let mut args = vec![self.comparison()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    args.push(self.comparison()?);
+                }
+                self.expect(Token::Close)?;
+                if args.len() != arity {
+                    return Err(self.error(&format!("{} takes {} arguments, got {}", name, arity, args.len())));
+                }
+                Ok(Expr::Call(function, args))
+            }
+            Some(Token::Name(name)) => match product_field(&name) {
+                Some(field) if field.kind != FieldKind::Text => Ok(Expr::Field(field)),
+                Some(_) => Err(self.error(&format!("{} isn't a number", name))),
+                None => Err(self.error(&format!("unknown field {}", name))),
+            },
+            Some(token) => Err(self.error(&format!("unexpected {:?}", token))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn evaluate(source: &str, product: Value) -> f64 {
+        match product {
+            Value::Object(map) => Expr::parse(source).unwrap().evaluate(&map),
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let product = json!({"Price": 10.0, "Volume": 500.0});
+        assert_eq!(14.0, evaluate("2 + 3 * 4", json!({})));
+        assert_eq!(20.0, evaluate("(2 + 3) * 4", json!({})));
+        assert_eq!(-1.0, evaluate("-Price / 5 + 1", product.clone()));
+        assert_eq!(1.0, evaluate("Volume / 2 > Price * 20 - 1", product.clone()));
+        assert_eq!(0.0, evaluate("10 - 2 - 8", json!({})));
+        assert_eq!(8.0, evaluate("16 / 4 * 2", json!({})));
+    }
+
+    #[test]
+    fn test_fields_and_functions() {
+        let spirit = json!({"Apk": 2.0, "AlcoholPercentage": 80.0, "IsOrganic": true, "Vintage": null});
+        assert_eq!(1.0, evaluate("Apk * if(AlcoholPercentage > 40, 40 / AlcoholPercentage, 1)", spirit.clone()));
+        assert_eq!(3.0, evaluate("Apk + IsOrganic + Vintage", spirit.clone()));
+        assert_eq!(0.0, evaluate("Apk / Vintage", spirit.clone()));
+        assert_eq!(2.0, evaluate("max(min(Apk, 3), abs(-1))", spirit.clone()));
+        assert_eq!(1.0, evaluate("IsOrganic == 1", spirit));
+    }
+
+    #[test]
+    fn test_rejects() {
+        for source in &["", "Apk +", "Apk Price", "(Apk", "Apk)", "Unknown * 2", "Category * 2", "pow(Apk, 2)",
+            "min(Apk)", "Apk = 1", "1.2.3", "Apk < Price < 2", "Apk; DROP TABLE products"] {
+            match Expr::parse(source).map_err(|e| e.kind().to_string()) {
+                Err(message) => assert!(message.starts_with("invalid formula"), "{}", message),
+                Ok(expr) => panic!("{:?} parsed as {:?}", source, expr),
+            }
+        }
+    }
+}
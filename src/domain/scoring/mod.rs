@@ -0,0 +1,162 @@
+//! Named scores products are ranked by, `score=` on product lists. Ranking is done by the database, so a score
+//! has to be expressible as a formula over product fields, see `expression`.
+use std::collections::BTreeMap;
+use crate::config::CONFIG;
+use crate::domain::models::product::Product;
+use crate::domain::result::{Result, ErrorKind};
+
+pub mod expression;
+
+use expression::Expr;
+
+/// Ranks by apk when no score is asked for.
+pub const DEFAULT_SCORE: &str = "apk";
+
+/// Scores available without configuration. A standard drink is 12 grams of alcohol, the deposit is part of the price.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("apk", "Apk"),
+    ("apk_recycling", "ApkRecycling"),
+    ("standard_drinks_per_krona", "Volume * AlcoholPercentage / 100 * 0.789 / 12 / (Price + RecycleFee)"),
+];
+
+lazy_static! {
+    pub static ref SCORES: Scores = Scores::new(&CONFIG.scores);
+}
+
+/// Higher ranks first.
+pub trait Score: Send + Sync {
+    /// What the database computes from `expression`, for scores summed outside of it and to test its ranking.
+    fn evaluate(&self, product: &Product) -> f64;
+
+    /// The same score over product fields, what the database ranks by.
+    fn expression(&self) -> &Expr;
+}
+
+/// A score parsed from its source, the built in ones and those in the config.
+pub struct Formula {
+    expr: Expr,
+}
+
+impl Formula {
+    pub fn parse(source: &str) -> Result<Formula> {
+        Ok(Formula { expr: Expr::parse(source)? })
+    }
+}
+
+impl Score for Formula {
+    fn evaluate(&self, product: &Product) -> f64 {
+        match serde_json::to_value(product) {
+            Ok(serde_json::Value::Object(fields)) => self.expr.evaluate(&fields),
+            _ => 0.0,
+        }
+    }
+
+    fn expression(&self) -> &Expr {
+        &self.expr
+    }
+}
+
+pub struct Scores {
+    scores: BTreeMap<String, Box<dyn Score>>,
+}
+
+impl Scores {
+    /// The built in scores and the `configured` formulas by name. A formula that doesn't parse, or would replace a
+    /// built in score, is left out with an error so one typo doesn't take down the others.
+    pub fn new(configured: &BTreeMap<String, String>) -> Scores {
+        let mut scores = Scores { scores: BTreeMap::new() };
+        for (name, source) in BUILT_IN {
+            scores.register(name, Box::new(Formula::parse(source).expect("Built in scores parse")));
+        }
+        for (name, source) in configured {
+            if scores.scores.contains_key(name) {
+                error!("Ignoring configured score {}, it's built in", name);
+                continue;
+            }
+            match Formula::parse(source) {
+                Ok(formula) => scores.register(name, Box::new(formula)),
+                Err(e) => error!("Ignoring configured score {}: {}", name, e),
+            }
+        }
+        scores
+    }
+
+    fn register(&mut self, name: &str, score: Box<dyn Score>) {
+        self.scores.insert(name.to_string(), score);
+    }
+
+    /// The score named `name`, the default for an empty name.
+    pub fn get(&self, name: &str) -> Result<&dyn Score> {
+        let name = if name.is_empty() { DEFAULT_SCORE } else { name };
+        self.scores.get(name)
+            .map(|score| score.as_ref())
+            .ok_or_else(|| ErrorKind::InvalidParameter(String::from("score"), name.to_string()).into())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.scores.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::product;
+
+    #[test]
+    fn test_configured_scores() {
+        let configured: BTreeMap<String, String> = vec![
+            ("apk", "Price"),
+            ("broken", "Apk +"),
+            ("organic", "Apk + IsOrganic"),
+        ].into_iter().map(|(name, source)| (name.to_string(), source.to_string())).collect();
+        let scores = Scores::new(&configured);
+        assert_eq!(vec!["apk", "apk_recycling", "organic", "standard_drinks_per_krona"], scores.names());
+
+        let mut beer = product("1", "öl", 20.0, 500.0, 5.0);
+        beer.is_organic = true;
+        assert_eq!(beer.apk, scores.get("").unwrap().evaluate(&beer));
+        assert_eq!(beer.apk + 1.0, scores.get("organic").unwrap().evaluate(&beer));
+        assert!(scores.get("broken").is_err());
+    }
+
+    /// A score that isn't a `Formula`, cheaper ranks higher.
+    struct Cheapest(Expr);
+
+    impl Score for Cheapest {
+        fn evaluate(&self, product: &Product) -> f64 {
+            -product.price
+        }
+
+        fn expression(&self) -> &Expr {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_plugged_in_score() {
+        let mut scores = Scores::new(&BTreeMap::new());
+        scores.register("cheapest", Box::new(Cheapest(Expr::parse("Apk").unwrap())));
+        let beer = product("1", "öl", 20.0, 500.0, 5.0);
+        assert_eq!(-20.0, scores.get("cheapest").unwrap().evaluate(&beer));
+        assert!(scores.names().contains(&"cheapest"));
+    }
+
+    #[test]
+    fn test_shipped_config() {
+        let config = crate::config::Config::parse(include_str!("../../../config.yml")).unwrap();
+        assert!(!config.scores.is_empty());
+        for source in config.scores.values() {
+            assert!(Formula::parse(source).is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_standard_drinks() {
+        let mut beer = product("1", "öl", 19.0, 500.0, 5.0);
+        beer.recycle_fee = 1.0;
+        // 19.725 grams of alcohol for 20 kr
+        let drinks = Scores::new(&BTreeMap::new()).get("standard_drinks_per_krona").unwrap().evaluate(&beer);
+        assert!((drinks - 19.725 / 12.0 / 20.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,140 @@
+//! Product page URLs on systembolaget.se, `/dryck/{category}/{name}-{product number}`.
+use std::collections::HashSet;
+use crate::domain::models::product::Product;
+
+pub const SITE: &str = "https://www.systembolaget.se";
+
+/// Category segments that aren't the slug of the category. Wines are plural on the site, singular in the apis,
+/// and the search api files every wine under `Vin` with the kind in the sub category.
+const CATEGORY_SEGMENTS: &[(&str, &str)] = &[
+    ("rött vin", "roda-viner"),
+    ("vitt vin", "vita-viner"),
+    ("rosévin", "roseviner"),
+    ("mousserande vin", "mousserande-viner"),
+];
+
+/// Generic categories whose page is picked by the sub category.
+const BY_SUB_CATEGORY: &[&str] = &["vin"];
+
+/// Lowercase ASCII letters and digits separated by single hyphens. Letters are transliterated, `ö` to `o` and
+/// `æ` to `ae`, apostrophes and quotes are dropped and any other run of symbols or whitespace is one hyphen.
+pub fn slug(source: &str) -> String {
+    let ascii = unidecode::unidecode(&source.to_lowercase());
+    let mut slug = String::with_capacity(ascii.len());
+    let mut separated = false;
+    for c in ascii.chars() {
+        if c.is_ascii_alphanumeric() {
+            if separated && !slug.is_empty() {
+                slug.push('-');
+            }
+            separated = false;
+            slug.push(c.to_ascii_lowercase());
+        } else if !matches!(c, '\'' | '"' | '`') {
+            separated = true;
+        }
+    }
+    slug
+}
+
+/// `None` if the product has no category to file it under.
+pub fn category_segment(category: &str, sub_category: &str) -> Option<String> {
+    let known = |name: &str| {
+        let name = name.trim().to_lowercase();
+        CATEGORY_SEGMENTS.iter().find(|(c, _)| *c == name).map(|(_, segment)| segment.to_string())
+    };
+    if BY_SUB_CATEGORY.contains(&category.trim().to_lowercase().as_str()) {
+        if let Some(segment) = known(sub_category) {
+            return Some(segment);
+        }
+    }
+    known(category).or_else(|| Some(slug(category)).filter(|s| !s.is_empty()))
+}
+
+/// The product's page, or a search for its number if it can't be placed in a category.
+pub fn product_url(product: &Product) -> String {
+    let number = slug(product.product_number.as_str());
+    let name = slug(&format!("{} {}", product.product_name_bold, product.product_name_thin.as_deref().unwrap_or("")));
+    match category_segment(&product.category, product.sub_category.as_deref().unwrap_or("")) {
+        Some(category) if !number.is_empty() => {
+            let page = if name.is_empty() { number } else { format!("{}-{}", name, number) };
+            format!("{}/dryck/{}/{}", SITE, category, page)
+        }
+        _ => search_url(if number.is_empty() { &name } else { &number }),
+    }
+}
+
+/// Site search, lists every product matching `query`.
+pub fn search_url(query: &str) -> String {
+    format!("{}/sok/?textQuery={}", SITE, slug(query))
+}
+
+/// Sets every product's link, the upstream sends none so each is derived with `product_url`.
+/// Each page is given to the first product claiming it, the rest link a search for their number instead.
+/// Returns the number of products that collided.
+pub fn assign_links(products: &mut [Product]) -> usize {
+    let mut taken = HashSet::with_capacity(products.len());
+    let mut collisions = 0;
+    for product in products.iter_mut() {
+        product.link = product_url(product);
+        if !taken.insert(product.link.clone()) {
+            debug!("Link {} is already taken, product_id={} links a search instead", product.link, product.product_id);
+            collisions += 1;
+            product.link = search_url(product.product_number.as_str());
+        }
+    }
+    collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::fixtures::product;
+
+    #[test]
+    fn test_category_segment() {
+        assert_eq!(Some("roda-viner"), category_segment("Rött vin", "").as_deref());
+        assert_eq!(Some("roda-viner"), category_segment("Vin", "Rött vin").as_deref());
+        assert_eq!(Some("vin"), category_segment("Vin", "Portvin").as_deref());
+        assert_eq!(Some("aperitif-dessert"), category_segment("Aperitif & dessert", "Portvin").as_deref());
+        assert_eq!(None, category_segment(" ", ""));
+    }
+
+    #[test]
+    fn test_product_url() {
+        let mut p = product("1", "Sprit", 1.0, 1.0, 1.0);
+        p.product_name_bold = String::from("Jack Daniel's");
+        p.product_name_thin = Some(String::from("Old No. 7"));
+        p.product_number = "566".into();
+        assert_eq!("https://www.systembolaget.se/dryck/sprit/jack-daniels-old-no-7-566", product_url(&p));
+        p.product_name_bold = String::from("***");
+        p.product_name_thin = None;
+        assert_eq!("https://www.systembolaget.se/dryck/sprit/566", product_url(&p));
+        p.category = String::new();
+        assert_eq!("https://www.systembolaget.se/sok/?textQuery=566", product_url(&p));
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!("sju-komma-tvaan-roseviner", slug("Sju komma två'an roséviner"));
+        assert_eq!("a-b", slug("  A  &  B  "));
+        assert_eq!("", slug("***"));
+        let slugged = slug("Ærøskøbing Þór's \"Öl\" / Weißbier 5.5%");
+        assert_eq!("aeroskobing-thors-ol-weissbier-5-5", slugged);
+        assert!(slugged.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+    }
+
+    #[test]
+    fn test_assign_links() {
+        let mut stale = product("2", "öl", 1.0, 1.0, 1.0);
+        stale.link = String::from("javascript:alert(1)");
+        let mut twin = product("3", "öl", 1.0, 1.0, 1.0);
+        twin.product_name_bold = String::from("Product 2");
+        twin.product_number = stale.product_number.clone();
+        let mut products = vec![product("1", "öl", 1.0, 1.0, 1.0), stale, twin];
+
+        assert_eq!(1, assign_links(&mut products));
+        assert_eq!("https://www.systembolaget.se/dryck/ol/product-1-101", products[0].link);
+        assert_eq!("https://www.systembolaget.se/dryck/ol/product-2-201", products[1].link);
+        assert_eq!("https://www.systembolaget.se/sok/?textQuery=201", products[2].link);
+    }
+}
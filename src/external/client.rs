@@ -1,6 +1,8 @@
+use async_trait::async_trait;
 use reqwest;
-use serde_json;
-use crate::domain::models::product::{Product, MinimalSite};
+use super::{ApiCaller, Upstream};
+use crate::domain::models::product::{Product, MinimalSite, DERIVED_FIELDS};
+use crate::domain::models::drift::{DriftReport, parse_records};
 use crate::domain::arithmetic::{get_apk, get_recyc_apk};
 use crate::domain::models::site::Site;
 use crate::domain::result::Result;
@@ -19,17 +21,22 @@ static HEADER_VAL: &str = "";
 
 
 
-pub struct ApiCaller {
+/// The original bulk api, every product and site in one response each.
+pub struct BulkApiCaller {
     client: Client
 }
 
-impl ApiCaller {
-    pub async fn request_products_and_stores(&self) -> Result<(Vec<Product>, Vec<Site>, Vec<MinimalSite>)> {
-        let prod_sites_both = tokio::try_join!(self.request_all_products(), self.request_all_sites(), self.request_products_with_store())?;
-        return Ok(prod_sites_both);
+#[async_trait]
+impl ApiCaller for BulkApiCaller {
+    async fn request_products_and_stores(&self) -> Result<Upstream> {
+        let ((products, product_drift), (sites, site_drift), (mapping, mapping_drift)) =
+            tokio::try_join!(self.request_all_products(), self.request_all_sites(), self.request_products_with_store())?;
+        Ok(Upstream { products, sites, mapping, drift: vec![product_drift, site_drift, mapping_drift] })
     }
+}
 
-    async fn request_products_with_store(&self) -> Result<Vec<MinimalSite>> {
+impl BulkApiCaller {
+    async fn request_products_with_store(&self) -> Result<(Vec<MinimalSite>, DriftReport)> {
         info!("Sending http request to url={}", PRODUCTS_AND_SITES);
         let http_time = SystemTime::now();
         let res = self.client.get(PRODUCTS_AND_SITES)
@@ -39,12 +46,12 @@ impl ApiCaller {
         let body = res.text().await?;
         info!("Products and sites received, http round trip was: {} millis", SystemTime::now().duration_since(http_time)?.as_millis());
         let processing = SystemTime::now();
-        let stores: Vec<MinimalSite> = serde_json::from_str(&body)?;
+        let stores = parse_records(&body, "MinimalSite", &[])?;
         info!("Products and sites deserialization complete, processing time was: {} millis", SystemTime::now().duration_since(processing)?.as_millis());
         Ok(stores)
     }
 
-    async fn request_all_sites(&self) -> Result<Vec<Site>> {
+    async fn request_all_sites(&self) -> Result<(Vec<Site>, DriftReport)> {
         info!("Sending http request to url={}", SITE_URL);
         let http_time = SystemTime::now();
         let res = self.client.get(SITE_URL)
@@ -54,13 +61,12 @@ impl ApiCaller {
         let body = res.text().await?;
         info!("Sites received, http round trip was: {} millis", SystemTime::now().duration_since(http_time)?.as_millis());
         let processing = SystemTime::now();
-        let sites = serde_json::from_str(&body)
-            .map_err(|e| e.into());
+        let sites = parse_records(&body, "Site", &[]);
         info!("Sites deserialization complete, processing time was: {} millis", SystemTime::now().duration_since(processing)?.as_millis());
         sites
     }
 
-    pub async fn request_all_products(&self) -> Result<Vec<Product>> {
+    pub async fn request_all_products(&self) -> Result<(Vec<Product>, DriftReport)> {
         info!("Sending http request to url={}", PRODUCTS_URL);
         let http_time = SystemTime::now();
         let res = self.client.get(PRODUCTS_URL)
@@ -70,9 +76,9 @@ impl ApiCaller {
         let body = res.text().await?;
         info!("Products received, http round trip was: {} millis", SystemTime::now().duration_since(http_time)?.as_millis());
         let processing = SystemTime::now();
-        let products = serde_json::from_str(&body)
-            .map(|p| {
-                ApiCaller::add_apk(p)
+        let products = parse_records(&body, "Product", DERIVED_FIELDS)
+            .map(|(p, drift)| {
+                (BulkApiCaller::add_apk(p), drift)
             })?;
         info!("Products deserialization complete, processing time was: {} millis", SystemTime::now().duration_since(processing)?.as_millis());
         Ok(products)
@@ -88,7 +94,7 @@ impl ApiCaller {
 
     pub fn new() -> Self {
         let client = Client::new();
-        ApiCaller { client }
+        BulkApiCaller { client }
     }
 }
 
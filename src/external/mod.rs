@@ -1 +1,32 @@
+use async_trait::async_trait;
+use crate::config::{UpstreamConfig, UpstreamApi};
+use crate::domain::models::drift::DriftReport;
+use crate::domain::models::product::{Product, MinimalSite};
+use crate::domain::models::site::Site;
+use crate::domain::result::Result;
+
 pub mod client;
+pub mod notifier;
+pub mod search;
+
+/// Everything one refresh fetches, with how each payload compared to its model.
+pub struct Upstream {
+    pub products: Vec<Product>,
+    pub sites: Vec<Site>,
+    pub mapping: Vec<MinimalSite>,
+    pub drift: Vec<DriftReport>,
+}
+
+/// A Systembolaget api format, mapped into the domain types.
+#[async_trait]
+pub trait ApiCaller: Send + Sync {
+    /// Fetches a full snapshot, products come back with apk computed.
+    async fn request_products_and_stores(&self) -> Result<Upstream>;
+}
+
+pub fn create_caller(config: &UpstreamConfig) -> Box<dyn ApiCaller> {
+    match config.api {
+        UpstreamApi::Bulk => Box::new(client::BulkApiCaller::new()),
+        UpstreamApi::Search => Box::new(search::SearchApiCaller::new(config)),
+    }
+}
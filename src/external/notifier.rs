@@ -0,0 +1,182 @@
+use std::io::Write;
+use std::fs::OpenOptions;
+use std::path::{Component, Path, PathBuf};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use reqwest::header::CONTENT_TYPE;
+use crate::config::NotifyConfig;
+use crate::domain::models::watchlist::{Notification, NotifierTarget, NotifierKind};
+use crate::domain::result::{Result, ErrorKind};
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, notification: &Notification) -> Result<()>;
+}
+
+pub struct WebhookNotifier {
+    client: Client,
+    url: String,
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let body = serde_json::to_string(notification)?;
+        let res = self.client.post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            warn!("Webhook url={} responded with status={}", self.url, res.status());
+            return Err(ErrorKind::Notify(self.url.clone()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Appends one json line per notification, mostly useful for testing watchlists locally.
+pub struct FileNotifier {
+    path: PathBuf,
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let line = serde_json::to_string(notification)?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+pub struct StdoutNotifier;
+
+#[async_trait]
+impl Notifier for StdoutNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        info!("Watchlist notification {}", serde_json::to_string(notification)?);
+        Ok(())
+    }
+}
+
+/// Checked again for every notification, a target stored before the config changed isn't delivered to.
+pub fn create_notifier(target: &NotifierTarget, client: &Client, config: &NotifyConfig) -> Result<Box<dyn Notifier>> {
+    Ok(match target.kind {
+        NotifierKind::Webhook => Box::new(WebhookNotifier { client: client.clone(), url: webhook_url(target, config)? }),
+        NotifierKind::File => Box::new(FileNotifier { path: file_path(target, config)? }),
+        NotifierKind::Stdout => {
+            local(target, config)?;
+            Box::new(StdoutNotifier)
+        }
+    })
+}
+
+/// Fails with `InvalidParameter` unless `config` allows delivering to `target`.
+pub fn check_target(target: &NotifierTarget, config: &NotifyConfig) -> Result<()> {
+    match target.kind {
+        NotifierKind::Webhook => webhook_url(target, config).map(|_| ()),
+        NotifierKind::File => file_path(target, config).map(|_| ()),
+        NotifierKind::Stdout => local(target, config),
+    }
+}
+
+fn refused(target: &NotifierTarget) -> crate::domain::result::Error {
+    ErrorKind::InvalidParameter(String::from("Notifier"), format!("{} {}", target.kind.as_str(), target.target)).into()
+}
+
+/// An http(s) url on one of the allowed hosts.
+fn webhook_url(target: &NotifierTarget, config: &NotifyConfig) -> Result<String> {
+    let url = Url::parse(&target.target).map_err(|_| refused(target))?;
+    let host = url.host_str().unwrap_or_default();
+    let allowed = matches!(url.scheme(), "http" | "https")
+        && config.webhook_hosts.iter().any(|h| !host.is_empty() && h.eq_ignore_ascii_case(host));
+    if !allowed {
+        return Err(refused(target));
+    }
+    Ok(url.to_string())
+}
+
+fn local(target: &NotifierTarget, config: &NotifyConfig) -> Result<()> {
+    if config.allow_local { Ok(()) } else { Err(refused(target)) }
+}
+
+/// A relative path that stays inside `file_dir`, no `..`, root or drive.
+fn file_path(target: &NotifierTarget, config: &NotifyConfig) -> Result<PathBuf> {
+    local(target, config)?;
+    let path = Path::new(&target.target);
+    if target.target.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(refused(target));
+    }
+    Ok(Path::new(&config.file_dir).join(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::watchlist::WatchEvent;
+
+    fn config(allow_local: bool) -> NotifyConfig {
+        NotifyConfig {
+            webhook_hosts: vec![String::from("hooks.example.com")],
+            allow_local,
+            file_dir: std::env::temp_dir().join("test_notifier").to_str().unwrap().to_string(),
+        }
+    }
+
+    fn target(kind: NotifierKind, target: &str) -> NotifierTarget {
+        NotifierTarget { kind, target: target.to_string() }
+    }
+
+    #[test]
+    fn test_check_target() {
+        let (local, remote) = (config(true), config(false));
+        assert!(check_target(&target(NotifierKind::Webhook, "https://HOOKS.example.com/apk"), &remote).is_ok());
+        for url in &["https://evil.example.com/", "http://169.254.169.254/latest", "file:///etc/passwd",
+            "https://hooks.example.com.evil.com/", "not a url"] {
+            assert!(check_target(&target(NotifierKind::Webhook, url), &local).is_err(), "{}", url);
+        }
+        assert!(check_target(&target(NotifierKind::File, "watch/beers.jsonl"), &local).is_ok());
+        assert!(check_target(&target(NotifierKind::File, "beers.jsonl"), &remote).is_err());
+        for path in &["", "/etc/passwd", "../beers.jsonl", "watch/../../beers.jsonl", "./beers.jsonl"] {
+            assert!(check_target(&target(NotifierKind::File, path), &local).is_err(), "{}", path);
+        }
+        assert!(check_target(&target(NotifierKind::Stdout, ""), &local).is_ok());
+        match check_target(&target(NotifierKind::Stdout, ""), &remote).map_err(|e| e.kind().to_string()) {
+            Err(message) => assert!(message.starts_with("invalid value for parameter 'Notifier'"), "{}", message),
+            Ok(_) => panic!("stdout allowed without allow_local"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_notifier_appends_lines() {
+        let config = config(true);
+        let path = Path::new(&config.file_dir).join("test_file_notifier.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let target = target(NotifierKind::File, "test_file_notifier.jsonl");
+        let notifier = create_notifier(&target, &Client::new(), &config).unwrap();
+        let notification = Notification {
+            watchlist_id: 3,
+            watchlist_name: String::from("beers"),
+            events: vec![WatchEvent::PriceDrop {
+                product_id: "1001".into(),
+                name: String::from("Norrlands Guld"),
+                link: String::new(),
+                old_price: 20.0,
+                new_price: 18.5,
+            }],
+        };
+        notifier.notify(&notification).await.unwrap();
+        notifier.notify(&notification).await.unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(2, written.lines().count());
+        assert!(written.starts_with("{\"WatchlistId\":3,"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
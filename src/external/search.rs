@@ -0,0 +1,531 @@
+//! The paginated product search api.
+//!
+//! `GET /productsearch/search?page=1&size=30` answers one page of products as
+//! `{"metadata": {"docCount": 1234, "nextPage": 2}, "products": [...]}`, `nextPage` is -1 on the last page.
+//! Adding `storeId` limits the search to one store's assortment, which is how the store to product mapping is built.
+//! Stores come unpaginated from `GET /site/stores`. Fields are camelCase, categories are split into levels.
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use serde_json::Value;
+use std::time::SystemTime;
+use crate::config::UpstreamConfig;
+use crate::domain::arithmetic::{get_apk, get_recyc_apk};
+use crate::domain::models::drift::{DriftReport, RecordParser, parse_values};
+use crate::domain::models::ids::{ProductId, ProductNumber, SiteId};
+use crate::domain::models::product::{Product, MinimalSite, MinimalProduct};
+use crate::domain::models::serialization_helpers::{nullable_string, optional_datetime, optional_string, date_or_datetime,
+                                                   optional_time};
+use crate::domain::models::site::{Site, OpeningTime, Position};
+use crate::domain::result::{Error, ErrorKind, Result};
+use super::{ApiCaller, Upstream};
+
+static SUBSCRIPTION_HEADER: &str = "Ocp-Apim-Subscription-Key";
+
+/// Fields of `SearchProduct` only sent by store searches, the catalogue search isn't expected to have them.
+const STORE_FIELDS: &[&str] = &["stock", "shelf"];
+
+/// Fetches api paths, swapped for recorded pages in tests.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Body of a GET to `path`, relative to the api base url.
+    async fn get(&self, path: &str) -> Result<String>;
+}
+
+pub struct HttpTransport {
+    client: Client,
+    base_url: String,
+    api_key: String,
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn get(&self, path: &str) -> Result<String> {
+        let url = format!("{}{}", self.base_url.trim_end_matches('/'), path);
+        debug!("Sending http request to url={}", url);
+        let res = self.client.get(&url)
+            .header(SUBSCRIPTION_HEADER, self.api_key.as_str())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(res.text().await?)
+    }
+}
+
+pub struct SearchApiCaller {
+    transport: Box<dyn Transport>,
+    page_size: usize,
+    max_pages: usize,
+    concurrency: usize,
+}
+
+#[async_trait]
+impl ApiCaller for SearchApiCaller {
+    async fn request_products_and_stores(&self) -> Result<Upstream> {
+        let start = SystemTime::now();
+        let (products, sites) = tokio::try_join!(self.request_products(), self.request_sites())?;
+        let (products, product_drift) = products;
+        let (sites, site_drift) = sites;
+        let (mapping, mapping_drift) = self.request_assortments(&sites).await?;
+        info!("Searched {} products in {} stores in {} millis", products.len(), sites.len(),
+              SystemTime::now().duration_since(start)?.as_millis());
+        Ok(Upstream { products, sites, mapping, drift: vec![product_drift, site_drift, mapping_drift] })
+    }
+}
+
+impl SearchApiCaller {
+    pub fn new(config: &UpstreamConfig) -> Self {
+        let transport = HttpTransport {
+            client: Client::new(),
+            base_url: config.base_url.clone(),
+            api_key: config.api_key.clone(),
+        };
+        SearchApiCaller::with_transport(Box::new(transport), config)
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>, config: &UpstreamConfig) -> Self {
+        SearchApiCaller {
+            transport,
+            page_size: config.page_size.max(1),
+            max_pages: config.max_pages.max(1),
+            concurrency: config.concurrency.max(1),
+        }
+    }
+
+    async fn request_products(&self) -> Result<(Vec<Product>, DriftReport)> {
+        let mut parser = RecordParser::<SearchProduct>::new("SearchProduct", STORE_FIELDS);
+        let products = self.search(None).await?
+            .into_iter()
+            .filter_map(|record| parser.parse(record))
+            .map(SearchProduct::into_product)
+            .collect();
+        Ok((products, parser.finish()?))
+    }
+
+    async fn request_sites(&self) -> Result<(Vec<Site>, DriftReport)> {
+        let records: Vec<Value> = serde_json::from_str(&self.transport.get("/site/stores").await?)?;
+        let (stores, drift) = parse_values::<SearchStore>(records, "SearchStore", &[])?;
+        Ok((stores.into_iter().map(SearchStore::into_site).collect(), drift))
+    }
+
+    /// One search per store, hits are checked against the product model like the full search.
+    async fn request_assortments(&self, sites: &[Site]) -> Result<(Vec<MinimalSite>, DriftReport)> {
+        let site_ids: Vec<SiteId> = sites.iter().map(|s| s.site_id.clone()).collect();
+        let searches: Vec<(SiteId, Vec<Value>)> = futures::stream::iter(site_ids)
+            .map(|site_id| async move {
+                let hits = self.search(Some(&site_id)).await?;
+                Ok::<_, Error>((site_id, hits))
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await?;
+        let mut parser = RecordParser::<SearchProduct>::new("StoreAssortment", &[]);
+        let mut mapping: Vec<MinimalSite> = searches.into_iter()
+            .map(|(site_id, hits)| MinimalSite {
+                site_id,
+                products: hits.into_iter()
+                    .filter_map(|hit| parser.parse(hit))
+                    .map(|p| MinimalProduct {
+                        product_id: p.product_id,
+                        product_number: p.product_number,
+                        stock: p.stock,
+                        shelf: p.shelf,
+                    })
+                    .collect(),
+            })
+            .collect();
+        mapping.sort_by(|a, b| a.site_id.cmp(&b.site_id));
+        Ok((mapping, parser.finish()?))
+    }
+
+    /// Every page of a search, `store` limits it to one store's assortment.
+    /// A search that pages past `max_pages` fails rather than returning part of the catalogue.
+    async fn search(&self, store: Option<&SiteId>) -> Result<Vec<Value>> {
+        let mut records = Vec::new();
+        let mut page = 1;
+        loop {
+            let mut path = format!("/productsearch/search?page={}&size={}", page, self.page_size);
+            if let Some(site_id) = store {
+                path.push_str(&format!("&storeId={}&isInStoreAssortmentSearch=true", site_id));
+            }
+            let body: SearchPage = serde_json::from_str(&self.transport.get(&path).await?)?;
+            let next = body.metadata.next_page;
+            let empty = body.products.is_empty();
+            records.extend(body.products);
+            if empty || next <= page {
+                if records.len() != body.metadata.doc_count {
+                    warn!("Search {} returned {} of {} documents", path, records.len(), body.metadata.doc_count);
+                }
+                break;
+            }
+            if next > self.max_pages as i64 {
+                return Err(ErrorKind::UpstreamTruncated(path, self.max_pages).into());
+            }
+            page = next;
+        }
+        Ok(records)
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchPage {
+    #[serde(default)]
+    metadata: PageMetadata,
+    #[serde(default)]
+    products: Vec<Value>,
+}
+
+#[derive(Deserialize, Default)]
+struct PageMetadata {
+    #[serde(rename="docCount", default)]
+    doc_count: usize,
+    #[serde(rename="nextPage", default="last_page")]
+    next_page: i64,
+}
+
+fn last_page() -> i64 {
+    -1
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct SearchProduct {
+    product_id: ProductId,
+    product_number: ProductNumber,
+    #[serde(default, deserialize_with="nullable_string")]
+    product_name_bold: String,
+    #[serde(default, deserialize_with="optional_string")]
+    product_name_thin: Option<String>,
+    #[serde(default, deserialize_with="nullable_string")]
+    product_number_short: String,
+    #[serde(default, deserialize_with="optional_string")]
+    producer_name: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    supplier_name: Option<String>,
+    #[serde(default)]
+    is_kosher: bool,
+    #[serde(default, deserialize_with="nullable_string")]
+    bottle_text: String,
+    #[serde(default)]
+    restricted_parcel_quantity: i32,
+    #[serde(default)]
+    is_organic: bool,
+    #[serde(default)]
+    is_ethical: bool,
+    #[serde(default, deserialize_with="optional_string")]
+    ethical_label: Option<String>,
+    #[serde(default)]
+    is_web_launch: bool,
+    #[serde(default, deserialize_with="optional_datetime")]
+    product_launch_date: Option<NaiveDateTime>,
+    #[serde(default)]
+    is_completely_out_of_stock: bool,
+    #[serde(default)]
+    is_temporary_out_of_stock: bool,
+    alcohol_percentage: f64,
+    volume: f64,
+    price: f64,
+    #[serde(default, deserialize_with="nullable_string")]
+    country: String,
+    #[serde(default, deserialize_with="optional_string")]
+    origin_level1: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    origin_level2: Option<String>,
+    #[serde(default, deserialize_with="vintage_text")]
+    vintage: Option<i32>,
+    #[serde(default, deserialize_with="nullable_string")]
+    category_level1: String,
+    #[serde(default, deserialize_with="optional_string")]
+    category_level2: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    category_level3: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    category_level4: Option<String>,
+    #[serde(default, deserialize_with="nullable_string")]
+    assortment: String,
+    #[serde(default, deserialize_with="nullable_string")]
+    assortment_text: String,
+    #[serde(default, deserialize_with="optional_string")]
+    usage: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    taste: Option<String>,
+    #[serde(default)]
+    recycle_fee: f64,
+    #[serde(default)]
+    is_manufacturing_country: bool,
+    #[serde(default)]
+    is_regional_restricted: bool,
+    #[serde(default)]
+    is_news: bool,
+    /// Units in the searched store, 0 if it's listed but sold out.
+    #[serde(default)]
+    stock: Option<i32>,
+    #[serde(default, deserialize_with="optional_string")]
+    shelf: Option<String>,
+}
+
+impl SearchProduct {
+    /// Category levels map onto category, sub category, type and style.
+    fn into_product(self) -> Product {
+        let mut product = Product {
+            product_id: self.product_id,
+            product_number: self.product_number,
+            product_name_bold: self.product_name_bold,
+            product_name_thin: self.product_name_thin,
+            category: self.category_level1,
+            product_number_short: self.product_number_short,
+            producer_name: self.producer_name,
+            supplier_name: self.supplier_name,
+            is_kosher: self.is_kosher,
+            bottle_text_short: self.bottle_text,
+            restricted_parcel_quantity: self.restricted_parcel_quantity,
+            is_organic: self.is_organic,
+            is_ethical: self.is_ethical,
+            ethical_label: self.ethical_label,
+            is_web_launch: self.is_web_launch,
+            sell_start_date: self.product_launch_date,
+            is_completely_out_of_stock: self.is_completely_out_of_stock,
+            is_temporary_out_of_stock: self.is_temporary_out_of_stock,
+            alcohol_percentage: self.alcohol_percentage,
+            volume: self.volume,
+            price: self.price,
+            country: self.country,
+            origin_level1: self.origin_level1,
+            origin_level2: self.origin_level2,
+            vintage: self.vintage,
+            sub_category: self.category_level2,
+            a_type: self.category_level3,
+            style: self.category_level4,
+            assortment_text: self.assortment_text,
+            usage_text: self.usage,
+            taste: self.taste,
+            assortment: self.assortment,
+            is_manufacturing_country: self.is_manufacturing_country,
+            recycle_fee: self.recycle_fee,
+            is_regional_retricted: self.is_regional_restricted,
+            is_news: self.is_news,
+            ..Product::default()
+        };
+        product.apk = get_apk(&product);
+        product.apk_recycling = get_recyc_apk(&product);
+        product
+    }
+}
+
+/// The search api sends vintage as text, empty for non-vintage products.
+fn vintage_text<'de, D>(deserializer: D) -> std::result::Result<Option<i32>, D::Error> where D: Deserializer<'de> {
+    let vintage = match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) if s.is_empty() => None,
+        Some(Value::String(s)) => Some(s.parse::<i32>().map_err(|_| DeError::custom(format!("invalid vintage {:?}", s)))?),
+        Some(Value::Number(n)) => n.as_i64().map(|n| n as i32),
+        Some(other) => return Err(DeError::custom(format!("invalid vintage {}", other))),
+    };
+    Ok(vintage.filter(|v| *v != 0))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct SearchStore {
+    site_id: SiteId,
+    #[serde(default, deserialize_with="optional_string")]
+    alias: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    display_name: Option<String>,
+    #[serde(default, deserialize_with="nullable_string")]
+    address: String,
+    #[serde(default, deserialize_with="nullable_string")]
+    postal_code: String,
+    #[serde(default, deserialize_with="nullable_string")]
+    city: String,
+    #[serde(default, deserialize_with="nullable_string")]
+    county: String,
+    #[serde(default, deserialize_with="optional_string")]
+    phone: Option<String>,
+    #[serde(default, deserialize_with="optional_string")]
+    email: Option<String>,
+    #[serde(default)]
+    is_tasting_store: bool,
+    #[serde(default)]
+    opening_hours: Vec<SearchOpening>,
+    #[serde(default)]
+    position: Option<SearchPosition>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct SearchOpening {
+    #[serde(deserialize_with="date_or_datetime")]
+    date: NaiveDate,
+    #[serde(default, deserialize_with="optional_time")]
+    open_from: Option<NaiveTime>,
+    #[serde(default, deserialize_with="optional_time")]
+    open_to: Option<NaiveTime>,
+    #[serde(default, deserialize_with="nullable_string")]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct SearchPosition {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl SearchStore {
+    /// Only stores are listed, agents aren't part of this api. The alias is the store's name.
+    fn into_site(self) -> Site {
+        let name = self.alias.clone();
+        Site {
+            site_id: self.site_id,
+            is_tasting_store: self.is_tasting_store,
+            alias: self.alias,
+            address: self.address,
+            display_name: self.display_name,
+            postal_code: self.postal_code,
+            city: self.city,
+            county: self.county,
+            country: String::from("Sverige"),
+            is_store: true,
+            phone: self.phone,
+            email: self.email,
+            opening_hours: self.opening_hours.into_iter().map(SearchOpening::into_opening_time).collect(),
+            name,
+            position: self.position
+                .map(|p| Position { lat: p.latitude, long: p.longitude })
+                .unwrap_or_default(),
+            ..Site::default()
+        }
+    }
+}
+
+impl SearchOpening {
+    /// Closed days are sent with the same opening and closing time.
+    fn into_opening_time(self) -> OpeningTime {
+        let is_open = self.open_from.is_some() && self.open_from != self.open_to;
+        OpeningTime {
+            is_open,
+            reason: self.reason,
+            date: self.date,
+            open_from: self.open_from,
+            open_to: self.open_to,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Recorded responses by path, anything else is a 404.
+    struct Recorded(HashMap<String, &'static str>);
+
+    #[async_trait]
+    impl Transport for Recorded {
+        async fn get(&self, path: &str) -> Result<String> {
+            self.0.get(path)
+                .map(|body| body.to_string())
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, path.to_string()).into())
+        }
+    }
+
+    fn caller(page_size: usize, max_pages: usize) -> SearchApiCaller {
+        let search = |page: usize, store: &str| {
+            let store = if store.is_empty() {
+                String::new()
+            } else {
+                format!("&storeId={}&isInStoreAssortmentSearch=true", store)
+            };
+            format!("/productsearch/search?page={}&size={}{}", page, page_size, store)
+        };
+        let pages = vec![
+            (search(1, ""), include_str!("fixtures/search/products_page_1.json")),
+            (search(2, ""), include_str!("fixtures/search/products_page_2.json")),
+            (search(3, ""), include_str!("fixtures/search/products_page_3.json")),
+            (search(1, "0102"), include_str!("fixtures/search/store_0102_page_1.json")),
+            (search(2, "0102"), include_str!("fixtures/search/store_0102_page_2.json")),
+            (search(1, "0104"), include_str!("fixtures/search/store_0104_page_1.json")),
+            (String::from("/site/stores"), include_str!("fixtures/search/stores.json")),
+        ];
+        let config = UpstreamConfig { page_size, max_pages, ..UpstreamConfig::default() };
+        SearchApiCaller::with_transport(Box::new(Recorded(pages.into_iter().collect())), &config)
+    }
+
+    #[tokio::test]
+    async fn test_pages_through_products() {
+        let upstream = caller(2, 100).request_products_and_stores().await.unwrap();
+        let ids: Vec<&str> = upstream.products.iter().map(|p| p.product_id.as_str()).collect();
+        // 2002 has no alcohol percentage and is skipped
+        assert_eq!(vec!["1001", "1002", "2001", "3001"], ids);
+        let product_drift = &upstream.drift[0];
+        assert_eq!((5, 1), (product_drift.records, product_drift.skipped));
+        assert!(product_drift.unknown_fields.is_empty() && product_drift.missing_fields.is_empty());
+
+        let wine = &upstream.products[2];
+        assert_eq!("Vin", wine.category);
+        assert_eq!(Some("Rött vin"), wine.sub_category.as_deref());
+        assert_eq!(Some("Fruktigt & Smakrikt"), wine.a_type.as_deref());
+        assert_eq!(None, wine.style);
+        assert_eq!(Some(2016), wine.vintage);
+        assert_eq!(Some(NaiveDate::from_ymd(2019, 3, 1).and_hms(0, 0, 0)), wine.sell_start_date);
+        assert!(wine.apk > 0.0 && wine.apk_recycling < wine.apk);
+        assert_eq!(None, upstream.products[1].sell_start_date);
+        assert_eq!(None, upstream.products[3].vintage);
+    }
+
+    #[tokio::test]
+    async fn test_maps_stores_and_assortments() {
+        let upstream = caller(2, 100).request_products_and_stores().await.unwrap();
+        let centrum = &upstream.sites[0];
+        assert_eq!("0102", centrum.site_id);
+        assert_eq!(Some("Centrum"), centrum.name.as_deref());
+        assert!(centrum.is_store && centrum.is_tasting_store && !centrum.is_agent);
+        assert_eq!(59.3326, centrum.position.lat);
+        assert!(!centrum.opening_hours[0].is_open);
+        assert_eq!("Julafton", centrum.opening_hours[0].reason);
+        assert!(centrum.opening_hours[1].is_open);
+        assert_eq!(Some(NaiveTime::from_hms(20, 0, 0)), centrum.opening_hours[1].open_to);
+        assert_eq!(None, upstream.sites[1].name);
+        assert_eq!(0.0, upstream.sites[1].position.lat);
+
+        let mapping: Vec<(&str, Vec<&str>)> = upstream.mapping.iter()
+            .map(|s| (s.site_id.as_str(), s.products.iter().map(|p| p.product_id.as_str()).collect()))
+            .collect();
+        assert_eq!(vec![("0102", vec!["1001", "2001", "3001"]), ("0104", vec![])], mapping);
+        assert_eq!(3, upstream.drift[2].records);
+        let stock: Vec<(Option<i32>, Option<&str>)> = upstream.mapping[0].products.iter()
+            .map(|p| (p.stock, p.shelf.as_deref()))
+            .collect();
+        // Listed but sold out stays distinct from a store search that doesn't report stock
+        assert_eq!(vec![(Some(12), Some("A3")), (Some(0), None), (None, None)], stock);
+    }
+
+    #[tokio::test]
+    async fn test_stops_at_max_pages() {
+        // Reaching the page cap fails the refresh rather than loading a partial snapshot
+        assert!(caller(2, 1).search(None).await.is_err());
+        assert!(caller(2, 1).request_products_and_stores().await.is_err());
+        let caller = caller(2, 3);
+        assert_eq!(5, caller.search(None).await.unwrap().len());
+        // So does a page that isn't recorded
+        assert!(super::SearchApiCaller::with_transport(caller.transport, &UpstreamConfig::default())
+            .request_products_and_stores().await.is_err());
+    }
+
+    #[test]
+    fn test_vintage_text() {
+        let vintage = |v: Value| {
+            #[derive(Deserialize)]
+            struct V(#[serde(deserialize_with="vintage_text")] Option<i32>);
+            serde_json::from_value::<V>(v).map(|v| v.0)
+        };
+        assert_eq!(Some(2016), vintage(Value::from("2016")).unwrap());
+        assert_eq!(Some(2016), vintage(Value::from(2016)).unwrap());
+        assert_eq!(None, vintage(Value::from("")).unwrap());
+        assert_eq!(None, vintage(Value::Null).unwrap());
+        assert!(vintage(Value::from("NV")).is_err());
+    }
+}
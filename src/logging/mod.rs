@@ -0,0 +1,79 @@
+mod trigger;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use log4rs::config::Deserializers;
+use trigger::SizeOrAgeTriggerDeserializer;
+
+static LOG_CONFIG: &str = "log4rs.yml";
+
+/// Mapped diagnostic context key, available as `{X(correlation_id)}` in patterns and under `mdc` in json.
+pub const CORRELATION_ID: &str = "correlation_id";
+
+lazy_static! {
+    static ref ID_SEED: RandomState = RandomState::new();
+}
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() -> anyhow::Result<()> {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert("size_or_age", SizeOrAgeTriggerDeserializer);
+    log4rs::init_file(LOG_CONFIG, deserializers)
+}
+
+/// 16 hex characters, unique within the process and unpredictable across restarts.
+pub fn new_id() -> String {
+    let mut hasher = ID_SEED.build_hasher();
+    hasher.write_u64(ID_COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Runs `inner` with `id` as the correlation id of everything it logs. The id is set for each poll and
+/// restored after, so interleaved futures on the same thread keep their own ids.
+pub fn correlated<F: Future>(id: String, inner: F) -> Correlated<F> {
+    Correlated { id, inner: Box::pin(inner) }
+}
+
+pub struct Correlated<F> {
+    id: String,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Correlated<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let _scope = log_mdc::insert_scoped(CORRELATION_ID, self.id.clone());
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> Option<String> {
+        log_mdc::get(CORRELATION_ID, |v| v.map(String::from))
+    }
+
+    #[tokio::test]
+    async fn test_correlated_scope() {
+        let first = correlated(String::from("a"), async {
+            assert_eq!(Some(String::from("a")), current());
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            assert_eq!(Some(String::from("a")), current());
+        });
+        let second = correlated(String::from("b"), async {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            assert_eq!(Some(String::from("b")), current());
+        });
+        futures::join!(first, second);
+        assert_eq!(None, current());
+        assert_ne!(new_id(), new_id());
+        assert_eq!(16, new_id().len());
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use log4rs::append::rolling_file::LogFile;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::config::{Deserialize, Deserializers};
+
+/// Rolls the log file once it grows past `limit` bytes or was started more than `max_age` ago.
+/// Registered as `size_or_age`, e.g. `{kind: size_or_age, limit: 10 mb, max_age: 7d}`.
+#[derive(Debug)]
+pub struct SizeOrAgeTrigger {
+    limit: u64,
+    max_age: Duration,
+    started: Mutex<Option<SystemTime>>,
+}
+
+impl SizeOrAgeTrigger {
+    pub fn new(limit: u64, max_age: Duration) -> Self {
+        SizeOrAgeTrigger { limit, max_age, started: Mutex::new(None) }
+    }
+
+    fn expired(&self, path: &Path, now: SystemTime) -> bool {
+        let mut started = self.started.lock().unwrap();
+        let since = *started.get_or_insert_with(|| std::fs::metadata(path)
+            .and_then(|m| m.created())
+            .unwrap_or(now));
+        now.duration_since(since).map(|age| age >= self.max_age).unwrap_or(false)
+    }
+}
+
+impl Trigger for SizeOrAgeTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        let roll = file.len_estimate() > self.limit || self.expired(file.path(), SystemTime::now());
+        if roll {
+            // The next file starts now, its age is read again on the next record
+            *self.started.lock().unwrap() = None;
+        }
+        Ok(roll)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SizeOrAgeTriggerConfig {
+    limit: String,
+    max_age: String,
+}
+
+#[derive(Debug)]
+pub struct SizeOrAgeTriggerDeserializer;
+
+impl Deserialize for SizeOrAgeTriggerDeserializer {
+    type Trait = dyn Trigger;
+
+    type Config = SizeOrAgeTriggerConfig;
+
+    fn deserialize(&self, config: SizeOrAgeTriggerConfig, _: &Deserializers) -> anyhow::Result<Box<dyn Trigger>> {
+        let limit = parse_size(&config.limit)
+            .ok_or_else(|| anyhow::anyhow!("invalid log size limit '{}'", config.limit))?;
+        let max_age = parse_age(&config.max_age)
+            .ok_or_else(|| anyhow::anyhow!("invalid log max_age '{}'", config.max_age))?;
+        Ok(Box::new(SizeOrAgeTrigger::new(limit, max_age)))
+    }
+}
+
+/// `1024`, `512 kb`, `10 mb` or `1 gb`.
+fn parse_size(source: &str) -> Option<u64> {
+    let (number, unit) = split_unit(source)?;
+    let factor = match unit.to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1024,
+        "mb" => 1024 * 1024,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.checked_mul(factor)
+}
+
+/// `90s`, `30m`, `12h` or `7d`.
+fn parse_age(source: &str) -> Option<Duration> {
+    let (number, unit) = split_unit(source)?;
+    let secs = match unit.to_lowercase().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.checked_mul(secs).map(Duration::from_secs)
+}
+
+fn split_unit(source: &str) -> Option<(u64, &str)> {
+    let source = source.trim();
+    let split = source.find(|c: char| !c.is_ascii_digit()).unwrap_or(source.len());
+    let number = source[..split].parse().ok()?;
+    Some((number, source[split..].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(Some(10 * 1024 * 1024), parse_size("10 mb"));
+        assert_eq!(Some(1024), parse_size("1024"));
+        assert_eq!(None, parse_size("10 parsecs"));
+        assert_eq!(Some(Duration::from_secs(7 * 24 * 60 * 60)), parse_age("7d"));
+        assert_eq!(Some(Duration::from_secs(12 * 60 * 60)), parse_age("12 h"));
+        assert_eq!(None, parse_age("7"));
+        assert_eq!(None, parse_age("d"));
+    }
+
+    #[test]
+    fn test_age_from_file_creation() {
+        let path = std::env::temp_dir().join("test_age_from_file_creation.log");
+        std::fs::write(&path, b"line\n").unwrap();
+        let now = SystemTime::now();
+        let trigger = SizeOrAgeTrigger::new(1024, Duration::from_secs(60 * 60));
+        assert!(!trigger.expired(&path, now));
+        assert!(trigger.expired(&path, now + Duration::from_secs(2 * 60 * 60)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
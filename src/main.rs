@@ -2,8 +2,8 @@ mod domain;
 mod external;
 mod database;
 mod app;
-use log4rs;
-use log4rs::config::Deserializers;
+mod config;
+mod logging;
 
 #[macro_use]
 extern crate rusqlite;
@@ -20,7 +20,16 @@ extern crate error_chain;
 #[actix_rt::main]
 async fn main() -> domain::result::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
-    log4rs::init_file("log4rs.yml", Deserializers::default()).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a == "export").unwrap_or(false) {
+        // No logging, stdout carries the export
+        return app::export(&args[1..]).await
+            .map_err(|e| -> domain::result::Error {
+                eprintln!("Export failed: {}", e);
+                std::process::exit(1);
+            });
+    }
+    logging::init().unwrap();
     let rt = tokio::runtime::Builder::new()
         .threaded_scheduler()
         .enable_all()
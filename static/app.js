@@ -0,0 +1,92 @@
+"use strict";
+
+const form = document.getElementById("filters");
+const status = document.getElementById("status");
+const rows = document.querySelector("#products tbody");
+
+function loadStores() {
+    fetch("api/v1/sites?is_store=true&fields=SiteId,Name,Alias,City")
+        .then(res => res.json())
+        .then(sites => {
+            const picker = document.getElementById("site_id");
+            sites
+                .map(s => ({id: s.SiteId, label: [s.Name || s.Alias, s.City].filter(x => x).join(", ")}))
+                .sort((a, b) => a.label.localeCompare(b.label, "sv"))
+                .forEach(s => {
+                    const option = document.createElement("option");
+                    option.value = s.id;
+                    option.textContent = s.label || s.id;
+                    picker.appendChild(option);
+                });
+        })
+        .catch(e => status.textContent = "Failed to load stores: " + e);
+}
+
+function query() {
+    const params = new URLSearchParams();
+    ["count", "max_volume", "site_id", "category"].forEach(name => {
+        const value = form.elements[name].value.trim();
+        if (value !== "") {
+            params.set(name, name === "category" ? value.toLowerCase() : value);
+        }
+    });
+    ["include_recycling", "exists_in_store", "open_now"].forEach(name => {
+        params.set(name, form.elements[name].checked);
+    });
+    return params;
+}
+
+function cell(text, numeric) {
+    const td = document.createElement("td");
+    td.textContent = text;
+    if (numeric) {
+        td.className = "num";
+    }
+    return td;
+}
+
+function render(products) {
+    const recycling = form.elements["include_recycling"].checked;
+    rows.textContent = "";
+    products.forEach((p, i) => {
+        const tr = document.createElement("tr");
+        tr.appendChild(cell(i + 1, true));
+        const name = document.createElement("td");
+        const link = document.createElement("a");
+        link.href = p.Link;
+        link.target = "_blank";
+        link.rel = "noopener";
+        link.textContent = [p.ProductNameBold, p.ProductNameThin].filter(x => x).join(" ");
+        name.appendChild(link);
+        tr.appendChild(name);
+        tr.appendChild(cell(p.Category));
+        tr.appendChild(cell(p.Volume + " ml", true));
+        tr.appendChild(cell(p.AlcoholPercentage + " %", true));
+        tr.appendChild(cell(p.Price.toFixed(2) + " kr", true));
+        tr.appendChild(cell((recycling ? p.ApkRecycling : p.Apk).toFixed(3), true));
+        rows.appendChild(tr);
+    });
+    status.textContent = products.length + " products";
+}
+
+function downloads(params) {
+    ["csv", "xlsx"].forEach(format => {
+        const withFormat = new URLSearchParams(params);
+        withFormat.set("format", format);
+        document.getElementById("download_" + format).href = "api/v1/top?" + withFormat;
+    });
+    document.getElementById("downloads").hidden = false;
+}
+
+form.addEventListener("submit", e => {
+    e.preventDefault();
+    status.textContent = "Loading...";
+    const params = query();
+    downloads(params);
+    fetch("api/v1/top?" + params + "&view=compact")
+        .then(res => res.ok ? res.json() : res.text().then(t => Promise.reject(t || res.status)))
+        .then(render)
+        .catch(e => status.textContent = "Failed to load products: " + e);
+});
+
+loadStores();